@@ -0,0 +1,117 @@
+use std::fmt;
+
+/// Selects the bluetooth adapters to scan with. The index of a USB adapter may change when it
+/// is plugged in again, so the adapter can also be selected by its MAC address or by a glob
+/// pattern of the name, e.g. hci*.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdapterSelector {
+    Name(String),
+    Address(String),
+    Pattern(String),
+}
+
+impl AdapterSelector {
+
+    pub fn parse(selector: &str) -> AdapterSelector {
+        let is_address = selector.len() == 17 && selector
+            .split(':')
+            .all(|b| b.len() == 2 && b.chars().all(|c| c.is_ascii_hexdigit()));
+        if is_address {
+            AdapterSelector::Address(selector.to_uppercase())
+        } else if selector.contains(['*', '?']) {
+            AdapterSelector::Pattern(selector.to_string())
+        } else {
+            AdapterSelector::Name(selector.to_string())
+        }
+    }
+
+    /// The address is None if it is not known, then an address selector does not match.
+    pub fn matches(&self, name: &str, address: Option<&str>) -> bool {
+        match *self {
+            AdapterSelector::Name(ref n) => n == name,
+            AdapterSelector::Address(ref a) => address.is_some_and(|addr| a.eq_ignore_ascii_case(addr)),
+            AdapterSelector::Pattern(ref p) => glob_match(p.as_bytes(), name.as_bytes()),
+        }
+    }
+
+}
+
+impl fmt::Display for AdapterSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AdapterSelector::Name(ref s) | AdapterSelector::Address(ref s)
+                | AdapterSelector::Pattern(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+pub fn parse_selectors(selectors: &[String]) -> Vec<AdapterSelector> {
+    selectors.iter().map(|s| AdapterSelector::parse(s)).collect()
+}
+
+pub fn matches_any(selectors: &[AdapterSelector], name: &str, address: Option<&str>) -> bool {
+    selectors.iter().any(|s| s.matches(name, address))
+}
+
+// * matches any number of characters and ? any one character.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], text) || (!text.is_empty() && glob_match(pattern, &text[1..]))
+        },
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p == t => glob_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "00:1A:7D:DA:71:13";
+
+    #[test]
+    fn matches_pattern() {
+        let selector = AdapterSelector::parse("hci*");
+        assert_eq!(selector, AdapterSelector::Pattern("hci*".to_string()));
+        assert!(selector.matches("hci0", None));
+        assert!(selector.matches("hci12", Some(ADDRESS)));
+        assert!(!selector.matches("usb0", None));
+        assert!(AdapterSelector::parse("hci?").matches("hci1", None));
+        assert!(!AdapterSelector::parse("hci?").matches("hci10", None));
+    }
+
+    #[test]
+    fn matches_address() {
+        let selector = AdapterSelector::parse("00:1a:7d:da:71:13");
+        assert_eq!(selector, AdapterSelector::Address(ADDRESS.to_string()));
+        assert!(selector.matches("hci1", Some(ADDRESS)));
+        assert!(selector.matches("hci1", Some("00:1a:7d:da:71:13")));
+        assert!(!selector.matches("hci1", Some("00:1A:7D:DA:71:14")));
+        // The HCI backend does not know the addresses.
+        assert!(!selector.matches("hci1", None));
+    }
+
+    #[test]
+    fn matches_name() {
+        let selector = AdapterSelector::parse("hci0");
+        assert_eq!(selector, AdapterSelector::Name("hci0".to_string()));
+        assert!(selector.matches("hci0", Some(ADDRESS)));
+        assert!(!selector.matches("hci01", None));
+        // Not an address, the last group has one digit.
+        assert_eq!(AdapterSelector::parse("00:1A:7D:DA:71:1"),
+                   AdapterSelector::Name("00:1A:7D:DA:71:1".to_string()));
+    }
+
+    #[test]
+    fn matches_nothing() {
+        let selectors = parse_selectors(&["hci9".to_string(), "usb*".to_string(), ADDRESS.to_string()]);
+        assert!(!matches_any(&selectors, "hci0", Some("00:1A:7D:DA:71:14")));
+        assert!(!matches_any(&selectors, "hci1", None));
+        assert!(!matches_any(&[], "hci0", None));
+        assert!(matches_any(&selectors, "usb1", None));
+    }
+
+}
@@ -0,0 +1,84 @@
+use dbus::{Message, MessageItem, MessageItemArray, Signature};
+
+// The monitor object the collector exports for the AdvertisementMonitorManager1 of BlueZ, see
+// doc/org.bluez.AdvertisementMonitor.rst in the BlueZ sources. BlueZ reads the monitors with
+// GetManagedObjects from the application root.
+pub static APP_PATH: &str = "/bt_sensor";
+pub static MONITOR_PATH: &str = "/bt_sensor/monitor0";
+pub static INTERFACE_MONITOR1: &str = "org.bluez.AdvertisementMonitor1";
+pub static INTERFACE_MONITOR_MANAGER1: &str = "org.bluez.AdvertisementMonitorManager1";
+
+const AD_MANUFACTURER_DATA: u8 = 0xFF;
+const AD_SERVICE_DATA_16: u8 = 0x16;
+
+// The advertising data type and the start of the data of the supported sensors, the company
+// ids and the UUIDs are little endian. An advertisement matching any of these is reported.
+// Inkbird puts a measurement in place of the company id and AltBeacon accepts any company
+// id, so those cannot be matched.
+static PATTERNS: [(u8, &[u8]); 11] = [
+    // Ruuvi
+    (AD_MANUFACTURER_DATA, &[0x99, 0x04]),
+    // Govee H5075 and H5179
+    (AD_MANUFACTURER_DATA, &[0x88, 0xEC]),
+    (AD_MANUFACTURER_DATA, &[0x01, 0x88]),
+    // SwitchBot
+    (AD_MANUFACTURER_DATA, &[0x69, 0x09]),
+    // iBeacon, Apple company id and the iBeacon type and length
+    (AD_MANUFACTURER_DATA, &[0x4C, 0x00, 0x02, 0x15]),
+    // BTHome
+    (AD_SERVICE_DATA_16, &[0xD2, 0xFC]),
+    // ATC1441 and pvvx firmware
+    (AD_SERVICE_DATA_16, &[0x1A, 0x18]),
+    // Xiaomi MiBeacon
+    (AD_SERVICE_DATA_16, &[0x95, 0xFE]),
+    // SwitchBot
+    (AD_SERVICE_DATA_16, &[0x3D, 0xFD]),
+    (AD_SERVICE_DATA_16, &[0x00, 0x0D]),
+    // Eddystone, the Ruuvi data formats 2 and 4 and TLM
+    (AD_SERVICE_DATA_16, &[0xAA, 0xFE]),
+];
+
+fn array(items: Vec<MessageItem>, signature: &str) -> Result<MessageItem, String> {
+    let sign = Signature::new(signature).map_err(|e| e.to_string())?;
+    MessageItemArray::new(items, sign)
+        .map(MessageItem::Array)
+        .map_err(|_| "ArrayError".to_string())
+}
+
+fn dict_entry(key: MessageItem, val: MessageItem) -> MessageItem {
+    MessageItem::DictEntry(Box::new(key), Box::new(val))
+}
+
+fn patterns() -> Result<MessageItem, String> {
+    let mut items = Vec::new();
+    for &(ad_type, content) in PATTERNS.iter() {
+        let content_items = content.iter().map(|b| MessageItem::Byte(*b)).collect();
+        items.push(MessageItem::Struct(vec!(
+            MessageItem::Byte(0),
+            MessageItem::Byte(ad_type),
+            array(content_items, "ay")?,
+        )));
+    }
+    array(items, "a(yyay)")
+}
+
+/// The reply to GetManagedObjects: the monitor object with its properties.
+pub fn managed_objects_reply(msg: &Message) -> Result<Message, String> {
+    let props = array(vec!(
+        dict_entry(
+            MessageItem::Str("Type".to_string()),
+            MessageItem::Variant(Box::new(MessageItem::Str("or_patterns".to_string()))),
+        ),
+        dict_entry(
+            MessageItem::Str("Patterns".to_string()),
+            MessageItem::Variant(Box::new(patterns()?)),
+        ),
+    ), "a{sv}")?;
+    let interfaces = array(vec!(
+        dict_entry(MessageItem::Str(INTERFACE_MONITOR1.to_string()), props),
+    ), "a{sa{sv}}")?;
+    let objects = array(vec!(
+        dict_entry(MessageItem::ObjectPath(MONITOR_PATH.to_string().into()), interfaces),
+    ), "a{oa{sa{sv}}}")?;
+    Ok(msg.method_return().append1(objects))
+}
@@ -0,0 +1,36 @@
+use std::error;
+use std::time::Duration;
+
+use consumer::Consumer;
+use recording::Recorder;
+
+type BoxErr = Box<dyn error::Error>;
+
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq)]
+pub enum BackendType {
+    Bluez,
+    Hci,
+    Gateway,
+    Mqtt,
+    Simulator,
+}
+
+/// A source of advertisements. The backends keep the devices in a DeviceStore and give the
+/// sensors to the consumer, so the rest of the pipeline does not depend on the backend.
+pub trait Backend {
+    fn initialize(&mut self) -> Result<(), BoxErr>;
+    /// Starts the capture again after an error. The devices must be kept.
+    fn reconnect(&mut self) -> Result<(), BoxErr> {
+        self.initialize()
+    }
+    /// Gathers advertisements for the wait time and gives them to the consumer.
+    fn consume(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr>;
+    /// Gives every sensor found within the wait time to the consumer once.
+    fn list(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr>;
+    /// Writes every advertisement the backend captures to the recorder.
+    fn set_recorder(&mut self, recorder: Recorder);
+    /// True when there is nothing more to capture, e.g. the end of a recording.
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
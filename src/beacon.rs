@@ -0,0 +1,238 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Value};
+use bt_device::BTDevice;
+
+pub struct BeaconConstructor;
+
+impl BeaconConstructor {
+    pub fn new() -> Box<BeaconConstructor> {
+        Box::new(BeaconConstructor{})
+    }
+}
+
+impl BTSensorConstructor for BeaconConstructor {
+    fn get_name(&self) -> &'static str {
+        "Beacon"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(Beacon::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        Beacon::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct Beacon {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for Beacon {
+
+    fn is_valid_data(&self) -> bool {
+        Beacon::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements()
+            .map(|m| {
+                format!(
+                    "{} {}\nmajor {}\tminor {}\tmeasured power {} dBm",
+                    m.beacon_type,
+                    m.uuid,
+                    m.major,
+                    m.minor,
+                    m.measured_power,
+                )
+            })
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        match self._get_measurements() {
+            Some(m) => {
+                let mut map = HashMap::<String, Value>::new();
+                map.insert(
+                    "beacon_type".to_string(),
+                    Value::String(m.beacon_type),
+                );
+                map.insert(
+                    "uuid".to_string(),
+                    Value::String(m.uuid),
+                );
+                map.insert(
+                    "major".to_string(),
+                    Value::Integer(m.major as i64),
+                );
+                map.insert(
+                    "minor".to_string(),
+                    Value::Integer(m.minor as i64),
+                );
+                map.insert(
+                    "measured_power".to_string(),
+                    Value::Integer(m.measured_power as i64),
+                );
+                Some(map)
+            },
+            None => None,
+        }
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+static IBEACON_MFR_DATA_FIELD: u16 = 0x004C;
+const IBEACON_PREFIX: [u8; 2] = [0x02, 0x15];
+const IBEACON_DATA_LEN: usize = 23;
+// AltBeacon can be sent with any company id, it is recognized by the beacon code.
+const ALTBEACON_PREFIX: [u8; 2] = [0xBE, 0xAC];
+const ALTBEACON_DATA_LEN: usize = 24;
+
+impl Beacon {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> Beacon {
+        Beacon{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        device.get_mfr_data()
+            .is_some_and(|m| Beacon::decode(m).is_some())
+    }
+
+    /// Formats the 16 byte proximity UUID as 8-4-4-4-12 hex digits.
+    pub fn format_uuid(data: &[u8]) -> String {
+        let hex: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(),
+            hex[8..10].concat(), hex[10..16].concat(),
+        )
+    }
+
+    // The iBeacon and AltBeacon layouts differ only in the prefix and in the reserved byte at
+    // the end of AltBeacon. All values are big endian.
+    //
+    // 0-1     0x02 0x15 for iBeacon, 0xBE 0xAC for AltBeacon
+    // 2-17    proximity UUID
+    // 18-19   major
+    // 20-21   minor
+    // 22      measured power, RSSI at one meter as signed dBm
+    // 23      AltBeacon only, reserved for the manufacturer
+    pub fn decode_frame(beacon_type: &str, data: &[u8]) -> BeaconMeas {
+        BeaconMeas{
+            beacon_type: beacon_type.to_string(),
+            uuid: Beacon::format_uuid(&data[2..18]),
+            major: ((data[18] as u16) << 8) | data[19] as u16,
+            minor: ((data[20] as u16) << 8) | data[21] as u16,
+            measured_power: data[22] as i8,
+            ..Default::default()
+        }
+    }
+
+    pub fn decode(mfr_data: &HashMap<u16, Vec<u8>>) -> Option<BeaconMeas> {
+        if let Some(data) = mfr_data.get(&IBEACON_MFR_DATA_FIELD) {
+            if data.len() == IBEACON_DATA_LEN && data[0..2] == IBEACON_PREFIX {
+                return Some(Beacon::decode_frame("ibeacon", data));
+            }
+        }
+        // The lowest company id if there are several, so that the choice does not depend on
+        // the order of the map.
+        mfr_data
+            .iter()
+            .filter(|(_, data)| data.len() == ALTBEACON_DATA_LEN && data[0..2] == ALTBEACON_PREFIX)
+            .min_by_key(|(id, _)| **id)
+            .map(|(_, data)| Beacon::decode_frame("altbeacon", data))
+    }
+
+    fn _get_measurements(&self) -> Option<BeaconMeas> {
+        let device = self.get_bt_device();
+        let mut meas = Beacon::decode(device.get_mfr_data()?)?;
+        meas.address = device.get_address().to_string();
+        meas.tag = device.get_tag().to_string();
+        Some(meas)
+    }
+
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct BeaconMeas {
+    beacon_type: String,
+    uuid: String,
+    major: u16,
+    minor: u16,
+    measured_power: i8,
+    address: String,
+    tag: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+
+    #[test]
+    fn decodes_ibeacon() {
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(IBEACON_MFR_DATA_FIELD, hex::decode(
+            "0215E2C56DB5DFFB48D2B060D0F5A71096E000010002C5").unwrap());
+        let meas = Beacon::decode(&mfr_data).unwrap();
+        assert_eq!(meas.beacon_type, "ibeacon");
+        assert_eq!(meas.uuid, "E2C56DB5-DFFB-48D2-B060-D0F5A71096E0");
+        assert_eq!(meas.major, 1);
+        assert_eq!(meas.minor, 2);
+        assert_eq!(meas.measured_power, -59);
+
+        // The iBeacon prefix with another company id is not an iBeacon.
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(0x0059, hex::decode("0215E2C56DB5DFFB48D2B060D0F5A71096E000010002C5").unwrap());
+        assert!(Beacon::decode(&mfr_data).is_none());
+    }
+
+    #[test]
+    fn decodes_altbeacon() {
+        // The example of the AltBeacon specification with the company id of Radius Networks.
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(0x0118, hex::decode(
+            "BEAC2F234454CF6D4A0FADF2F4911BA9FFA600010002C500").unwrap());
+        let meas = Beacon::decode(&mfr_data).unwrap();
+        assert_eq!(meas.beacon_type, "altbeacon");
+        assert_eq!(meas.uuid, "2F234454-CF6D-4A0F-ADF2-F4911BA9FFA6");
+        assert_eq!(meas.major, 1);
+        assert_eq!(meas.minor, 2);
+        assert_eq!(meas.measured_power, -59);
+
+        // Of several AltBeacon frames the one of the lowest company id is decoded.
+        mfr_data.insert(0x0059, hex::decode(
+            "BEAC2F234454CF6D4A0FADF2F4911BA9FFA600030004C500").unwrap());
+        assert_eq!(Beacon::decode(&mfr_data).unwrap().major, 3);
+
+        mfr_data.clear();
+        mfr_data.insert(0x0118, hex::decode("BEAC2F234454CF6D4A0FADF2F4911BA9FFA600010002C5").unwrap());
+        assert!(Beacon::decode(&mfr_data).is_none());
+    }
+
+}
@@ -1,16 +1,98 @@
 use std::collections::HashMap;
+use std::rc::Rc;
 use std::time::{SystemTime, Duration};
 
-use bt_sensor::BTSensor;
+use bt_sensor::{BTSensor, Sequence, Value};
 use discovery_mode::DiscoveryMode;
+use frame_counters::{FrameCheck, FrameCounters};
+
+/// Radio link information of the latest advertisement. The backends report only the values
+/// they got, so the missing ones keep their previous values.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct LinkInfo {
+    pub rssi: Option<i16>,
+    pub tx_power: Option<i16>,
+    pub address_type: Option<String>,
+    pub name: Option<String>,
+    /// The adapter that heard the advertisement, e.g. hci0.
+    pub adapter: Option<String>,
+}
+
+impl LinkInfo {
+
+    pub fn update(&mut self, other: LinkInfo) {
+        if other.rssi.is_some() {
+            self.rssi = other.rssi;
+        }
+        if other.tx_power.is_some() {
+            self.tx_power = other.tx_power;
+        }
+        if other.address_type.is_some() {
+            self.address_type = other.address_type;
+        }
+        if other.name.is_some() {
+            self.name = other.name;
+        }
+        if other.adapter.is_some() {
+            self.adapter = other.adapter;
+        }
+    }
+
+    /// The numeric values as measurement fields. The advertised TX power is written as
+    /// adv_tx_power so that it does not conflict with the tx_power some sensors report in
+    /// their data. The address type and the name are tags, not measurements.
+    pub fn get_values(&self) -> HashMap<String, Value> {
+        let mut map = HashMap::<String, Value>::new();
+        if let Some(r) = self.rssi {
+            map.insert("rssi".to_string(), Value::Integer(r as i64));
+        }
+        if let Some(t) = self.tx_power {
+            map.insert("adv_tx_power".to_string(), Value::Integer(t as i64));
+        }
+        map
+    }
+
+    pub fn get_tags(&self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        if let Some(ref t) = self.address_type {
+            map.insert("address_type".to_string(), t.to_string());
+        }
+        if let Some(ref n) = self.name {
+            map.insert("name".to_string(), n.to_string());
+        }
+        if let Some(ref a) = self.adapter {
+            map.insert("adapter".to_string(), a.to_string());
+        }
+        map
+    }
+
+    pub fn get_measurements_str(&self) -> String {
+        let opt_str = |v: Option<String>| v.unwrap_or_else(|| "N/A".to_string());
+        format!(
+            "rssi {} dBm\tadv tx power {} dBm\taddress type {}\tname {}\tadapter {}",
+            opt_str(self.rssi.map(|r| r.to_string())),
+            opt_str(self.tx_power.map(|t| t.to_string())),
+            opt_str(self.address_type.clone()),
+            opt_str(self.name.clone()),
+            opt_str(self.adapter.clone()),
+        )
+    }
+
+}
 
 pub struct BTDevice {
     address: String,
     tag: String,
-    object_path: String,
+    key: Option<Vec<u8>>,
+    expected_mac: Option<String>,
+    frame_counters: Rc<FrameCounters>,
+    sequence: Option<(Sequence, SystemTime)>,
     mfr_data: Option<HashMap<u16, Vec<u8>>>,
+    latest_mfr_id: Option<u16>,
     svc_data: Option<HashMap<String, Vec<u8>>>,
     measurement_timestamp: u64,
+    link_info: LinkInfo,
+    adapter_rssi: HashMap<String, (Option<i16>, SystemTime)>,
     last_seen: SystemTime,
     last_seen_forget: Duration,
     discovery_mode: DiscoveryMode,
@@ -20,7 +102,6 @@ pub struct BTDevice {
 impl BTDevice {
 
     pub fn new(
-        object_path: String,
         address: String,
         tag: String,
         mfr_data: Option<HashMap<u16, Vec<u8>>>,
@@ -31,13 +112,20 @@ impl BTDevice {
         ) -> BTDevice
     {
 
+        let latest_mfr_id = BTDevice::changed_mfr_id(None, mfr_data.as_ref());
         BTDevice{
-            address: address,
-            tag: tag,
-            object_path: object_path,
-            mfr_data: mfr_data,
-            svc_data: svc_data,
+            address,
+            tag,
+            key: None,
+            expected_mac: None,
+            frame_counters: Rc::new(FrameCounters::default()),
+            sequence: None,
+            mfr_data,
+            latest_mfr_id,
+            svc_data,
             measurement_timestamp,
+            link_info: LinkInfo::default(),
+            adapter_rssi: HashMap::new(),
             last_seen: SystemTime::now(),
             last_seen_forget,
             discovery_mode,
@@ -46,10 +134,6 @@ impl BTDevice {
 
     }
 
-    pub fn get_object_path(&self) -> &str {
-        &self.object_path
-    }
-
     pub fn get_address(&self) -> &str {
         &self.address
     }
@@ -58,12 +142,29 @@ impl BTDevice {
         self.mfr_data.as_ref()
     }
 
-    pub fn get_svc_data(&self) -> Option<&HashMap<String, Vec<u8>>> {
-        self.svc_data.as_ref()
+    /// The company id of the manufacturer data that changed last. BlueZ keeps the entries of
+    /// the earlier advertisements, so this tells which one is the latest.
+    pub fn get_latest_mfr_id(&self) -> Option<u16> {
+        self.latest_mfr_id
+    }
+
+    fn changed_mfr_id(
+        old: Option<&HashMap<u16, Vec<u8>>>,
+        new: Option<&HashMap<u16, Vec<u8>>>,
+        ) -> Option<u16>
+    {
+        let mut changed = new?
+            .iter()
+            .filter(|&(id, data)| old.and_then(|o| o.get(id)) != Some(data))
+            .map(|(id, _)| *id);
+        match (changed.next(), changed.next()) {
+            (Some(id), None) => Some(id),
+            _ => None,
+        }
     }
 
-    pub fn set_address(&mut self, address: String) {
-        self.address = address;
+    pub fn get_svc_data(&self) -> Option<&HashMap<String, Vec<u8>>> {
+        self.svc_data.as_ref()
     }
 
     pub fn update_data(
@@ -71,12 +172,16 @@ impl BTDevice {
         mfr_data: Option<HashMap<u16,Vec<u8>>>,
         svc_data: Option<HashMap<String, Vec<u8>>>,
         meas_timestamp: u64
-        ) -> ()
+        ) -> bool
     {
-        if self.set_mfr_data(mfr_data) || self.set_svc_data(svc_data) {
+        let mfr_updated = self.set_mfr_data(mfr_data);
+        let svc_updated = self.set_svc_data(svc_data);
+        if mfr_updated || svc_updated {
             self.set_measurement_timestamp(meas_timestamp);
             self.reset_last_seen();
+            return true;
         }
+        false
     }
 
     pub fn set_mfr_data(&mut self, mfr_data: Option<HashMap<u16, Vec<u8>>>) -> bool {
@@ -84,6 +189,7 @@ impl BTDevice {
             debug!("{} mfr data not updated", self.get_address());
             return false
         }
+        self.latest_mfr_id = BTDevice::changed_mfr_id(self.mfr_data.as_ref(), mfr_data.as_ref());
         self.mfr_data = mfr_data;
         true
     }
@@ -105,10 +211,101 @@ impl BTDevice {
         self.measurement_timestamp
     }
 
+    pub fn get_link_info(&self) -> &LinkInfo {
+        &self.link_info
+    }
+
+    pub fn update_link_info(&mut self, link_info: LinkInfo) {
+        self.link_info.update(link_info);
+    }
+
+    /// Remembers that the adapter of the link info heard the device.
+    pub fn record_adapter(&mut self, link_info: &LinkInfo) {
+        if let Some(ref adapter) = link_info.adapter {
+            self.adapter_rssi.insert(adapter.to_string(), (link_info.rssi, SystemTime::now()));
+        }
+    }
+
+    /// True if the adapter of the link info reports another RSSI than it did before. BlueZ
+    /// updates the RSSI when it hears the device again, also when the data is the same.
+    pub fn is_rssi_changed(&self, link_info: &LinkInfo) -> bool {
+        match (&link_info.adapter, link_info.rssi) {
+            (Some(adapter), Some(rssi)) => self.adapter_rssi
+                .get(adapter)
+                .is_none_or(|(previous, _)| *previous != Some(rssi)),
+            _ => false,
+        }
+    }
+
+    /// The adapter with the strongest RSSI of the adapters that have heard the device within
+    /// the given time.
+    pub fn get_best_adapter(&self, within: Duration) -> Option<&str> {
+        self.adapter_rssi
+            .iter()
+            .filter(|(_, (_, seen))| seen.elapsed().map(|e| e < within).unwrap_or(true))
+            .max_by_key(|(_, (rssi, _))| rssi.unwrap_or(i16::MIN))
+            .map(|(adapter, _)| adapter.as_str())
+    }
+
     pub fn get_tag(&self) -> &str {
         &self.tag
     }
 
+    pub fn set_key(&mut self, key: Option<Vec<u8>>) {
+        self.key = key;
+    }
+
+    pub fn get_key(&self) -> Option<&[u8]> {
+        self.key.as_deref()
+    }
+
+    pub fn set_expected_mac(&mut self, mac: Option<String>) {
+        self.expected_mac = mac;
+    }
+
+    /// The MAC address configured for the device in the devicemap, or the address BlueZ
+    /// reports if none is configured.
+    pub fn get_expected_mac(&self) -> &str {
+        self.expected_mac.as_deref().unwrap_or(&self.address)
+    }
+
+    /// Shares the frame counters of the store, so that they outlive the device.
+    pub fn set_frame_counters(&mut self, frame_counters: Rc<FrameCounters>) {
+        self.frame_counters = frame_counters;
+    }
+
+    /// Checks the frame counter of an encrypted advertisement against the last accepted one
+    /// of the address. An older counter is a replayed frame.
+    pub fn check_frame_counter(&self, counter: u32) -> FrameCheck {
+        let check = self.frame_counters.check(&self.address, counter);
+        if check == FrameCheck::Replayed {
+            debug!("{} frame counter {} is older than the last accepted one", self.address, counter);
+        }
+        check
+    }
+
+    /// Accepts the sequence number of a new reading if it is after the last accepted one. An
+    /// adapter that reports the device late gives older readings, those are dropped. A sensor
+    /// that restarts starts the sequence again, so any sequence number is accepted when the
+    /// last one was accepted more than reset_after ago.
+    pub fn accept_sequence(&mut self, sequence: Sequence, reset_after: Duration) -> bool {
+        let accepted = match self.sequence {
+            Some((ref last, accepted_at)) => sequence.is_after(last)
+                || accepted_at.elapsed().map_or(true, |e| e >= reset_after),
+            None => true,
+        };
+        if accepted {
+            self.sequence = Some((sequence, SystemTime::now()));
+        }
+        accepted
+    }
+
+    /// Marks the device heard now without new data, e.g. a beacon whose data never changes.
+    pub fn sight(&mut self, meas_timestamp: u64) {
+        self.set_measurement_timestamp(meas_timestamp);
+        self.reset_last_seen();
+    }
+
     pub fn reset_last_seen(&mut self) {
         self.last_seen = SystemTime::now();
     }
@@ -140,7 +337,7 @@ impl BTDevice {
     }
 
     pub fn get_sensor(&self) -> Option<&dyn BTSensor> {
-        self.bt_sensor.as_ref().map(|b| &**b)
+        self.bt_sensor.as_deref()
     }
 
 }
@@ -1,19 +1,54 @@
 use std::rc::Rc;
 use std::cell::{RefCell, Ref};
 use std::collections::HashMap;
+use std::fmt::Display;
+
+use serde_json;
 
 use bt_device::BTDevice;
-use discovery_mode::DiscoveryMode;
 
 pub trait BTSensor {
 
     fn is_valid_data(&self) -> bool;
 
-    fn get_measurements(&self) -> Option<HashMap<String, Value>>;
-    fn get_measurements_json_str(&self) -> Option<String>;
-    fn get_measurements_str(&self) -> Option<String>;
+    /// The values decoded from the advertisement data.
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>>;
+    fn get_sensor_measurements_json_str(&self) -> Option<String>;
+    fn get_sensor_measurements_str(&self) -> Option<String>;
+
+    /// The sensor measurements with the link values of the device, see LinkInfo.
+    fn get_measurements(&self) -> Option<HashMap<String, Value>> {
+        let mut values = self.get_sensor_measurements()?;
+        values.extend(self.get_bt_device().get_link_info().get_values());
+        Some(values)
+    }
+
+    fn get_measurements_json_str(&self) -> Option<String> {
+        let json = self.get_sensor_measurements_json_str()?;
+        let mut map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&json).ok()?;
+        let device = self.get_bt_device();
+        let link_info = device.get_link_info();
+        for (key, val) in link_info.get_values() {
+            map.insert(key, serde_json::to_value(val).ok()?);
+        }
+        for (key, val) in link_info.get_tags() {
+            map.insert(key, serde_json::Value::String(val));
+        }
+        serde_json::to_string(&map).ok()
+    }
+
+    fn get_measurements_str(&self) -> Option<String> {
+        let s = self.get_sensor_measurements_str()?;
+        Some(format!("{}\n{}", s, self.get_bt_device().get_link_info().get_measurements_str()))
+    }
+
+    /// The measurement sequence number of the reading if the sensor sends one. The readings
+    /// of a device that several adapters hear are deduplicated with it.
+    fn get_sequence(&self) -> Option<Sequence> {
+        None
+    }
 
-    fn get_bt_device(&self) -> Ref<BTDevice>;
+    fn get_bt_device(&self) -> Ref<'_, BTDevice>;
 
     fn get_measurement_timestamp(&self) -> u64;
     fn get_address(&self) -> String;
@@ -23,10 +58,40 @@ pub trait BTSensor {
 
 pub trait BTSensorConstructor {
     fn get_name(&self) -> &'static str;
-    fn construct(&self, device: Rc<RefCell<BTDevice>>, discovery_mode: DiscoveryMode) -> Box<dyn BTSensor>;
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor>;
     fn is_valid_data(&self, device: &BTDevice) -> bool;
 }
 
+/// Formats an optional value for the measurement strings.
+pub fn opt_str<T: Display>(value: &Option<T>) -> String {
+    match *value {
+        Some(ref v) => v.to_string(),
+        None => "N/A".to_string(),
+    }
+}
+
+/// A sequence number that wraps around to zero after modulus values.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sequence {
+    pub value: u32,
+    pub modulus: u32,
+}
+
+impl Sequence {
+
+    /// True if the sequence number is ahead of the previous one by less than half of the
+    /// range, otherwise it is an older reading.
+    pub fn is_after(&self, previous: &Sequence) -> bool {
+        if self.modulus != previous.modulus {
+            return true;
+        }
+        let modulus = self.modulus as u64;
+        let distance = (self.value as u64 + modulus - previous.value as u64 % modulus) % modulus;
+        distance > 0 && distance < modulus / 2
+    }
+
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Value {
@@ -35,3 +100,19 @@ pub enum Value {
 	Float(f64),
 	Boolean(bool),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_wrapping_sequences() {
+        let seq = |value| Sequence{value, modulus: 256};
+        assert!(seq(11).is_after(&seq(10)));
+        assert!(seq(2).is_after(&seq(250)));
+        assert!(!seq(10).is_after(&seq(10)));
+        assert!(!seq(9).is_after(&seq(10)));
+        assert!(!seq(250).is_after(&seq(2)));
+    }
+
+}
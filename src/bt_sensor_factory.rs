@@ -1,18 +1,31 @@
-use std::collections::HashMap;
 use std::cell::RefCell;
 use std::rc::Rc;
 
 use config;
 use bt_sensor::BTSensorConstructor;
 use ruuvitag_df3::RuuvitagDF3Constructor;
+use ruuvitag_df5::RuuvitagDF5Constructor;
+use ruuvitag_df6::RuuvitagDF6Constructor;
+use ruuvitag_df8::RuuvitagDF8Constructor;
+use ruuvitag_dfe1::RuuvitagDFE1Constructor;
+use bthome::BTHomeConstructor;
+use xiaomi_atc::XiaomiATCConstructor;
+use mibeacon::MiBeaconConstructor;
+use govee::GoveeConstructor;
+use inkbird::InkbirdConstructor;
+use switchbot::SwitchBotConstructor;
+use eddystone_tlm::EddystoneTLMConstructor;
+use beacon::BeaconConstructor;
 use ruuvitag_df2::RuuvitagDF2Constructor;
+use ruuvitag_df4::RuuvitagDF4Constructor;
 use bt_device::BTDevice;
 use bt_sensor::{BTSensor};
 use discovery_mode::DiscoveryMode;
 
 pub struct BTSensorFactory {
     conf: config::SensorConf,
-    sensor_constructors: HashMap<&'static str, Box<dyn BTSensorConstructor>>,
+    // In the order autodetection tries them.
+    sensor_constructors: Vec<Box<dyn BTSensorConstructor>>,
 }
 
 impl BTSensorFactory {
@@ -20,7 +33,7 @@ impl BTSensorFactory {
     pub fn new(conf: config::SensorConf) -> BTSensorFactory {
         let mut factory = BTSensorFactory{
             conf,
-            sensor_constructors: HashMap::new(),
+            sensor_constructors: Vec::new(),
         };
         factory.initialize();
         factory
@@ -30,11 +43,24 @@ impl BTSensorFactory {
         self.init_sensor_constructors();
     }
 
+    /// The sensors that are recognized by their own company id, service UUID or name come first,
+    /// the beacons that other devices may send as well are tried last.
     fn init_sensor_constructors(&mut self) {
-        let constr = RuuvitagDF3Constructor::new();
-        self.sensor_constructors.insert(constr.get_name(), constr);
-        let constr = RuuvitagDF2Constructor::new();
-        self.sensor_constructors.insert(constr.get_name(), constr);
+        self.sensor_constructors.push(RuuvitagDF3Constructor::new());
+        self.sensor_constructors.push(RuuvitagDF2Constructor::new());
+        self.sensor_constructors.push(RuuvitagDF4Constructor::new());
+        self.sensor_constructors.push(RuuvitagDF5Constructor::new());
+        self.sensor_constructors.push(RuuvitagDF6Constructor::new());
+        self.sensor_constructors.push(RuuvitagDF8Constructor::new());
+        self.sensor_constructors.push(RuuvitagDFE1Constructor::new());
+        self.sensor_constructors.push(BTHomeConstructor::new());
+        self.sensor_constructors.push(XiaomiATCConstructor::new());
+        self.sensor_constructors.push(MiBeaconConstructor::new());
+        self.sensor_constructors.push(GoveeConstructor::new());
+        self.sensor_constructors.push(InkbirdConstructor::new());
+        self.sensor_constructors.push(SwitchBotConstructor::new());
+        self.sensor_constructors.push(EddystoneTLMConstructor::new());
+        self.sensor_constructors.push(BeaconConstructor::new());
     }
 
     pub fn get_sensor_discovery_mode(&self, address: &str) -> DiscoveryMode {
@@ -53,7 +79,7 @@ impl BTSensorFactory {
                         let is_valid_data = bt_device
                             .borrow()
                             .get_sensor()
-                            .map_or(false, |s| s.is_valid_data());
+                            .is_some_and(|s| s.is_valid_data());
                         if is_valid_data {
                             return;
                         }
@@ -71,7 +97,7 @@ impl BTSensorFactory {
                 let is_valid_data = bt_device
                     .borrow()
                     .get_sensor()
-                    .map_or(false, |s| s.is_valid_data());
+                    .is_some_and(|s| s.is_valid_data());
                 if is_valid_data {
                     return;
                 }
@@ -91,32 +117,57 @@ impl BTSensorFactory {
         if sensor_type == "auto" {
             return self.autofind_sensor_type(bt_device)
         }
-        match self.sensor_constructors.get(sensor_type) {
-            Some(constructor) => {
-                let name = constructor.get_name().to_string();
-                Some(constructor.construct(bt_device, DiscoveryMode::Configured(name)))
-            },
-            None => None,
-        }
+        self.sensor_constructors
+            .iter()
+            .find(|c| c.get_name() == sensor_type)
+            .map(|c| c.construct(bt_device))
+    }
+
+    /// Constructs every sensor type that accepts the data of the device, sorted by name.
+    pub fn find_sensor_types(&self, bt_device: Rc<RefCell<BTDevice>>) -> Vec<(&'static str, Box<dyn BTSensor>)> {
+        let mut sensors: Vec<(&'static str, Box<dyn BTSensor>)> = self.sensor_constructors
+            .iter()
+            .filter(|c| c.is_valid_data(&bt_device.borrow()))
+            .map(|c| (c.get_name(), c.construct(bt_device.clone())))
+            .collect();
+        sensors.sort_by_key(|s| s.0);
+        sensors
     }
 
     fn autofind_sensor_type(&self, bt_device: Rc<RefCell<BTDevice>>) -> Option<Box<dyn BTSensor>> {
-        for (_, v) in &self.sensor_constructors {
-            let is_valid_data = v.is_valid_data(&bt_device.borrow());
-            match is_valid_data {
-                true => {
-                    return Some(
-                        v.construct(
-                            bt_device,
-                            DiscoveryMode::Auto
-                        )
-                    )
-                },
-                false => {}
-            }
-        }
-        None
+        self.sensor_constructors
+            .iter()
+            .find(|c| c.is_valid_data(&bt_device.borrow()))
+            .map(|c| c.construct(bt_device))
     }
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use hex;
+
+    #[test]
+    fn autofinds_sensor_in_order() {
+        // A Ruuvi tag that sends an AltBeacon as well is a Ruuvi tag, whatever the order of
+        // the manufacturer data.
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(0x0499, hex::decode("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F").unwrap());
+        mfr_data.insert(0x0118, hex::decode("BEAC2F234454CF6D4A0FADF2F4911BA9FFA600010002C500").unwrap());
+        let device = BTDevice::new(
+            String::new(), String::new(),
+            Some(mfr_data), None, 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        let device = Rc::new(RefCell::new(device));
+        let factory = BTSensorFactory::new(config::SensorConf::default());
+        let sensor = factory.autofind_sensor_type(device.clone()).unwrap();
+        assert!(sensor.get_sensor_measurements().unwrap().contains_key("pressure"));
+
+        let names: Vec<&str> = factory.find_sensor_types(device).iter().map(|s| s.0).collect();
+        assert_eq!(names, vec!["Beacon", "RuuvitagDF5"]);
+    }
+
+}
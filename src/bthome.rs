@@ -0,0 +1,518 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use hex;
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Sequence, Value};
+use bt_device::BTDevice;
+use error::DecodeError;
+use crypto;
+use frame_counters::FrameCheck;
+
+pub struct BTHomeConstructor;
+
+impl BTHomeConstructor {
+    pub fn new() -> Box<BTHomeConstructor> {
+        Box::new(BTHomeConstructor{})
+    }
+}
+
+impl BTSensorConstructor for BTHomeConstructor {
+    fn get_name(&self) -> &'static str {
+        "BTHome"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(BTHome::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        BTHome::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct BTHome {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for BTHome {
+
+    /// The packet id of an unencrypted advertisement. The frame counter of the encrypted ones
+    /// is checked when they are decrypted.
+    fn get_sequence(&self) -> Option<Sequence> {
+        let device = self.get_bt_device();
+        let data = device.get_svc_data()?.get(SVC_DATA_UUID)?;
+        if BTHome::is_encrypted(*data.first()?) {
+            return None;
+        }
+        let values = BTHome::parse_objects(&data[1..]).ok()?;
+        match values.iter().find(|(name, _)| name == "packet_id") {
+            Some((_, Value::Integer(id))) => Some(Sequence{value: *id as u32, modulus: 0x100}),
+            _ => None,
+        }
+    }
+
+    fn is_valid_data(&self) -> bool {
+        BTHome::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        let values = self._get_measurements()?;
+        let mut map = serde_json::Map::new();
+        for (key, val) in values {
+            map.insert(key, serde_json::to_value(val).ok()?);
+        }
+        map.insert("address".to_string(), serde_json::Value::String(self.get_address()));
+        map.insert("tag".to_string(), serde_json::Value::String(self.get_tag()));
+        serde_json::to_string(&map).ok()
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        let values = self._get_measurements()?;
+        let lines: Vec<String> = values
+            .iter()
+            .map(|(key, val)| format!("{} {}", key, BTHome::value_str(val)))
+            .collect();
+        Some(lines.join("\n"))
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        self._get_measurements().map(|values| values.into_iter().collect())
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+pub static SVC_DATA_UUID: &str = "0000fcd2-0000-1000-8000-00805f9b34fb";
+const ENCRYPTION_FLAG: u8 = 0x01;
+const TRIGGER_BASED_FLAG: u8 = 0x04;
+const VERSION: u8 = 2;
+const UUID16: [u8; 2] = [0xD2, 0xFC];
+const COUNTER_LEN: usize = 4;
+
+/// How the bytes of an object are turned into measurement values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Integer,
+    Float,
+    Boolean,
+    // Written as integer and float, the same way as the ruuvitag humidity.
+    Humidity,
+    Button,
+    Dimmer,
+    Text,
+    Raw,
+    Version,
+}
+
+struct ObjectType {
+    id: u8,
+    name: &'static str,
+    len: usize,
+    signed: bool,
+    factor: f64,
+    kind: Kind,
+}
+
+macro_rules! object {
+    ($id:expr, $name:expr, $len:expr, $signed:expr, $factor:expr, $kind:ident) => {
+        ObjectType{id: $id, name: $name, len: $len, signed: $signed, factor: $factor, kind: Kind::$kind}
+    };
+}
+
+// See https://bthome.io/format/ for the object ids. Field names that are also written by the
+// ruuvitag decoders use the same units and value types: pressure is in pascals and the
+// battery percentage is separated from the battery voltage. Text and raw objects have the
+// length in the first data byte, their len here is zero.
+static OBJECT_TYPES: &[ObjectType] = &[
+    object!(0x00, "packet_id", 1, false, 1.0, Integer),
+    object!(0x01, "battery_percent", 1, false, 1.0, Integer),
+    object!(0x02, "temperature", 2, true, 0.01, Float),
+    object!(0x03, "humidity", 2, false, 0.01, Humidity),
+    object!(0x04, "pressure", 3, false, 1.0, Integer),
+    object!(0x05, "illuminance", 3, false, 0.01, Float),
+    object!(0x06, "mass_kg", 2, false, 0.01, Float),
+    object!(0x07, "mass_lb", 2, false, 0.01, Float),
+    object!(0x08, "dewpoint", 2, true, 0.01, Float),
+    object!(0x09, "count", 1, false, 1.0, Integer),
+    object!(0x0A, "energy", 3, false, 0.001, Float),
+    object!(0x0B, "power", 3, false, 0.01, Float),
+    object!(0x0C, "voltage", 2, false, 0.001, Float),
+    object!(0x0D, "pm2_5", 2, false, 1.0, Float),
+    object!(0x0E, "pm10_0", 2, false, 1.0, Float),
+    object!(0x0F, "generic_boolean", 1, false, 1.0, Boolean),
+    object!(0x10, "power_on", 1, false, 1.0, Boolean),
+    object!(0x11, "opening", 1, false, 1.0, Boolean),
+    object!(0x12, "co2", 2, false, 1.0, Integer),
+    object!(0x13, "tvoc", 2, false, 1.0, Integer),
+    object!(0x14, "moisture", 2, false, 0.01, Float),
+    object!(0x15, "battery_low", 1, false, 1.0, Boolean),
+    object!(0x16, "battery_charging", 1, false, 1.0, Boolean),
+    object!(0x17, "carbon_monoxide", 1, false, 1.0, Boolean),
+    object!(0x18, "cold", 1, false, 1.0, Boolean),
+    object!(0x19, "connectivity", 1, false, 1.0, Boolean),
+    object!(0x1A, "door", 1, false, 1.0, Boolean),
+    object!(0x1B, "garage_door", 1, false, 1.0, Boolean),
+    object!(0x1C, "gas_detected", 1, false, 1.0, Boolean),
+    object!(0x1D, "heat", 1, false, 1.0, Boolean),
+    object!(0x1E, "light", 1, false, 1.0, Boolean),
+    object!(0x1F, "lock", 1, false, 1.0, Boolean),
+    object!(0x20, "moisture_detected", 1, false, 1.0, Boolean),
+    object!(0x21, "motion", 1, false, 1.0, Boolean),
+    object!(0x22, "moving", 1, false, 1.0, Boolean),
+    object!(0x23, "occupancy", 1, false, 1.0, Boolean),
+    object!(0x24, "plug", 1, false, 1.0, Boolean),
+    object!(0x25, "presence", 1, false, 1.0, Boolean),
+    object!(0x26, "problem", 1, false, 1.0, Boolean),
+    object!(0x27, "running", 1, false, 1.0, Boolean),
+    object!(0x28, "safety", 1, false, 1.0, Boolean),
+    object!(0x29, "smoke", 1, false, 1.0, Boolean),
+    object!(0x2A, "sound", 1, false, 1.0, Boolean),
+    object!(0x2B, "tamper", 1, false, 1.0, Boolean),
+    object!(0x2C, "vibration", 1, false, 1.0, Boolean),
+    object!(0x2D, "window", 1, false, 1.0, Boolean),
+    object!(0x2E, "humidity", 1, false, 1.0, Humidity),
+    object!(0x2F, "moisture", 1, false, 1.0, Float),
+    object!(0x3A, "button", 1, false, 1.0, Button),
+    object!(0x3C, "dimmer", 2, false, 1.0, Dimmer),
+    object!(0x3D, "count", 2, false, 1.0, Integer),
+    object!(0x3E, "count", 4, false, 1.0, Integer),
+    object!(0x3F, "rotation", 2, true, 0.1, Float),
+    object!(0x40, "distance_mm", 2, false, 1.0, Integer),
+    object!(0x41, "distance_m", 2, false, 0.1, Float),
+    object!(0x42, "duration", 3, false, 0.001, Float),
+    object!(0x43, "current", 2, false, 0.001, Float),
+    object!(0x44, "speed", 2, false, 0.01, Float),
+    object!(0x45, "temperature", 2, true, 0.1, Float),
+    object!(0x46, "uv_index", 1, false, 0.1, Float),
+    object!(0x47, "volume", 2, false, 0.1, Float),
+    object!(0x48, "volume_ml", 2, false, 1.0, Integer),
+    object!(0x49, "volume_flow_rate", 2, false, 0.001, Float),
+    object!(0x4A, "voltage", 2, false, 0.1, Float),
+    object!(0x4B, "gas", 3, false, 0.001, Float),
+    object!(0x4C, "gas", 4, false, 0.001, Float),
+    object!(0x4D, "energy", 4, false, 0.001, Float),
+    object!(0x4E, "volume", 4, false, 0.001, Float),
+    object!(0x4F, "water", 4, false, 0.001, Float),
+    object!(0x50, "timestamp", 4, false, 1.0, Integer),
+    object!(0x51, "acceleration", 2, false, 0.001, Float),
+    object!(0x52, "gyroscope", 2, false, 0.001, Float),
+    object!(0x53, "text", 0, false, 1.0, Text),
+    object!(0x54, "raw", 0, false, 1.0, Raw),
+    object!(0x55, "volume_storage", 4, false, 0.001, Float),
+    object!(0x56, "conductivity", 2, false, 1.0, Integer),
+    object!(0x57, "temperature", 1, true, 1.0, Float),
+    object!(0x58, "temperature", 1, true, 0.35, Float),
+    object!(0x59, "count", 1, true, 1.0, Integer),
+    object!(0x5A, "count", 2, true, 1.0, Integer),
+    object!(0x5B, "count", 4, true, 1.0, Integer),
+    object!(0x5C, "power", 4, true, 0.01, Float),
+    object!(0x5D, "current", 2, true, 0.001, Float),
+    object!(0x5E, "direction", 2, false, 0.01, Float),
+    object!(0x5F, "precipitation", 2, false, 0.1, Float),
+    object!(0x60, "channel", 1, false, 1.0, Integer),
+    object!(0x61, "rotational_speed", 2, false, 1.0, Integer),
+    object!(0xF0, "device_type_id", 2, false, 1.0, Integer),
+    object!(0xF1, "firmware_version", 4, false, 1.0, Version),
+    object!(0xF2, "firmware_version", 3, false, 1.0, Version),
+];
+
+impl BTHome {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> BTHome {
+        BTHome{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        match device.get_svc_data().and_then(|m| m.get(SVC_DATA_UUID)) {
+            Some(data) => data.len() > 1 && BTHome::get_version(data[0]) == VERSION,
+            None => false,
+        }
+    }
+
+    pub fn get_version(device_info: u8) -> u8 {
+        device_info >> 5
+    }
+
+    pub fn is_encrypted(device_info: u8) -> bool {
+        device_info & ENCRYPTION_FLAG != 0
+    }
+
+    pub fn is_trigger_based(device_info: u8) -> bool {
+        device_info & TRIGGER_BASED_FLAG != 0
+    }
+
+    fn value_str(val: &Value) -> String {
+        match *val {
+            Value::String(ref s) => s.to_string(),
+            Value::Integer(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Boolean(b) => b.to_string(),
+        }
+    }
+
+    /// Little endian integer of 1-4 bytes.
+    pub fn read_int(data: &[u8], signed: bool) -> i64 {
+        let mut raw: u64 = 0;
+        for (i, b) in data.iter().enumerate() {
+            raw |= (*b as u64) << (8 * i);
+        }
+        let bits = 8 * data.len();
+        if signed && bits > 0 && raw & (1 << (bits - 1)) != 0 {
+            raw as i64 - (1_i64 << bits)
+        } else {
+            raw as i64
+        }
+    }
+
+    /// Dividing by the inverse keeps e.g. 2506 * 0.01 as 25.06 instead of 25.060000000000002.
+    pub fn scale(raw: i64, factor: f64) -> f64 {
+        raw as f64 / (1.0 / factor)
+    }
+
+    fn button_event(raw: u8) -> &'static str {
+        match raw {
+            0x00 => "none",
+            0x01 => "press",
+            0x02 => "double_press",
+            0x03 => "triple_press",
+            0x04 => "long_press",
+            0x05 => "long_double_press",
+            0x06 => "long_triple_press",
+            0x80 => "hold_press",
+            _ => "unknown",
+        }
+    }
+
+    fn object_values(obj: &ObjectType, data: &[u8]) -> Vec<(&'static str, Value)> {
+        match obj.kind {
+            Kind::Integer => vec![
+                (obj.name, Value::Integer(BTHome::read_int(data, obj.signed))),
+            ],
+            Kind::Float => vec![
+                (obj.name, Value::Float(BTHome::scale(BTHome::read_int(data, obj.signed), obj.factor))),
+            ],
+            Kind::Boolean => vec![
+                (obj.name, Value::Boolean(data[0] != 0)),
+            ],
+            Kind::Humidity => {
+                let humidity = BTHome::scale(BTHome::read_int(data, obj.signed), obj.factor);
+                vec![
+                    ("humidity", Value::Integer(humidity.round() as i64)),
+                    ("humidity_float", Value::Float(humidity)),
+                ]
+            },
+            Kind::Button => vec![
+                (obj.name, Value::String(BTHome::button_event(data[0]).to_string())),
+            ],
+            Kind::Dimmer => {
+                // Rotating left is reported as negative steps.
+                let steps = data[1] as i64;
+                let steps = match data[0] {
+                    0x01 => -steps,
+                    0x02 => steps,
+                    _ => 0,
+                };
+                vec![(obj.name, Value::Integer(steps))]
+            },
+            Kind::Text => vec![
+                (obj.name, Value::String(String::from_utf8_lossy(data).to_string())),
+            ],
+            Kind::Raw => vec![
+                (obj.name, Value::String(hex::encode(data))),
+            ],
+            Kind::Version => {
+                let parts: Vec<String> = data.iter().rev().map(|b| b.to_string()).collect();
+                vec![(obj.name, Value::String(parts.join(".")))]
+            },
+        }
+    }
+
+    /// Parses the (decrypted) objects that follow the device info byte. Every object becomes
+    /// one or more values, an object that appears several times in the same advertisement gets
+    /// a running number suffix starting from the second instance, e.g. temperature_2.
+    pub fn parse_objects(payload: &[u8]) -> Result<Vec<(String, Value)>, DecodeError> {
+        let mut values = Vec::new();
+        let mut instances: HashMap<&'static str, usize> = HashMap::new();
+        let mut i = 0;
+        while i < payload.len() {
+            let id = payload[i];
+            let obj = match OBJECT_TYPES.iter().find(|o| o.id == id) {
+                Some(o) => o,
+                None => {
+                    return Err(DecodeError::new(format!("Unknown BTHome object id 0x{:02X}", id)));
+                },
+            };
+            i += 1;
+            let len = match obj.kind {
+                Kind::Text | Kind::Raw => {
+                    let len = *payload.get(i)
+                        .ok_or_else(|| DecodeError::new(format!("Truncated BTHome object {}", obj.name)))?;
+                    i += 1;
+                    len as usize
+                },
+                _ => obj.len,
+            };
+            if i + len > payload.len() {
+                return Err(DecodeError::new(format!("Truncated BTHome object {}", obj.name)));
+            }
+            let instance = {
+                let count = instances.entry(obj.name).or_insert(0);
+                *count += 1;
+                *count
+            };
+            for (name, val) in BTHome::object_values(obj, &payload[i..i + len]) {
+                let key = match instance {
+                    1 => name.to_string(),
+                    n => format!("{}_{}", name, n),
+                };
+                values.push((key, val));
+            }
+            i += len;
+        }
+        Ok(values)
+    }
+
+    // See https://bthome.io/encryption/ for the specification. The encrypted objects are
+    // followed by a four byte frame counter and the four byte MIC. The nonce is the MAC
+    // address, the UUID, the device info byte and the frame counter.
+    pub fn decrypt(data: &[u8], key: &[u8], mac: &[u8]) -> Result<(Vec<u8>, u32), DecodeError> {
+        if data.len() < 1 + COUNTER_LEN + crypto::MIC_LEN {
+            return Err(DecodeError::new("Encrypted BTHome advertisement too short".to_string()));
+        }
+        let mic_start = data.len() - crypto::MIC_LEN;
+        let counter_start = mic_start - COUNTER_LEN;
+        let counter_bytes = &data[counter_start..mic_start];
+
+        let mut nonce = mac.to_vec();
+        nonce.extend_from_slice(&UUID16);
+        nonce.push(data[0]);
+        nonce.extend_from_slice(counter_bytes);
+
+        let payload = crypto::aes_ccm_decrypt(
+            key, &nonce, &[], &data[1..counter_start], &data[mic_start..])?;
+        let counter = BTHome::read_int(counter_bytes, false) as u32;
+        Ok((payload, counter))
+    }
+
+    fn decrypt_device_data(device: &BTDevice, data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        let key = device.get_key()
+            .ok_or_else(|| DecodeError::new("No bindkey configured in the devicemap".to_string()))?;
+        let mac = crypto::mac_to_bytes(device.get_expected_mac())
+            .ok_or_else(|| DecodeError::new(format!("Invalid MAC address {}", device.get_expected_mac())))?;
+        let (payload, counter) = BTHome::decrypt(data, key, &mac)?;
+        // The counter is checked only after the MIC so that forged frames cannot move it.
+        if device.check_frame_counter(counter) == FrameCheck::Replayed {
+            return Err(DecodeError::new(
+                format!("Replayed frame, counter {} is older than the last accepted one", counter)));
+        }
+        Ok(payload)
+    }
+
+    fn _get_measurements(&self) -> Option<Vec<(String, Value)>> {
+        let device = self.get_bt_device();
+        let data = device
+            .get_svc_data()?
+            .get(SVC_DATA_UUID)?;
+        let device_info = *data.first()?;
+        let payload = if BTHome::is_encrypted(device_info) {
+            match BTHome::decrypt_device_data(&device, data) {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Cannot decrypt BTHome data from {}: {}", device.get_address(), e);
+                    return None;
+                },
+            }
+        } else {
+            data[1..].to_vec()
+        };
+        match BTHome::parse_objects(&payload) {
+            Ok(mut values) => {
+                values.push((
+                    "trigger_based".to_string(),
+                    Value::Boolean(BTHome::is_trigger_based(device_info)),
+                ));
+                values.push((
+                    "encrypted".to_string(),
+                    Value::Boolean(BTHome::is_encrypted(device_info)),
+                ));
+                Some(values)
+            },
+            Err(e) => {
+                warn!("Cannot decode BTHome data from {}: {}", device.get_address(), e);
+                None
+            },
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+
+    // The example of https://bthome.io/encryption/
+    const KEY: &str = "231d39c1d7cc1ab1aee224cd096db932";
+    const MAC: &str = "54:48:E6:8F:80:A5";
+    const DATA: &str = "41a47266c95f730011223378237214";
+
+    #[test]
+    fn decrypts_spec_example() {
+        let key = hex::decode(KEY).unwrap();
+        let mac = crypto::mac_to_bytes(MAC).unwrap();
+        let (payload, counter) = BTHome::decrypt(&hex::decode(DATA).unwrap(), &key, &mac).unwrap();
+        assert_eq!(counter, 0x33221100);
+        let values: HashMap<String, Value> = BTHome::parse_objects(&payload).unwrap()
+            .into_iter()
+            .collect();
+        match (&values["temperature"], &values["humidity_float"]) {
+            (&Value::Float(t), &Value::Float(h)) => {
+                assert!((t - 25.06).abs() < 1e-9);
+                assert!((h - 50.55).abs() < 1e-9);
+            },
+            v => panic!("unexpected values {:?}", v),
+        }
+
+        let mut bad_key = key.clone();
+        bad_key[0] ^= 1;
+        assert!(BTHome::decrypt(&hex::decode(DATA).unwrap(), &bad_key, &mac).is_err());
+    }
+
+    #[test]
+    fn rejects_replayed_frame() {
+        let mut svc_data = HashMap::new();
+        svc_data.insert(SVC_DATA_UUID.to_string(), hex::decode(DATA).unwrap());
+        let mut device = BTDevice::new(
+            MAC.to_string(), String::new(),
+            None, Some(svc_data), 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        device.set_key(Some(hex::decode(KEY).unwrap()));
+        let sensor = BTHome::new(Rc::new(RefCell::new(device)));
+        assert!(sensor._get_measurements().is_some());
+        // The same frame again.
+        assert!(sensor._get_measurements().is_some());
+
+        // The sensor has sent a newer frame, the captured one is a replay.
+        assert_eq!(sensor.get_bt_device().check_frame_counter(0x33221101), FrameCheck::New);
+        assert!(sensor._get_measurements().is_none());
+        assert_eq!(sensor.get_bt_device().check_frame_counter(1), FrameCheck::Replayed);
+    }
+
+}
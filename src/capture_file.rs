@@ -0,0 +1,436 @@
+use std::error;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+use error::{DecodeError, ReplayError};
+use hci::{self, Advertisement, Event, EventParser};
+use recording::Record;
+
+type BoxErr = Box<dyn error::Error>;
+// The record header and the packet data.
+type Packet = (Vec<u8>, Vec<u8>);
+
+// btsnoop, written by btmon -w and by the HCI snoop log of Android. See
+// https://fte.com/webhelpii/hsu/Content/Technical_Information/BT_Snoop_File_Format.htm
+const BTSNOOP_MAGIC: &[u8] = b"btsnoop\0";
+const BTSNOOP_H1: u32 = 1001;
+const BTSNOOP_H4: u32 = 1002;
+const BTSNOOP_MONITOR: u32 = 2001;
+// btsnoop has no snapshot length, the HCI packets are much shorter than this.
+const BTSNOOP_MAX_PACKET_LEN: u32 = 64 * 1024;
+// The timestamps are microseconds since midnight January 1st, 0 AD.
+const BTSNOOP_UNIX_EPOCH: i64 = 0x00dc_ddb3_0f2f_8000;
+// H1 flags: bit 0 set when the packet was received, bit 1 set for commands and events.
+const BTSNOOP_H1_EVENT: u32 = 0x03;
+// The monitor flags have the adapter index in the high and the opcode in the low 16 bits.
+const MONITOR_EVENT_PKT: u32 = 0x03;
+
+// pcap, see https://www.tcpdump.org/linktypes.html for the link types.
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+// The section header block type that starts a pcapng file.
+const PCAPNG_MAGIC: u32 = 0x0a0d_0d0a;
+// The snapshot length of tcpdump, larger ones in the header are limited to it.
+const PCAP_MAX_SNAPLEN: u32 = 256 * 1024;
+const DLT_BLUETOOTH_HCI_H4: u32 = 187;
+const DLT_BLUETOOTH_HCI_H4_WITH_PHDR: u32 = 201;
+const DLT_BLUETOOTH_LE_LL: u32 = 251;
+const DLT_BLUETOOTH_LE_LL_WITH_PHDR: u32 = 256;
+// The access address of the advertising channel packets.
+const ADVERTISING_ACCESS_ADDRESS: [u8; 4] = [0xD6, 0xBE, 0x89, 0x8E];
+// The LE_LL_WITH_PHDR flag telling that the signal power is valid.
+const LE_PHDR_SIGNAL_POWER_VALID: u16 = 0x0002;
+
+enum Format {
+    Btsnoop { datalink: u32 },
+    Pcap { big_endian: bool, nanos: bool, linktype: u32, snaplen: u32 },
+}
+
+/// Reads the LE advertising reports from a btsnoop or pcap capture as records that can be
+/// replayed. The other packets are skipped.
+pub struct CaptureReader {
+    reader: Box<dyn BufRead>,
+    format: Format,
+    parser: EventParser,
+    pending: VecDeque<Record>,
+}
+
+fn u32_at(data: &[u8], index: usize, big_endian: bool) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&data[index..index + 4]);
+    match big_endian {
+        true => u32::from_be_bytes(b),
+        false => u32::from_le_bytes(b),
+    }
+}
+
+impl CaptureReader {
+
+    /// Opens the file if it is a btsnoop or pcap capture, otherwise returns None.
+    pub fn open(path: &str) -> Result<Option<CaptureReader>, BoxErr> {
+        let file = File::open(path)
+            .map_err(|e| ReplayError::new(format!("Cannot open {}: {}", path, e)))?;
+        CaptureReader::from_reader(Box::new(BufReader::new(file)), path)
+    }
+
+    fn from_reader(mut reader: Box<dyn BufRead>, path: &str) -> Result<Option<CaptureReader>, BoxErr> {
+        let mut header = [0; 16];
+        let is_btsnoop = reader.fill_buf()?.starts_with(BTSNOOP_MAGIC);
+        let magic = reader.fill_buf()?.get(0..4).map(|m| u32_at(m, 0, false));
+        let format = if is_btsnoop {
+            // magic, version and datalink
+            reader.read_exact(&mut header)?;
+            let datalink = u32_at(&header, 12, true);
+            match datalink {
+                BTSNOOP_H1 | BTSNOOP_H4 | BTSNOOP_MONITOR => Format::Btsnoop{datalink},
+                _ => return Err(Box::new(ReplayError::new(
+                    format!("Unsupported btsnoop datalink {} in {}", datalink, path)))),
+            }
+        } else if let Some(magic) = magic.filter(|m| [PCAP_MAGIC_MICROS, PCAP_MAGIC_NANOS]
+                                                  .iter().any(|p| *p == *m || *p == m.swap_bytes())) {
+            // magic, version, time zone, accuracy, snapshot length and link type
+            let mut pcap_header = [0; 24];
+            reader.read_exact(&mut pcap_header)?;
+            let big_endian = magic == PCAP_MAGIC_MICROS.swap_bytes()
+                || magic == PCAP_MAGIC_NANOS.swap_bytes();
+            let nanos = magic == PCAP_MAGIC_NANOS || magic == PCAP_MAGIC_NANOS.swap_bytes();
+            let snaplen = u32_at(&pcap_header, 16, big_endian).min(PCAP_MAX_SNAPLEN);
+            let linktype = u32_at(&pcap_header, 20, big_endian) & 0x0FFF_FFFF;
+            match linktype {
+                DLT_BLUETOOTH_HCI_H4 | DLT_BLUETOOTH_HCI_H4_WITH_PHDR
+                    | DLT_BLUETOOTH_LE_LL | DLT_BLUETOOTH_LE_LL_WITH_PHDR => (),
+                _ => return Err(Box::new(ReplayError::new(
+                    format!("Unsupported pcap link type {} in {}", linktype, path)))),
+            }
+            Format::Pcap{big_endian, nanos, linktype, snaplen}
+        } else if magic == Some(PCAPNG_MAGIC) {
+            return Err(Box::new(ReplayError::new(
+                format!("{} is a pcapng file, only pcap is supported, save it as pcap", path))));
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(CaptureReader{reader, format, parser: EventParser::default(), pending: VecDeque::new()}))
+    }
+
+    /// Reads the next record header and data, None at the end of the file.
+    fn read_packet(&mut self, header_len: usize) -> Result<Option<Packet>, BoxErr> {
+        let mut header = vec![0; header_len];
+        if let Err(e) = self.reader.read_exact(&mut header) {
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(Box::new(e)),
+            };
+        }
+        let (included_len, max_len) = match self.format {
+            // original length, included length, flags, drops and timestamp
+            Format::Btsnoop{..} => (u32_at(&header, 4, true), BTSNOOP_MAX_PACKET_LEN),
+            // seconds, fraction, included length and original length
+            Format::Pcap{big_endian, snaplen, ..} => (u32_at(&header, 8, big_endian), snaplen),
+        };
+        if included_len > max_len {
+            return Err(Box::new(DecodeError::new(
+                format!("Packet length {} is over the limit {}", included_len, max_len))));
+        }
+        let mut data = vec![0; included_len as usize];
+        if let Err(e) = self.reader.read_exact(&mut data) {
+            // The capture was cut while the packet was written.
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    warn!("Truncated packet at the end of the capture");
+                    Ok(None)
+                },
+                _ => Err(Box::new(e)),
+            };
+        }
+        Ok(Some((header, data)))
+    }
+
+    /// Reads packets until one has advertisements, returns false at the end of the file.
+    fn read_advertisements(&mut self) -> Result<bool, BoxErr> {
+        loop {
+            let (timestamp, adapter, advertisements) = match self.format {
+                Format::Btsnoop{datalink} => {
+                    let (header, data) = match self.read_packet(24)? {
+                        Some(p) => p,
+                        None => return Ok(false),
+                    };
+                    let flags = u32_at(&header, 8, true);
+                    let mut ts = [0; 8];
+                    ts.copy_from_slice(&header[16..24]);
+                    let micros = i64::from_be_bytes(ts) - BTSNOOP_UNIX_EPOCH;
+                    let (event, adapter) = match datalink {
+                        BTSNOOP_H1 if flags & BTSNOOP_H1_EVENT == BTSNOOP_H1_EVENT => {
+                            ([&[hci::HCI_EVENT_PKT], &data[..]].concat(), None)
+                        },
+                        BTSNOOP_MONITOR if flags & 0xFFFF == MONITOR_EVENT_PKT => {
+                            let adapter = format!("hci{}", flags >> 16);
+                            ([&[hci::HCI_EVENT_PKT], &data[..]].concat(), Some(adapter))
+                        },
+                        BTSNOOP_H4 => (data, None),
+                        _ => continue,
+                    };
+                    (micros.max(0) as u64 / 1000, adapter, self.parse_event(&event))
+                },
+                Format::Pcap{big_endian, nanos, linktype, ..} => {
+                    let (header, data) = match self.read_packet(16)? {
+                        Some(p) => p,
+                        None => return Ok(false),
+                    };
+                    let secs = u32_at(&header, 0, big_endian) as u64;
+                    let fraction = u32_at(&header, 4, big_endian) as u64;
+                    let millis = match nanos {
+                        true => fraction / 1_000_000,
+                        false => fraction / 1000,
+                    };
+                    let advertisements = match linktype {
+                        DLT_BLUETOOTH_HCI_H4 => self.parse_event(&data),
+                        // The direction is in the four bytes before the packet.
+                        DLT_BLUETOOTH_HCI_H4_WITH_PHDR => {
+                            self.parse_event(data.get(4..).unwrap_or_default())
+                        },
+                        DLT_BLUETOOTH_LE_LL => CaptureReader::parse_ll_packet(&data),
+                        _ => CaptureReader::parse_ll_packet_with_phdr(&data),
+                    };
+                    (secs * 1000 + millis, None, advertisements)
+                },
+            };
+            if advertisements.is_empty() {
+                continue;
+            }
+            for mut adv in advertisements {
+                adv.link_info.adapter = adapter.clone();
+                let mfr_data = Some(adv.mfr_data).filter(|m| !m.is_empty());
+                let svc_data = Some(adv.svc_data).filter(|s| !s.is_empty());
+                self.pending.push_back(Record::new(
+                    &adv.address, mfr_data.as_ref(), svc_data.as_ref(), &adv.link_info, timestamp));
+            }
+            return Ok(true);
+        }
+    }
+
+    fn parse_event(&mut self, packet: &[u8]) -> Vec<Advertisement> {
+        match self.parser.parse(packet) {
+            Ok(Event::Advertisements(a)) => a,
+            Ok(_) => Vec::new(),
+            Err(e) => {
+                warn!("Cannot parse HCI event: {}", e);
+                Vec::new()
+            },
+        }
+    }
+
+    // 0-3     access address
+    // 4-      PDU
+    fn parse_ll_packet(packet: &[u8]) -> Vec<Advertisement> {
+        if !packet.starts_with(&ADVERTISING_ACCESS_ADDRESS) {
+            return Vec::new();
+        }
+        match hci::parse_advertising_pdu(&packet[4..]) {
+            Ok(adv) => adv.into_iter().collect(),
+            Err(e) => {
+                warn!("Cannot parse advertising PDU: {}", e);
+                Vec::new()
+            },
+        }
+    }
+
+    // 0       RF channel
+    // 1       signal power
+    // 2       noise power
+    // 3       access address offenses
+    // 4-7     reference access address
+    // 8-9     flags
+    // 10-     link layer packet
+    fn parse_ll_packet_with_phdr(packet: &[u8]) -> Vec<Advertisement> {
+        if packet.len() < 10 {
+            return Vec::new();
+        }
+        let flags = ((packet[9] as u16) << 8) | packet[8] as u16;
+        let mut advertisements = CaptureReader::parse_ll_packet(&packet[10..]);
+        if flags & LE_PHDR_SIGNAL_POWER_VALID != 0 {
+            for adv in advertisements.iter_mut() {
+                adv.link_info.rssi = Some(packet[1] as i8 as i16);
+            }
+        }
+        advertisements
+    }
+
+}
+
+impl Iterator for CaptureReader {
+    type Item = Result<Record, BoxErr>;
+
+    fn next(&mut self) -> Option<Result<Record, BoxErr>> {
+        if self.pending.is_empty() {
+            match self.read_advertisements() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use hex;
+
+    // The flags and the DF5 manufacturer data of CB:B8:33:4C:88:4F.
+    const ADV_DATA: &str = "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F";
+    const ADDRESS: &str = "4F884C33B8CB";
+    // 2023-11-14 22:13:20.123 UTC
+    const TIMESTAMP: u64 = 1_700_000_000_123;
+
+    // An H4 event packet with an LE advertising report, RSSI -59.
+    fn event() -> Vec<u8> {
+        hex::decode(format!("043E2B02010301{}1F{}C5", ADDRESS, ADV_DATA)).unwrap()
+    }
+
+    // The access address and an ADV_NONCONN_IND PDU with the CRC.
+    fn ll_packet() -> Vec<u8> {
+        hex::decode(format!("D6BE898E4225{}{}AABBCC", ADDRESS, ADV_DATA)).unwrap()
+    }
+
+    fn btsnoop(datalink: u32, packets: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut file = BTSNOOP_MAGIC.to_vec();
+        file.extend_from_slice(&1u32.to_be_bytes());
+        file.extend_from_slice(&datalink.to_be_bytes());
+        for (flags, data) in packets {
+            file.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            file.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            file.extend_from_slice(&flags.to_be_bytes());
+            file.extend_from_slice(&0u32.to_be_bytes());
+            let micros = BTSNOOP_UNIX_EPOCH + TIMESTAMP as i64 * 1000;
+            file.extend_from_slice(&micros.to_be_bytes());
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    fn pcap(linktype: u32, big_endian: bool, nanos: bool, packets: &[Vec<u8>]) -> Vec<u8> {
+        let u32_bytes = |v: u32| match big_endian {
+            true => v.to_be_bytes(),
+            false => v.to_le_bytes(),
+        };
+        let magic = if nanos { PCAP_MAGIC_NANOS } else { PCAP_MAGIC_MICROS };
+        let mut file = u32_bytes(magic).to_vec();
+        // version 2.4, time zone and accuracy
+        for version in &[2u16, 4] {
+            file.extend_from_slice(&match big_endian {
+                true => version.to_be_bytes(),
+                false => version.to_le_bytes(),
+            });
+        }
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&u32_bytes(65535));
+        file.extend_from_slice(&u32_bytes(linktype));
+        for data in packets {
+            let fraction = (TIMESTAMP % 1000) as u32 * if nanos { 1_000_000 } else { 1000 };
+            file.extend_from_slice(&u32_bytes((TIMESTAMP / 1000) as u32));
+            file.extend_from_slice(&u32_bytes(fraction));
+            file.extend_from_slice(&u32_bytes(data.len() as u32));
+            file.extend_from_slice(&u32_bytes(data.len() as u32));
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    fn read(file: Vec<u8>) -> Vec<Record> {
+        CaptureReader::from_reader(Box::new(Cursor::new(file)), "test")
+            .unwrap()
+            .expect("not a capture")
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    fn assert_df5(record: &Record, rssi: Option<i16>, adapter: Option<&str>) {
+        assert_eq!(record.timestamp, TIMESTAMP);
+        assert_eq!(record.address, "CB:B8:33:4C:88:4F");
+        assert_eq!(record.rssi, rssi);
+        assert_eq!(record.adapter.as_deref(), adapter);
+        assert_eq!(record.get_mfr_data().unwrap().unwrap()[&0x0499][0], 0x05);
+    }
+
+    #[test]
+    fn reads_btsnoop() {
+        // The H4 packets have the packet type, the H1 and monitor ones have it in the flags.
+        let records = read(btsnoop(BTSNOOP_H4, &[(0x03, event())]));
+        assert_eq!(records.len(), 1);
+        assert_df5(&records[0], Some(-59), None);
+
+        let command = hex::decode("0C2002010000").unwrap();
+        let records = read(btsnoop(BTSNOOP_H1, &[(0x02, command.clone()), (0x03, event()[1..].to_vec())]));
+        assert_eq!(records.len(), 1);
+        assert_df5(&records[0], Some(-59), None);
+
+        // The opcode 2 of the monitor is a command, the adapter index is in the high bits.
+        let records = read(btsnoop(BTSNOOP_MONITOR, &[
+            (0x0001_0002, command),
+            (0x0001_0003, event()[1..].to_vec()),
+        ]));
+        assert_eq!(records.len(), 1);
+        assert_df5(&records[0], Some(-59), Some("hci1"));
+    }
+
+    #[test]
+    fn reads_pcap() {
+        let records = read(pcap(DLT_BLUETOOTH_HCI_H4, false, false, &[event()]));
+        assert_eq!(records.len(), 1);
+        assert_df5(&records[0], Some(-59), None);
+
+        let with_phdr = [&[0, 0, 0, 1][..], &event()[..]].concat();
+        let records = read(pcap(DLT_BLUETOOTH_HCI_H4_WITH_PHDR, true, true, &[with_phdr]));
+        assert_eq!(records.len(), 1);
+        assert_df5(&records[0], Some(-59), None);
+    }
+
+    #[test]
+    fn reads_le_ll_pcap() {
+        let records = read(pcap(DLT_BLUETOOTH_LE_LL, false, false, &[ll_packet()]));
+        assert_eq!(records.len(), 1);
+        assert_df5(&records[0], None, None);
+
+        // Channel 37, signal power -59 dBm and the signal power valid flag.
+        let phdr = hex::decode("25C50000D6BE898E0200").unwrap();
+        let packet = [&phdr[..], &ll_packet()[..]].concat();
+        let records = read(pcap(DLT_BLUETOOTH_LE_LL_WITH_PHDR, false, false, &[packet]));
+        assert_eq!(records.len(), 1);
+        assert_df5(&records[0], Some(-59), None);
+    }
+
+    #[test]
+    fn stops_at_truncated_packet() {
+        let mut file = pcap(DLT_BLUETOOTH_HCI_H4, false, false, &[event(), event()]);
+        file.truncate(file.len() - 5);
+        assert_eq!(read(file).len(), 1);
+    }
+
+    #[test]
+    fn rejects_oversized_packet() {
+        // The included length of the first record is over the snapshot length 65535.
+        let mut file = pcap(DLT_BLUETOOTH_HCI_H4, false, false, &[event()]);
+        file[32..36].copy_from_slice(&65536u32.to_le_bytes());
+        let mut reader = CaptureReader::from_reader(Box::new(Cursor::new(file)), "test")
+            .unwrap().unwrap();
+        assert!(reader.next().unwrap().is_err());
+
+        let mut file = btsnoop(BTSNOOP_H4, &[(0x03, event())]);
+        file[20..24].copy_from_slice(&(64 * 1024 + 1u32).to_be_bytes());
+        let mut reader = CaptureReader::from_reader(Box::new(Cursor::new(file)), "test")
+            .unwrap().unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn detects_other_files() {
+        let pcapng = hex::decode("0A0D0D0A1C0000004D3C2B1A").unwrap();
+        assert!(CaptureReader::from_reader(Box::new(Cursor::new(pcapng)), "test").is_err());
+        let ndjson = b"{\"timestamp\":1700000000000}\n".to_vec();
+        assert!(CaptureReader::from_reader(Box::new(Cursor::new(ndjson)), "test").unwrap().is_none());
+    }
+
+}
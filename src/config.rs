@@ -1,23 +1,37 @@
 use std::collections::HashMap;
 use std::fs::File;
+use std::rc::Rc;
 use std::time::Duration;
 
 use serde_json;
+use hex;
 
 use ::Args;
+use device_store::AdapterPolicy;
+use frame_counters::FrameCounters;
+
+// The sensors use AES-128, so all the keys are 16 bytes.
+const KEY_LEN: usize = 16;
 
 #[derive(Clone, Debug)]
 pub struct SensorInfo {
-    address: String,
     tag: String,
     sensor_if: String,
+    key: Option<Vec<u8>>,
+    mac: Option<String>,
 }
 
 impl SensorInfo {
 
-    pub fn new(address: String, tag: String, sensor_if: String) -> SensorInfo {
+    pub fn new(
+        tag: String,
+        sensor_if: String,
+        key: Option<Vec<u8>>,
+        mac: Option<String>,
+        ) -> SensorInfo
+    {
         SensorInfo{
-            address, tag, sensor_if,
+            tag, sensor_if, key, mac,
         }
     }
 
@@ -25,14 +39,18 @@ impl SensorInfo {
         &self.tag
     }
 
-    pub fn get_address(&self) -> &str {
-        &self.address
-    }
-
     pub fn get_sensor_if(&self) -> &str {
         &self.sensor_if
     }
 
+    pub fn get_key(&self) -> Option<&[u8]> {
+        self.key.as_deref()
+    }
+
+    pub fn get_mac(&self) -> Option<&str> {
+        self.mac.as_deref()
+    }
+
 }
 
 #[derive(Default, Clone, Debug)]
@@ -40,6 +58,9 @@ pub struct SensorConf {
     auto: bool,
     address_map: HashMap<String, SensorInfo>,
     last_seen_forget: Duration,
+    adapter_policy: AdapterPolicy,
+    forget_after: Duration,
+    frame_counters: Rc<FrameCounters>,
 }
 
 impl SensorConf {
@@ -56,41 +77,63 @@ impl SensorConf {
             auto: !args.flag_manual,
             address_map: devicemap,
             last_seen_forget: Duration::from_secs(args.flag_interval),
+            adapter_policy: args.flag_adapter_policy,
+            forget_after: Duration::from_secs(args.flag_forget_after),
+            frame_counters: Rc::new(FrameCounters::load(args.flag_frame_counters.as_deref())),
         }
     }
 
     fn parse_devicemap_file(filename: &str) -> HashMap<String, SensorInfo> {
 
         let f = File::open(filename)
-            .expect(&format!("Cannot open file {}", filename));
+            .unwrap_or_else(|_| panic!("Cannot open file {}", filename));
         let v: serde_json::Value = serde_json::from_reader(f)
             .map_err(|e| panic!("JSON error in {}: {}", filename, e))
             .unwrap();
 
         v.as_object()
-            .expect(&format!("Invalid JSON in {}, not an object", filename))
+            .unwrap_or_else(|| panic!("Invalid JSON in {}, not an object", filename))
             .iter()
             .map(|(k, v)| {
-                let val = v.as_object().expect(&format!("Value not an object in {}", filename));
+                let val = v.as_object().unwrap_or_else(|| panic!("Value not an object in {}", filename));
                 let address = k;
                 let tag = val
                     .get("tag")
                     .map(|tag|
-                         tag.as_str().expect(&format!("tag not string in {}, device {}", filename, address))
+                         tag.as_str().unwrap_or_else(|| panic!("tag not string in {}, device {}", filename, address))
                      )
                     .unwrap_or(address);
                 let sensor_if = val
                     .get("sensor_if")
                     .map(|parser|
-                         parser.as_str().expect(&format!("sensor_if not string in {}, device {}", filename, address))
+                         parser.as_str().unwrap_or_else(|| panic!("sensor_if not string in {}, device {}", filename, address))
                      )
                     .unwrap_or("auto");
+                let key = val
+                    .get("key")
+                    .map(|key| {
+                        let key_str = key.as_str()
+                            .unwrap_or_else(|| panic!("key not string in {}, device {}", filename, address));
+                        let key = hex::decode(key_str)
+                            .unwrap_or_else(|_| panic!("key not a hex string in {}, device {}", filename, address));
+                        if key.len() != KEY_LEN {
+                            panic!("key not {} bytes in {}, device {}", KEY_LEN, filename, address);
+                        }
+                        key
+                    });
+                let mac = val
+                    .get("mac")
+                    .map(|mac|
+                         mac.as_str().unwrap_or_else(|| panic!("mac not string in {}, device {}", filename, address))
+                            .to_uppercase()
+                     );
                 (
                     k.to_string(),
                     SensorInfo::new(
-                        address.to_string(),
                         tag.to_string(),
                         sensor_if.to_string(),
+                        key,
+                        mac,
                     )
                 )
             })
@@ -102,13 +145,13 @@ impl SensorConf {
         let mut map = HashMap::new();
         for arg in dev_args {
             let cuts = arg.split(",").collect::<Vec<&str>>();
-            let addr = match cuts.get(0) {
+            let addr = match cuts.first() {
                 Some(a) => a.to_string(),
                 None => continue,
             };
             let tag = cuts.get(1).map(|t| t.to_string()).unwrap_or(addr.clone());
             let sensor_if = cuts.get(2).map(|s| s.to_string()).unwrap_or("auto".to_string());
-            let info = SensorInfo::new(addr.clone(), tag, sensor_if);
+            let info = SensorInfo::new(tag, sensor_if, None, None);
             map.insert(addr, info);
         }
         map
@@ -130,8 +173,34 @@ impl SensorConf {
             .map(|c| c.get_tag())
     }
 
+    pub fn get_sensor_key(&self, address: &str) -> Option<&[u8]> {
+        self.address_map
+            .get(address)
+            .and_then(|c| c.get_key())
+    }
+
+    pub fn get_sensor_mac(&self, address: &str) -> Option<&str> {
+        self.address_map
+            .get(address)
+            .and_then(|c| c.get_mac())
+    }
+
     pub fn get_last_seen_forget(&self) -> Duration {
         self.last_seen_forget
     }
 
+    pub fn get_adapter_policy(&self) -> AdapterPolicy {
+        self.adapter_policy
+    }
+
+    /// How long a device may be unseen before it is removed.
+    pub fn get_forget_after(&self) -> Duration {
+        self.forget_after
+    }
+
+    /// The last accepted frame counters of the encrypted sensors, shared by all devices.
+    pub fn get_frame_counters(&self) -> Rc<FrameCounters> {
+        self.frame_counters.clone()
+    }
+
 }
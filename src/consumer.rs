@@ -15,6 +15,9 @@ pub enum ConsumerType {
 
 pub trait Consumer {
     fn consume(&mut self, sensors: &[&dyn BTSensor]);
+    /// Called after each batch of consume calls, e.g. once per interval when advertisements
+    /// are consumed as they arrive.
+    fn flush(&mut self) {}
 }
 
 pub fn initialize_consumer(consumer_name: &ConsumerType) -> Result<Box<dyn Consumer>, String> {
@@ -37,12 +40,9 @@ impl Consumer for StdOutConsumer {
     fn consume(&mut self, sensors: &[&dyn BTSensor]) {
         for sensor in sensors {
             if sensor.get_bt_device().is_upto_date() {
-                match sensor.get_measurements_str() {
-                    Some(s) => {
-                        println!("Address: {}", sensor.get_address());
-                        println!("{}", s);
-                    },
-                    None => (),
+                if let Some(s) = sensor.get_measurements_str() {
+                    println!("Address: {}", sensor.get_address());
+                    println!("{}", s);
                 }
             }
         }
@@ -55,9 +55,8 @@ impl Consumer for StdOutJsonConsumer {
     fn consume(&mut self, sensors: &[&dyn BTSensor]) {
         for sensor in sensors {
             if sensor.get_bt_device().is_upto_date() {
-                match sensor.get_measurements_json_str() {
-                    Some(s) => println!("{}", s),
-                    None => (),
+                if let Some(s) = sensor.get_measurements_json_str() {
+                    println!("{}", s);
                 }
             }
         }
@@ -97,42 +96,45 @@ impl Consumer for InfluxdbConsumer {
             if !sensor.get_bt_device().is_upto_date() {
                 continue;
             }
-            match sensor.get_measurements() {
-                Some(measurements) => {
-                    let mut point = Point::new("ruuvitag");
-                    point.add_tag(
-                        "tag",
-                        InfluxVal::String(sensor.get_tag().to_string())
-                    );
-                    point.add_tag(
-                        "address",
-                        InfluxVal::String(sensor.get_address().to_string())
-                    );
-                    point.add_timestamp(sensor.get_measurement_timestamp() as i64);
+            if let Some(measurements) = sensor.get_measurements() {
+                let mut point = Point::new("ruuvitag");
+                point.add_tag(
+                    "tag",
+                    InfluxVal::String(sensor.get_tag().to_string())
+                );
+                point.add_tag(
+                    "address",
+                    InfluxVal::String(sensor.get_address().to_string())
+                );
+                for (key, val) in sensor.get_bt_device().get_link_info().get_tags() {
+                    point.add_tag(key, InfluxVal::String(val));
+                }
+                point.add_timestamp(sensor.get_measurement_timestamp() as i64);
 
-                    for (key, val) in measurements {
-                        match val {
-                            Value::String(s) => {
-                                point.add_field(key, InfluxVal::String(s.to_string()));
-                            },
-                            Value::Integer(i) => {
-                                point.add_field(key, InfluxVal::Integer(i));
-                            },
-                            Value::Float(f) => {
-                                point.add_field(key, InfluxVal::Float(f));
-                            },
-                            Value::Boolean(b) => {
-                                point.add_field(key, InfluxVal::Boolean(b));
-                            },
-                        }
+                for (key, val) in measurements {
+                    match val {
+                        Value::String(s) => {
+                            point.add_field(key, InfluxVal::String(s.to_string()));
+                        },
+                        Value::Integer(i) => {
+                            point.add_field(key, InfluxVal::Integer(i));
+                        },
+                        Value::Float(f) => {
+                            point.add_field(key, InfluxVal::Float(f));
+                        },
+                        Value::Boolean(b) => {
+                            point.add_field(key, InfluxVal::Boolean(b));
+                        },
                     }
-                    points_vec.push(point);
-                },
-                None => (),
+                }
+                points_vec.push(point);
             }
         }
         self.measurements.append(&mut points_vec);
-        if self.measurements.len() > 0 {
+    }
+
+    fn flush(&mut self) {
+        if !self.measurements.is_empty() {
             debug!("Writing {} points to influxdb", self.measurements.len());
             let points = Points::create_new(self.measurements.clone());
             match self.client.write_points(points, Some(Precision::Milliseconds), None) {
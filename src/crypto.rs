@@ -0,0 +1,55 @@
+use aes::Aes128;
+use ccm::Ccm;
+use ccm::aead::{Aead, KeyInit, Payload, generic_array::GenericArray};
+use ccm::consts::{U4, U12, U13};
+
+use error::DecodeError;
+
+type Aes128Ccm12 = Ccm<Aes128, U4, U12>;
+type Aes128Ccm13 = Ccm<Aes128, U4, U13>;
+
+pub const MIC_LEN: usize = 4;
+
+/// Decrypts an AES-128-CCM message with a four byte MIC. The MIC is given separately because
+/// the advertisement formats put other fields between the ciphertext and the MIC.
+pub fn aes_ccm_decrypt(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    ciphertext: &[u8],
+    mic: &[u8],
+    ) -> Result<Vec<u8>, DecodeError>
+{
+    if key.len() != 16 {
+        return Err(DecodeError::new(format!("Expected 16 byte key, got {} bytes", key.len())));
+    }
+    if mic.len() != MIC_LEN {
+        return Err(DecodeError::new(format!("Expected {} byte MIC, got {} bytes", MIC_LEN, mic.len())));
+    }
+    let mut msg = ciphertext.to_vec();
+    msg.extend_from_slice(mic);
+    let payload = Payload{msg: &msg, aad};
+    let result = match nonce.len() {
+        12 => Aes128Ccm12::new_from_slice(key)
+            .map_err(|_| DecodeError::new("Invalid key".to_string()))?
+            .decrypt(GenericArray::from_slice(nonce), payload),
+        13 => Aes128Ccm13::new_from_slice(key)
+            .map_err(|_| DecodeError::new("Invalid key".to_string()))?
+            .decrypt(GenericArray::from_slice(nonce), payload),
+        n => return Err(DecodeError::new(format!("Unsupported nonce length {}", n))),
+    };
+    result.map_err(|_| DecodeError::new("MIC check failed, the key is probably wrong".to_string()))
+}
+
+/// Converts an address written as "AA:BB:CC:DD:EE:FF" to bytes in the same order.
+pub fn mac_to_bytes(mac: &str) -> Option<Vec<u8>> {
+    let bytes: Vec<u8> = mac
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()?;
+    if bytes.len() != 6 {
+        return None;
+    }
+    Some(bytes)
+}
@@ -1,20 +1,22 @@
 use std::error;
-use std::{thread, time::{Duration, SystemTime}};
-use std::collections::{HashMap, hash_map::Entry};
-use std::rc::Rc;
-use std::cell::{RefCell, Ref};
+use std::{thread, time::{Duration, Instant, SystemTime}};
+use std::collections::HashMap;
 
 use dbus::{
-    Message, MessageItem, MessageItemArray,
+    Message, MessageItem, MessageItemArray, MessageType,
     Signature, Props, Connection, BusType,
 };
 
 use  error::BlueZError;
-use bt_sensor_factory::BTSensorFactory;
-use bt_sensor::{BTSensor};
 use config;
-use bt_device::BTDevice;
 use consumer::Consumer;
+use backend::Backend;
+use device_store::DeviceStore;
+use bt_device::LinkInfo;
+use recording::Recorder;
+use adapter_selector::{self, AdapterSelector};
+use adv_monitor::{self, APP_PATH, MONITOR_PATH, INTERFACE_MONITOR1, INTERFACE_MONITOR_MANAGER1};
+use timestamp::unix_timestamp;
 
 macro_rules! dbus_err {
     ($msg:expr) => {
@@ -24,49 +26,163 @@ macro_rules! dbus_err {
 
 type BoxErr = Box<dyn error::Error>;
 
-static BLUEZ_SERVICE: &'static str = "org.bluez";
-static BLUEZ_INTERFACE_ADAPTER1: &'static str = "org.bluez.Adapter1";
-static BLUEZ_START_DISCOVERY: &'static str = "StartDiscovery";
-static BLUEZ_SET_DISCOVERY_FILTER: &'static str = "SetDiscoveryFilter";
+static BLUEZ_SERVICE: &str = "org.bluez";
+static BLUEZ_INTERFACE_ADAPTER1: &str = "org.bluez.Adapter1";
+static BLUEZ_START_DISCOVERY: &str = "StartDiscovery";
+static BLUEZ_SET_DISCOVERY_FILTER: &str = "SetDiscoveryFilter";
+static BLUEZ_REMOVE_DEVICE: &str = "RemoveDevice";
+static BLUEZ_INTERFACE_DEVICE1: &str = "org.bluez.Device1";
+static DBUS_INTERFACE_PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+static DBUS_INTERFACE_OBJECT_MANAGER: &str = "org.freedesktop.DBus.ObjectManager";
+static DBUS_INTERFACE_DBUS: &str = "org.freedesktop.DBus";
+static DBUS_INTERFACE_LOCAL: &str = "org.freedesktop.DBus.Local";
+static DBUS_ERROR_UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+
+static SIGNAL_MATCH_RULES: [&str; 5] = [
+    "type='signal',sender='org.bluez',interface='org.freedesktop.DBus.Properties',\
+     member='PropertiesChanged',arg0='org.bluez.Device1'",
+    "type='signal',sender='org.bluez',interface='org.freedesktop.DBus.Properties',\
+     member='PropertiesChanged',arg0='org.bluez.Adapter1'",
+    "type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',\
+     member='NameOwnerChanged',arg0='org.bluez'",
+    "type='signal',sender='org.bluez',interface='org.freedesktop.DBus.ObjectManager',\
+     member='InterfacesAdded'",
+    "type='signal',sender='org.bluez',interface='org.freedesktop.DBus.ObjectManager',\
+     member='InterfacesRemoved'",
+];
+
+/// How the advertisements are read from BlueZ. Poll reads all devices with
+/// GetManagedObjects once per interval, Signal handles every advertisement as BlueZ reports
+/// the changed device properties. Monitor is like Signal, but instead of starting discovery
+/// the collector registers an advertisement monitor and BlueZ scans passively for it.
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq)]
+pub enum CaptureMode {
+    Poll,
+    Signal,
+    Monitor,
+}
+
+// The devices BlueZ has cached are pruned this many times per --forget-after.
+const PRUNES_PER_FORGET_AFTER: u32 = 4;
+
+// What BlueZ last reported of a device that is not a sensor. A device is seen again when
+// any of it changes.
+type DeviceSighting = (Option<i16>, Option<HashMap<u16, Vec<u8>>>, Option<HashMap<String, Vec<u8>>>);
+
+/// The optional parts of the discovery filter, see SetDiscoveryFilter in the BlueZ adapter
+/// API. BlueZ uses its own default for the ones that are not set.
+#[derive(Debug, Default, Clone)]
+pub struct DiscoveryFilter {
+    rssi: Option<i16>,
+    pathloss: Option<u16>,
+    duplicate_data: Option<bool>,
+    pattern: Option<String>,
+}
+
+impl DiscoveryFilter {
+
+    pub fn new(
+        rssi: Option<i16>,
+        pathloss: Option<u16>,
+        duplicate_data: Option<&str>,
+        pattern: Option<String>,
+        ) -> Result<DiscoveryFilter, BoxErr>
+    {
+        if rssi.is_some() && pathloss.is_some() {
+            return Err(dbus_err!("RSSI and pathloss cannot be used in the same discovery filter"));
+        }
+        let duplicate_data = match duplicate_data {
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            Some(d) => return Err(dbus_err!(format!("Invalid duplicate data {}, expected true or false", d))),
+            None => None,
+        };
+        Ok(DiscoveryFilter{rssi, pathloss, duplicate_data, pattern})
+    }
+
+    fn entries(&self) -> Vec<MessageItem> {
+        let entry = |key: &str, val: MessageItem| MessageItem::DictEntry(
+            Box::new(MessageItem::Str(key.to_string())),
+            Box::new(MessageItem::Variant(Box::new(val))),
+        );
+        let mut entries = Vec::new();
+        if let Some(rssi) = self.rssi {
+            entries.push(entry("RSSI", MessageItem::Int16(rssi)));
+        }
+        if let Some(pathloss) = self.pathloss {
+            entries.push(entry("Pathloss", MessageItem::UInt16(pathloss)));
+        }
+        if let Some(duplicate_data) = self.duplicate_data {
+            entries.push(entry("DuplicateData", MessageItem::Bool(duplicate_data)));
+        }
+        if let Some(ref pattern) = self.pattern {
+            entries.push(entry("Pattern", MessageItem::Str(pattern.to_string())));
+        }
+        entries
+    }
+
+}
+
+/// The Device1 properties the collector is interested in. A PropertiesChanged signal has
+/// only the changed properties, so any of them can be missing.
+#[derive(Default)]
+struct DeviceProperties {
+    address: Option<String>,
+    mfr_data: Option<HashMap<u16, Vec<u8>>>,
+    svc_data: Option<HashMap<String, Vec<u8>>>,
+    link_info: LinkInfo,
+    /// Paired, trusted or connected, e.g. a keyboard. These are never removed from BlueZ.
+    in_use: bool,
+}
 
 pub struct DbusBluez {
     conn: Connection,
-    sensor_factory: BTSensorFactory,
-    device_map: HashMap<String, Rc<RefCell<BTDevice>>>,
-    bluez_obj_path: String,
-    conf: config::SensorConf,
+    store: DeviceStore,
+    selectors: Vec<AdapterSelector>,
+    adapter_paths: Vec<String>,
+    capture_mode: CaptureMode,
+    discovery_filter: DiscoveryFilter,
+    forget_after: Duration,
+    other_devices: HashMap<String, (DeviceSighting, Instant)>,
+    last_prune: Instant,
 }
 
 impl DbusBluez {
 
-    pub fn new(conf: config::SensorConf, bt_devname: String) -> Result<DbusBluez, BoxErr> {
-        let bluez_obj_path = format!("/org/bluez/{}", bt_devname);
+    pub fn new(
+        conf: config::SensorConf,
+        bt_devnames: &[String],
+        capture_mode: CaptureMode,
+        discovery_filter: DiscoveryFilter,
+        ) -> Result<DbusBluez, BoxErr>
+    {
+        if capture_mode == CaptureMode::Monitor {
+            warn!("The advertisement monitor cannot match Inkbird or AltBeacon sensors, \
+                   use --capture=signal to read them");
+        }
         let bus = DbusBluez{
-            conn: Connection::get_private(BusType::System)?,
-            sensor_factory: BTSensorFactory::new(conf.clone()),
-            device_map: HashMap::new(),
-            bluez_obj_path: bluez_obj_path,
-            conf: conf,
+            conn: DbusBluez::connect(capture_mode)?,
+            forget_after: conf.get_forget_after(),
+            other_devices: HashMap::new(),
+            store: DeviceStore::new(conf),
+            selectors: adapter_selector::parse_selectors(bt_devnames),
+            adapter_paths: Vec::new(),
+            capture_mode,
+            discovery_filter,
+            last_prune: Instant::now(),
         };
         Ok(bus)
     }
 
-    pub fn initialize(&self) -> Result<(), BoxErr> {
-
-        let props = Props::new(
-            &self.conn,
-            BLUEZ_SERVICE,
-            &self.bluez_obj_path,
-            BLUEZ_INTERFACE_ADAPTER1,
-            500,
-        );
-        self.poweron_interface(&props)?;
-        self.set_discovery_filter()?;
-        self.start_discovering(&props)?;
-
-        info!("Bluetooth discovering!");
-        Ok(())
-
+    /// BlueZ calls the advertisement monitor through the connection that registered it, so
+    /// the objects are exported on every new connection.
+    fn connect(capture_mode: CaptureMode) -> Result<Connection, BoxErr> {
+        let conn = Connection::get_private(BusType::System)?;
+        if capture_mode == CaptureMode::Monitor {
+            conn.register_object_path(APP_PATH)?;
+            conn.register_object_path(MONITOR_PATH)?;
+        }
+        Ok(conn)
     }
 
     fn poweron_interface(&self, props: &Props) -> Result<(), BoxErr> {
@@ -93,7 +209,7 @@ impl DbusBluez {
 
     }
 
-    fn set_discovery_filter(&self) -> Result<(), BoxErr> {
+    fn set_discovery_filter(&self, adapter_path: &str) -> Result<(), BoxErr> {
 
         let empty: Vec<MessageItem> = Vec::new();
         let str_arr_sign = Signature::new("a(s)")?;
@@ -109,9 +225,12 @@ impl DbusBluez {
             Box::new(MessageItem::Variant(Box::new(MessageItem::Str(String::from("le"))))),
         );
 
+        let mut entries = vec!(uuid_entry, transport_entry);
+        entries.extend(self.discovery_filter.entries());
+
         let dict_sign = Signature::new("a{sv}")?;
         let dict_arr = MessageItemArray::new(
-            vec!(uuid_entry, transport_entry),
+            entries,
             dict_sign,
         ).map_err(|_| dbus_err!("ArrayError"))?;
 
@@ -119,22 +238,31 @@ impl DbusBluez {
 
         let msg1 = Message::new_method_call(
             BLUEZ_SERVICE,
-            &self.bluez_obj_path,
+            adapter_path,
             BLUEZ_INTERFACE_ADAPTER1,
             BLUEZ_SET_DISCOVERY_FILTER
         )?.append1(param);
         self.conn
             .send_with_reply_and_block(msg1, 1000)
-            .map_err(|_| dbus_err!("DBus Error while setting discovery filter"))?;
+            .map_err(|e| dbus_err!(format!("DBus Error while setting discovery filter: {}", e)))?;
+
+        Ok(())
 
+    }
+
+    fn subscribe_signals(&self) -> Result<(), BoxErr> {
+
+        for rule in SIGNAL_MATCH_RULES.iter() {
+            self.conn.add_match(rule)?;
+        }
         Ok(())
 
     }
 
-    fn start_discovering(&self, props: &Props) -> Result<(), BoxErr> {
+    fn start_discovering(&self, props: &Props, adapter_path: &str) -> Result<(), BoxErr> {
 
         let msg = Message::new_method_call(
-            BLUEZ_SERVICE, &self.bluez_obj_path, BLUEZ_INTERFACE_ADAPTER1, BLUEZ_START_DISCOVERY)?;
+            BLUEZ_SERVICE, adapter_path, BLUEZ_INTERFACE_ADAPTER1, BLUEZ_START_DISCOVERY)?;
         self.conn.send_with_reply_and_block(msg, 1000)?;
         let sleep_time = Duration::from_millis(500);
         thread::sleep(sleep_time);
@@ -143,13 +271,87 @@ impl DbusBluez {
             _ => panic!("Not the type that was expected!"),
         };
         if !is_discovering {
-            return Err(dbus_err!("Can't set bluetooth to discover mode"))
+            return Err(dbus_err!(format!("Can't set bluetooth {} to discover mode", adapter_path)))
         }
 
         Ok(())
 
     }
 
+    /// BlueZ reads the monitor from the application root with GetManagedObjects after it
+    /// has replied to the registration.
+    fn register_monitor(&self, adapter_path: &str) -> Result<(), BoxErr> {
+
+        let msg = Message::new_method_call(
+            BLUEZ_SERVICE,
+            adapter_path,
+            INTERFACE_MONITOR_MANAGER1,
+            "RegisterMonitor",
+        )?.append1(MessageItem::ObjectPath(APP_PATH.to_string().into()));
+        self.conn
+            .send_with_reply_and_block(msg, 1000)
+            .map_err(|e| dbus_err!(format!("DBus Error while registering the advertisement monitor: {}", e)))?;
+
+        Ok(())
+
+    }
+
+    fn start_adapter(&self, adapter_path: &str) -> Result<(), BoxErr> {
+
+        let props = Props::new(
+            &self.conn,
+            BLUEZ_SERVICE,
+            adapter_path,
+            BLUEZ_INTERFACE_ADAPTER1,
+            500,
+        );
+        self.poweron_interface(&props)?;
+        if self.capture_mode == CaptureMode::Monitor {
+            self.register_monitor(adapter_path)?;
+            info!("{} monitoring advertisements", adapter_path);
+            return Ok(());
+        }
+        self.set_discovery_filter(adapter_path)?;
+        self.start_discovering(&props, adapter_path)?;
+        info!("{} discovering", adapter_path);
+        Ok(())
+
+    }
+
+    fn is_selected(&self, adapter_path: &str, address: Option<&str>) -> bool {
+        let name = adapter_path.rsplit('/').next().unwrap_or_default();
+        adapter_selector::matches_any(&self.selectors, name, address)
+    }
+
+    /// Starts the selected adapters that BlueZ has but that are not used yet, and stops
+    /// using the ones BlueZ does not have anymore.
+    fn update_adapters(&mut self, adapter_paths: Vec<String>) -> Result<(), BoxErr> {
+
+        for adapter_path in &adapter_paths {
+            if self.adapter_paths.contains(adapter_path) {
+                continue;
+            }
+            match self.start_adapter(adapter_path) {
+                Ok(()) => self.adapter_paths.push(adapter_path.to_string()),
+                Err(e) => warn!("Cannot start {}: {}", adapter_path, e),
+            }
+        }
+        self.adapter_paths.retain(|p| {
+            let present = adapter_paths.contains(p);
+            if !present {
+                warn!("{} removed", p);
+            }
+            present
+        });
+        if self.adapter_paths.is_empty() {
+            let selectors: Vec<String> = self.selectors.iter().map(|s| s.to_string()).collect();
+            return Err(dbus_err!(
+                format!("No bluetooth adapter {} is discovering", selectors.join(","))));
+        }
+        Ok(())
+
+    }
+
     fn read_manufacturer_data(&self, dbusmap: &MessageItem) -> Result<HashMap<u16, Vec<u8>>, BoxErr> {
 
         let mut map = HashMap::new();
@@ -222,6 +424,92 @@ impl DbusBluez {
 
     }
 
+    /// BlueZ names the device objects after the address, e.g.
+    /// /org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF.
+    fn address_from_path(object_path: &str) -> String {
+        object_path
+            .rsplit('/')
+            .next()
+            .map(|name| name.trim_start_matches("dev_").replace('_', ":"))
+            .unwrap_or_default()
+    }
+
+    /// The name of the adapter, e.g. hci0, if the device object belongs to one of the
+    /// adapters the collector uses.
+    fn adapter_of(&self, object_path: &str) -> Option<String> {
+        self.adapter_paths
+            .iter()
+            .find(|p| object_path.starts_with(p.as_str())
+                  && object_path[p.len()..].starts_with('/'))
+            .and_then(|p| p.rsplit('/').next())
+            .map(|name| name.to_string())
+    }
+
+    fn read_device_properties(&self, prop_arr: &[MessageItem]) -> Result<DeviceProperties, BoxErr> {
+
+        let mut props = DeviceProperties::default();
+        for prop in prop_arr {
+            let (key, val) = match *prop {
+                MessageItem::DictEntry(ref k, ref v) => (k, v),
+                _ => return Err(dbus_err!("Unexpected type!")),
+            };
+            let key_str: &str = key
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &str"))?;
+            let variant = match **val {
+                MessageItem::Variant(ref v) => v,
+                _ => return Err(dbus_err!("Expected Variant")),
+            };
+            match key_str {
+                "Address" => {
+                    let address: &str = variant
+                        .inner()
+                        .map_err(|_| dbus_err!("inner() is not &str"))?;
+                    props.address = Some(address.to_string());
+                },
+                "ManufacturerData" => {
+                    props.mfr_data = Some(self.read_manufacturer_data(variant)?);
+                },
+                "ServiceData" => {
+                    props.svc_data = Some(self.read_service_data(variant)?);
+                },
+                "RSSI" => {
+                    let rssi: i16 = variant
+                        .inner()
+                        .map_err(|_| dbus_err!("inner() is not i16"))?;
+                    props.link_info.rssi = Some(rssi);
+                },
+                "TxPower" => {
+                    let tx_power: i16 = variant
+                        .inner()
+                        .map_err(|_| dbus_err!("inner() is not i16"))?;
+                    props.link_info.tx_power = Some(tx_power);
+                },
+                "AddressType" => {
+                    let address_type: &str = variant
+                        .inner()
+                        .map_err(|_| dbus_err!("inner() is not &str"))?;
+                    props.link_info.address_type = Some(address_type.to_string());
+                },
+                "Name" => {
+                    let name: &str = variant
+                        .inner()
+                        .map_err(|_| dbus_err!("inner() is not &str"))?;
+                    props.link_info.name = Some(name.to_string());
+                },
+                "Paired" | "Trusted" | "Connected" => {
+                    let b: bool = variant
+                        .inner()
+                        .map_err(|_| dbus_err!("inner() is not bool"))?;
+                    props.in_use |= b;
+                },
+                _ => continue,
+            }
+        }
+        Ok(props)
+
+    }
+
     pub fn update_sensors(&mut self) -> Result<(), BoxErr> {
 
         let msg = Message::new_method_call(
@@ -236,114 +524,598 @@ impl DbusBluez {
         let result = self.conn
             .send_with_reply_and_block(msg, 3000)
             .map_err(|_| dbus_err!("Failed to make dbus query".to_string()))?;
-        let timestamp = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(t) => t,
-            Err(_) => panic!("System clock before unix epoch!"),
-        };
+        let unix_ts = unix_timestamp();
         let result_vec = result.get_items();
-        let items: &[MessageItem] = result_vec.get(0).unwrap().inner().unwrap();
+        let items: &[MessageItem] = result_vec.first().unwrap().inner().unwrap();
+        let mut adapter_paths = Vec::new();
         for i in items {
             let (path, ifs) = i.inner().unwrap();
             let interfaces: &[MessageItem] = ifs.inner().unwrap();
             for intf in interfaces {
                 let (intf_tmp, prop_map) = intf.inner().unwrap();
                 let intf_str: &str = intf_tmp.inner().unwrap();
-                if intf_str == "org.bluez.Device1" {
+                if intf_str == BLUEZ_INTERFACE_ADAPTER1 {
                     let path_str: &str = path.inner().unwrap();
-
-                    let mut address = "";
-                    let mut mfr_data = None;
-                    let mut svc_data = None;
                     let prop_arr: &[MessageItem] = prop_map.inner().unwrap();
-                    for prop in prop_arr {
-                        let (key, val) = match *prop {
-                            MessageItem::DictEntry(ref k, ref v) => (k, v),
-                            _ => panic!("Unexpected type!"),
-                        };
-                        let key_str: &str = key.inner().unwrap();
-                        match key_str {
-                            "Address" => {
-                                let key_val: &str = match **val {
-                                    MessageItem::Variant(ref v) => v.inner().unwrap(),
-                                    _ => panic!("Expected Variant"),
-                                };
-                                debug!("{:?}", key_val);
-                                address = key_val;
-                            },
-                            "ManufacturerData" => {
-                                mfr_data = match **val {
-                                    MessageItem::Variant(ref v) => Some(self.read_manufacturer_data(v)?),
-                                    _ => panic!("Expected Variant"),
-                                };
-                            },
-                            "ServiceData" => {
-                                svc_data = match **val {
-                                    MessageItem::Variant(ref v) => Some(self.read_service_data(v)?),
-                                    _ => panic!("Expected Variant"),
-                                };
-                            },
-                            _ => continue,
-                        }
+                    let props = self.read_device_properties(prop_arr)?;
+                    if self.is_selected(path_str, props.address.as_deref()) {
+                        adapter_paths.push(path_str.to_string());
                     }
-                    let millis = timestamp.subsec_millis() as u64;
-                    let unix_ts = timestamp.as_secs() * 1000 + millis;
-                    self._update_device(path_str, address, mfr_data, svc_data, unix_ts)?;
+                } else if intf_str == BLUEZ_INTERFACE_DEVICE1 {
+                    let path_str: &str = path.inner().unwrap();
+                    let adapter = match self.adapter_of(path_str) {
+                        Some(a) => a,
+                        None => continue,
+                    };
+                    let prop_arr: &[MessageItem] = prop_map.inner().unwrap();
+                    let mut props = self.read_device_properties(prop_arr)?;
+                    let address = props.address
+                        .unwrap_or_else(|| DbusBluez::address_from_path(path_str));
+                    debug!("{:?}", address);
+                    props.link_info.adapter = Some(adapter);
+                    self.store.poll_device(
+                        &address, props.mfr_data, props.svc_data, props.link_info, unix_ts);
                 }
             }
         }
-        Ok(())
+        adapter_paths.sort();
+        self.update_adapters(adapter_paths)
 
     }
 
-    fn _update_device(
+    fn properties_changed(
         &mut self,
         object_path: &str,
-        address: &str,
-        mfr_data: Option<HashMap<u16, Vec<u8>>>,
-        svc_data: Option<HashMap<String, Vec<u8>>>,
+        items: &[MessageItem],
         meas_timestamp: u64,
-        ) -> Result<(), BoxErr>
+        ) -> Result<Option<String>, BoxErr>
     {
 
-        let tag = self.conf.get_sensor_tag(address).unwrap_or(address);
-        match self.device_map.entry(object_path.to_string()) {
-            Entry::Occupied(mut e) => {
-                let mut device = e.get_mut();
-                device.borrow_mut().update_data(mfr_data, svc_data, meas_timestamp);
-                self.sensor_factory.set_sensor(device.clone());
-            },
-            Entry::Vacant(e) => {
-                let device = Rc::new(RefCell::new(BTDevice::new(
-                    object_path.to_string(),
-                    address.to_string(),
-                    tag.to_string(),
-                    mfr_data,
-                    svc_data,
-                    meas_timestamp,
-                    self.conf.get_last_seen_forget(),
-                    self.sensor_factory.get_sensor_discovery_mode(address),
-                )));
-                self.sensor_factory.set_sensor(device.clone());
-                if device.borrow().get_sensor().is_some() {
-                    e.insert(device);
+        let interface: &str = items.first()
+            .ok_or_else(|| dbus_err!("PropertiesChanged without arguments"))?
+            .inner()
+            .map_err(|_| dbus_err!("inner() is not &str"))?;
+        if interface != BLUEZ_INTERFACE_DEVICE1 {
+            return Ok(None);
+        }
+        let prop_arr: &[MessageItem] = items.get(1)
+            .ok_or_else(|| dbus_err!("PropertiesChanged without properties"))?
+            .inner()
+            .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+        let adapter = match self.adapter_of(object_path) {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+        let mut props = self.read_device_properties(prop_arr)?;
+        let address = props.address
+            .take()
+            .unwrap_or_else(|| DbusBluez::address_from_path(object_path));
+        props.link_info.adapter = Some(adapter);
+        if props.mfr_data.is_none() && props.svc_data.is_none() {
+            // Only the RSSI is signaled when BlueZ hears the device again with the same data.
+            return match self.store.update_link_info(&address, props.link_info, meas_timestamp) {
+                true => Ok(Some(address)),
+                false => Ok(None),
+            };
+        }
+
+        // The properties that did not change are taken from the known device.
+        let (mfr_data, svc_data) = self.store.merge_data(&address, props.mfr_data, props.svc_data);
+        match self.store.update_device(&address, mfr_data, svc_data, props.link_info, meas_timestamp) {
+            true => Ok(Some(address)),
+            false => Ok(None),
+        }
+
+    }
+
+    fn interfaces_added(
+        &mut self,
+        items: &[MessageItem],
+        meas_timestamp: u64,
+        ) -> Result<Option<String>, BoxErr>
+    {
+
+        let object_path: &str = items.first()
+            .ok_or_else(|| dbus_err!("InterfacesAdded without arguments"))?
+            .inner()
+            .map_err(|_| dbus_err!("inner() is not &str"))?;
+        let interfaces: &[MessageItem] = items.get(1)
+            .ok_or_else(|| dbus_err!("InterfacesAdded without interfaces"))?
+            .inner()
+            .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+        for intf in interfaces {
+            let (intf_name, prop_map) = intf
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not tuple"))?;
+            let intf_str: &str = intf_name
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &str"))?;
+            if intf_str != BLUEZ_INTERFACE_DEVICE1 {
+                continue;
+            }
+            let prop_arr: &[MessageItem] = prop_map
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+            let adapter = match self.adapter_of(object_path) {
+                Some(a) => a,
+                None => continue,
+            };
+            let mut props = self.read_device_properties(prop_arr)?;
+            let address = props.address
+                .unwrap_or_else(|| DbusBluez::address_from_path(object_path));
+            props.link_info.adapter = Some(adapter);
+            let updated = self.store.update_device(
+                &address, props.mfr_data, props.svc_data, props.link_info, meas_timestamp);
+            if updated {
+                return Ok(Some(address));
+            }
+        }
+        Ok(None)
+
+    }
+
+    fn interfaces_removed(&mut self, items: &[MessageItem]) -> Result<(), BoxErr> {
+
+        let object_path: &str = items.first()
+            .ok_or_else(|| dbus_err!("InterfacesRemoved without arguments"))?
+            .inner()
+            .map_err(|_| dbus_err!("inner() is not &str"))?;
+        let interfaces: &[MessageItem] = items.get(1)
+            .ok_or_else(|| dbus_err!("InterfacesRemoved without interfaces"))?
+            .inner()
+            .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+        let device_removed = interfaces
+            .iter()
+            .any(|i| i.inner::<&str>().ok() == Some(BLUEZ_INTERFACE_DEVICE1));
+        if !device_removed {
+            return Ok(());
+        }
+        // The device is forgotten only if the adapter the readings are taken from removed it,
+        // the other adapters may still hear it.
+        let address = DbusBluez::address_from_path(object_path);
+        let from_adapter = match self.store.get_device(&address) {
+            Some(device) => device.borrow().get_link_info().adapter == self.adapter_of(object_path),
+            None => false,
+        };
+        if from_adapter && self.store.remove_device(&address) {
+            debug!("{} removed by BlueZ", object_path);
+        }
+        Ok(())
+
+    }
+
+    /// Follows the selected adapters appearing and disappearing. Returns an error if the
+    /// signal tells that the advertisements stopped coming: the connection was lost,
+    /// bluetoothd stopped or restarted, the last adapter was removed or an adapter stopped
+    /// discovering.
+    fn check_signal(&mut self, msg: &Message) -> Result<(), BoxErr> {
+
+        let (msg_type, path, interface, member) = msg.headers();
+        if msg_type != MessageType::Signal {
+            return Ok(());
+        }
+        let (path, interface, member) = match (path, interface, member) {
+            (Some(p), Some(i), Some(m)) => (p, i, m),
+            _ => return Ok(()),
+        };
+        let items = msg.get_items();
+        if interface == DBUS_INTERFACE_LOCAL && member == "Disconnected" {
+            return Err(dbus_err!("D-Bus connection lost"));
+        }
+        if interface == DBUS_INTERFACE_DBUS && member == "NameOwnerChanged" {
+            return Err(dbus_err!(format!("{} owner changed", BLUEZ_SERVICE)));
+        }
+        if interface == DBUS_INTERFACE_OBJECT_MANAGER && member == "InterfacesRemoved" {
+            let object_path: &str = items.first()
+                .ok_or_else(|| dbus_err!("InterfacesRemoved without arguments"))?
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &str"))?;
+            if !self.adapter_paths.iter().any(|p| p == object_path) {
+                return Ok(());
+            }
+            let interfaces: &[MessageItem] = items.get(1)
+                .ok_or_else(|| dbus_err!("InterfacesRemoved without interfaces"))?
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+            let adapter_removed = interfaces
+                .iter()
+                .any(|i| i.inner::<&str>().ok() == Some(BLUEZ_INTERFACE_ADAPTER1));
+            if adapter_removed {
+                let adapter_paths = self.adapter_paths
+                    .iter()
+                    .filter(|p| *p != object_path)
+                    .cloned()
+                    .collect();
+                self.update_adapters(adapter_paths)?;
+            }
+        } else if interface == DBUS_INTERFACE_OBJECT_MANAGER && member == "InterfacesAdded" {
+            let object_path: &str = items.first()
+                .ok_or_else(|| dbus_err!("InterfacesAdded without arguments"))?
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &str"))?;
+            let interfaces: &[MessageItem] = items.get(1)
+                .ok_or_else(|| dbus_err!("InterfacesAdded without interfaces"))?
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+            for intf in interfaces {
+                let (intf_name, prop_map) = intf
+                    .inner()
+                    .map_err(|_| dbus_err!("inner() is not tuple"))?;
+                if intf_name.inner::<&str>().ok() != Some(BLUEZ_INTERFACE_ADAPTER1) {
+                    continue;
+                }
+                let prop_arr: &[MessageItem] = prop_map
+                    .inner()
+                    .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+                let props = self.read_device_properties(prop_arr)?;
+                if self.is_selected(object_path, props.address.as_deref()) {
+                    info!("{} added", object_path);
+                    let mut adapter_paths = self.adapter_paths.clone();
+                    adapter_paths.push(object_path.to_string());
+                    self.update_adapters(adapter_paths)?;
                 }
             }
+        } else if interface == DBUS_INTERFACE_PROPERTIES && member == "PropertiesChanged"
+            && self.adapter_paths.contains(&path) && self.capture_mode != CaptureMode::Monitor {
+            if items.first().and_then(|i| i.inner::<&str>().ok()) != Some(BLUEZ_INTERFACE_ADAPTER1) {
+                return Ok(());
+            }
+            let prop_arr: &[MessageItem] = items.get(1)
+                .ok_or_else(|| dbus_err!("PropertiesChanged without properties"))?
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+            for prop in prop_arr {
+                if let MessageItem::DictEntry(ref k, ref v) = *prop {
+                    if k.inner::<&str>().ok() != Some("Discovering") {
+                        continue;
+                    }
+                    if let MessageItem::Variant(ref v) = **v {
+                        if v.inner::<bool>().ok() == Some(false) {
+                            return Err(dbus_err!(format!("{} stopped discovering", path)));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+
+    }
+
+    /// Polling does not see the signals, instead the adapters are checked after every poll.
+    fn check_discovering(&self) -> Result<(), BoxErr> {
+        for adapter_path in &self.adapter_paths {
+            let props = Props::new(
+                &self.conn,
+                BLUEZ_SERVICE,
+                adapter_path,
+                BLUEZ_INTERFACE_ADAPTER1,
+                500,
+            );
+            let is_discovering = match props.get("Discovering")? {
+                MessageItem::Bool(b) => b,
+                _ => return Err(dbus_err!("Discovering is not a bool")),
+            };
+            if !is_discovering {
+                return Err(dbus_err!(format!("{} stopped discovering", adapter_path)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the device from a BlueZ signal and returns the address of the device if it
+    /// got a new advertisement.
+    fn handle_signal(&mut self, msg: &Message) -> Result<Option<String>, BoxErr> {
+
+        let meas_timestamp = unix_timestamp();
+        let (msg_type, path, interface, member) = msg.headers();
+        if msg_type != MessageType::Signal {
+            return Ok(None);
+        }
+        let (path, interface, member) = match (path, interface, member) {
+            (Some(p), Some(i), Some(m)) => (p, i, m),
+            _ => return Ok(None),
+        };
+        let items = msg.get_items();
+        if interface == DBUS_INTERFACE_PROPERTIES && member == "PropertiesChanged" {
+            return self.properties_changed(&path, &items, meas_timestamp);
+        } else if interface == DBUS_INTERFACE_OBJECT_MANAGER && member == "InterfacesAdded" {
+            return self.interfaces_added(&items, meas_timestamp);
+        } else if interface == DBUS_INTERFACE_OBJECT_MANAGER && member == "InterfacesRemoved" {
+            self.interfaces_removed(&items)?;
+        }
+        Ok(None)
+
+    }
+
+    fn reply(&self, msg: Message) -> Result<(), BoxErr> {
+        self.conn.send(msg).map_err(|_| dbus_err!("Cannot send the D-Bus reply"))?;
+        Ok(())
+    }
+
+    /// Reads the device BlueZ found for the advertisement monitor, the following
+    /// advertisements of the device are signaled as property changes.
+    fn device_found(&mut self, object_path: &str) -> Result<Option<String>, BoxErr> {
+
+        let adapter = match self.adapter_of(object_path) {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+        let msg = Message::new_method_call(
+            BLUEZ_SERVICE,
+            object_path,
+            DBUS_INTERFACE_PROPERTIES,
+            "GetAll",
+        )?.append1(BLUEZ_INTERFACE_DEVICE1);
+        let result = self.conn
+            .send_with_reply_and_block(msg, 1000)
+            .map_err(|e| dbus_err!(format!("Cannot read {}: {}", object_path, e)))?;
+        let result_vec = result.get_items();
+        let prop_arr: &[MessageItem] = result_vec.first()
+            .ok_or_else(|| dbus_err!("GetAll without result"))?
+            .inner()
+            .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+        let mut props = self.read_device_properties(prop_arr)?;
+        let address = props.address
+            .take()
+            .unwrap_or_else(|| DbusBluez::address_from_path(object_path));
+        props.link_info.adapter = Some(adapter);
+        let meas_timestamp = unix_timestamp();
+        match self.store.update_device(
+            &address, props.mfr_data, props.svc_data, props.link_info, meas_timestamp) {
+            true => Ok(Some(address)),
+            false => Ok(None),
+        }
+
+    }
+
+    /// Answers the calls BlueZ makes to the advertisement monitor and returns the address of
+    /// the device if BlueZ found a new one.
+    fn handle_monitor_call(&mut self, msg: &Message) -> Result<Option<String>, BoxErr> {
+
+        let (_, path, interface, member) = msg.headers();
+        let path = path.unwrap_or_default();
+        let interface = interface.unwrap_or_default();
+        let member = member.unwrap_or_default();
+        if path == APP_PATH && interface == DBUS_INTERFACE_OBJECT_MANAGER
+            && member == "GetManagedObjects" {
+            let reply = adv_monitor::managed_objects_reply(msg).map_err(|e| dbus_err!(e))?;
+            self.reply(reply)?;
+            return Ok(None);
+        }
+        if path != MONITOR_PATH || interface != INTERFACE_MONITOR1 {
+            let error = Message::new_error(msg, DBUS_ERROR_UNKNOWN_METHOD, "Unknown method")
+                .ok_or_else(|| dbus_err!("Cannot create the D-Bus error"))?;
+            self.reply(error)?;
+            return Ok(None);
+        }
+        self.reply(msg.method_return())?;
+        let items = msg.get_items();
+        let object_path = items.first().and_then(|i| i.inner::<&str>().ok());
+        match (member.as_str(), object_path) {
+            ("Activate", _) => info!("Advertisement monitor active"),
+            ("Release", _) => warn!("BlueZ released the advertisement monitor"),
+            ("DeviceFound", Some(object_path)) => return self.device_found(object_path),
+            ("DeviceLost", Some(object_path)) => debug!("{} lost", object_path),
+            _ => (),
+        }
+        Ok(None)
+
+    }
+
+    fn remove_bluez_device(&self, adapter_path: &str, object_path: &str) {
+
+        let msg = match Message::new_method_call(
+            BLUEZ_SERVICE, adapter_path, BLUEZ_INTERFACE_ADAPTER1, BLUEZ_REMOVE_DEVICE) {
+            Ok(m) => m.append1(MessageItem::ObjectPath(object_path.to_string().into())),
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            },
         };
+        // The device may already be gone, so the error is not a problem.
+        match self.conn.send_with_reply_and_block(msg, 1000) {
+            Ok(_) => debug!("Removed {} from BlueZ", object_path),
+            Err(e) => debug!("Cannot remove {} from BlueZ: {}", object_path, e),
+        }
+
+    }
+
+    /// The object paths and the properties of the Device1 objects of the adapters in use.
+    fn get_device_objects(&self) -> Result<Vec<(String, DeviceProperties)>, BoxErr> {
+
+        let msg = Message::new_method_call(
+            BLUEZ_SERVICE,
+            "/",
+            DBUS_INTERFACE_OBJECT_MANAGER,
+            "GetManagedObjects",
+        )?;
+        let result = self.conn
+            .send_with_reply_and_block(msg, 3000)
+            .map_err(|_| dbus_err!("Failed to make dbus query".to_string()))?;
+        let result_vec = result.get_items();
+        let items: &[MessageItem] = result_vec.first()
+            .ok_or_else(|| dbus_err!("GetManagedObjects without result"))?
+            .inner()
+            .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+        let mut devices = Vec::new();
+        for i in items {
+            let (path, ifs) = i
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not tuple"))?;
+            let path_str: &str = path
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &str"))?;
+            if self.adapter_of(path_str).is_none() {
+                continue;
+            }
+            let interfaces: &[MessageItem] = ifs
+                .inner()
+                .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+            for intf in interfaces {
+                let (intf_name, prop_map) = intf
+                    .inner()
+                    .map_err(|_| dbus_err!("inner() is not tuple"))?;
+                if intf_name.inner::<&str>().ok() != Some(BLUEZ_INTERFACE_DEVICE1) {
+                    continue;
+                }
+                let prop_arr: &[MessageItem] = prop_map
+                    .inner()
+                    .map_err(|_| dbus_err!("inner() is not &[MessageItem]"))?;
+                devices.push((path_str.to_string(), self.read_device_properties(prop_arr)?));
+            }
+        }
+        Ok(devices)
+
+    }
+
+    /// Forgets the sensors that have not been seen for a while and removes them from BlueZ
+    /// too, otherwise a poll would read the old data again. The other devices, e.g. the
+    /// phones that walk by, are removed from BlueZ as well when they have not been seen for
+    /// the same time, because BlueZ would keep every one of them. The devices in the
+    /// devicemap are kept, they may not have sent valid data yet.
+    fn prune(&mut self) -> Result<(), BoxErr> {
 
+        for address in self.store.evict_unseen() {
+            let dev_name = format!("dev_{}", address.replace(':', "_"));
+            for adapter_path in &self.adapter_paths {
+                self.remove_bluez_device(adapter_path, &format!("{}/{}", adapter_path, dev_name));
+            }
+        }
+        let mut other_devices = HashMap::new();
+        for (object_path, props) in self.get_device_objects()? {
+            let address = props.address
+                .unwrap_or_else(|| DbusBluez::address_from_path(&object_path));
+            if props.in_use || self.store.get_device(&address).is_some()
+                || self.store.is_configured(&address) {
+                continue;
+            }
+            let sighting = (props.link_info.rssi, props.mfr_data, props.svc_data);
+            let seen_at = match self.other_devices.remove(&object_path) {
+                Some((previous, seen_at)) if previous == sighting => seen_at,
+                _ => Instant::now(),
+            };
+            if seen_at.elapsed() < self.forget_after {
+                other_devices.insert(object_path, (sighting, seen_at));
+                continue;
+            }
+            if let Some(adapter) = self.adapter_of(&object_path) {
+                self.remove_bluez_device(&format!("/org/bluez/{}", adapter), &object_path);
+            }
+        }
+        self.other_devices = other_devices;
+        self.last_prune = Instant::now();
         Ok(())
 
     }
 
-    pub fn consume(&mut self, consumer: &mut dyn Consumer) -> Result<(), BoxErr> {
+    /// Reads all devices once and gives the sensors to the consumer.
+    pub fn poll(&mut self, consumer: &mut dyn Consumer) -> Result<(), BoxErr> {
         self.update_sensors()?;
-        let devices: Vec<Ref<BTDevice>> = self.device_map.iter()
-            .map(|(_, d)| d.borrow())
-            .collect();
-        let sensors: Vec<&dyn BTSensor> = devices.iter()
-            .filter_map(|d| d.get_sensor())
-            .collect();
-        consumer.consume(&sensors);
+        self.store.consume_all(consumer);
+        consumer.flush();
         Ok(())
     }
 
+    /// Gives every advertisement to the consumer as it arrives until the wait time is over.
+    fn listen(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        let start = SystemTime::now();
+        loop {
+            let elapsed = start.elapsed().unwrap_or(wait);
+            if elapsed >= wait {
+                break;
+            }
+            let timeout = (wait - elapsed).as_millis() as u32;
+            let msg = match self.conn.incoming(timeout).next() {
+                Some(m) => m,
+                None if !self.conn.is_connected() => return Err(dbus_err!("D-Bus connection lost")),
+                None => continue,
+            };
+            if msg.msg_type() == MessageType::MethodCall {
+                match self.handle_monitor_call(&msg) {
+                    Ok(Some(address)) => self.store.consume_device(&address, consumer),
+                    Ok(None) => (),
+                    Err(e) => warn!("Cannot handle the advertisement monitor call: {}", e),
+                }
+                continue;
+            }
+            self.check_signal(&msg)?;
+            match self.handle_signal(&msg) {
+                Ok(Some(address)) => self.store.consume_device(&address, consumer),
+                Ok(None) => (),
+                Err(e) => warn!("Cannot handle BlueZ signal: {}", e),
+            }
+        }
+        consumer.flush();
+        Ok(())
+    }
+
+}
+
+impl Backend for DbusBluez {
+
+    fn initialize(&mut self) -> Result<(), BoxErr> {
+
+        if self.capture_mode != CaptureMode::Poll {
+            self.subscribe_signals()?;
+        }
+        // Finds and starts the adapters. The signals only tell about the changes, so this
+        // also reads the devices BlueZ already knows.
+        self.adapter_paths.clear();
+        self.update_sensors()?;
+
+        info!("Bluetooth discovering!");
+        Ok(())
+
+    }
+
+    /// The connection is opened again, because a lost connection cannot be used anymore and
+    /// the match rules of the old one may be gone if bluetoothd restarted.
+    fn reconnect(&mut self) -> Result<(), BoxErr> {
+        self.conn = DbusBluez::connect(self.capture_mode)?;
+        self.initialize()
+    }
+
+    fn consume(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        match self.capture_mode {
+            CaptureMode::Poll => {
+                self.poll(consumer)?;
+                self.check_discovering()?;
+                thread::sleep(wait);
+            },
+            CaptureMode::Signal | CaptureMode::Monitor => self.listen(consumer, wait)?,
+        }
+        if self.last_prune.elapsed() >= self.forget_after / PRUNES_PER_FORGET_AFTER {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// BlueZ already knows the devices it has seen, so there is no need to wait.
+    fn list(&mut self, consumer: &mut dyn Consumer, _wait: Duration) -> Result<(), BoxErr> {
+        self.poll(consumer)
+    }
+
+    fn set_recorder(&mut self, recorder: Recorder) {
+        self.store.set_recorder(recorder);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_given_duplicate_data() {
+        let entry = |val: bool| MessageItem::DictEntry(
+            Box::new(MessageItem::Str("DuplicateData".to_string())),
+            Box::new(MessageItem::Variant(Box::new(MessageItem::Bool(val)))),
+        );
+        let filter = DiscoveryFilter::new(None, None, Some("false"), None).unwrap();
+        assert_eq!(filter.entries(), vec!(entry(false)));
+        let filter = DiscoveryFilter::new(None, None, Some("true"), None).unwrap();
+        assert_eq!(filter.entries(), vec!(entry(true)));
+        assert!(DiscoveryFilter::new(None, None, None, None).unwrap().entries().is_empty());
+        assert!(DiscoveryFilter::new(None, None, Some("yes"), None).is_err());
+    }
 }
@@ -0,0 +1,158 @@
+use std::error;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::rc::Rc;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+use hex;
+
+use bt_device::BTDevice;
+use bt_sensor_factory::BTSensorFactory;
+use config;
+use error::DecodeError;
+use hci::{self, Advertisement};
+
+type BoxErr = Box<dyn error::Error>;
+
+/// Where the payload of the decode command goes. A raw payload is the whole advertising data
+/// as the Ruuvi app and the gateways show it, e.g. 0201061BFF9904...
+pub enum PayloadSource {
+    Manufacturer(u16),
+    Service(String),
+    Raw,
+}
+
+impl PayloadSource {
+
+    /// The manufacturer id is hex, e.g. 0499 or 0x0499. The service UUID is a 16 or 32 bit
+    /// hex UUID or a full UUID.
+    pub fn new(mfr_id: Option<&str>, svc_uuid: Option<&str>) -> Result<PayloadSource, BoxErr> {
+        match (mfr_id, svc_uuid) {
+            (Some(id), None) => {
+                let id = u16::from_str_radix(id.trim_start_matches("0x"), 16)
+                    .map_err(|e| DecodeError::new(format!("Invalid manufacturer id {}: {}", id, e)))?;
+                Ok(PayloadSource::Manufacturer(id))
+            },
+            (None, Some(uuid)) => {
+                let uuid = uuid.trim_start_matches("0x").to_lowercase();
+                let mut bytes = match uuid.len() {
+                    4 | 8 => hex::decode(&uuid)
+                        .map_err(|e| DecodeError::new(format!("Invalid service UUID {}: {}", uuid, e)))?,
+                    36 => return Ok(PayloadSource::Service(uuid)),
+                    _ => return Err(Box::new(DecodeError::new(format!("Invalid service UUID {}", uuid)))),
+                };
+                bytes.reverse();
+                Ok(PayloadSource::Service(hci::uuid_string(&bytes)))
+            },
+            (None, None) => Ok(PayloadSource::Raw),
+            (Some(_), Some(_)) => Err(Box::new(DecodeError::new(
+                "Give either a manufacturer id or a service UUID".to_string()))),
+        }
+    }
+
+    fn advertisement(&self, payload: &[u8]) -> Result<Advertisement, DecodeError> {
+        let mut adv = Advertisement::default();
+        match *self {
+            PayloadSource::Manufacturer(id) => {
+                adv.mfr_data.insert(id, payload.to_vec());
+            },
+            PayloadSource::Service(ref uuid) => {
+                adv.svc_data.insert(uuid.to_string(), payload.to_vec());
+            },
+            PayloadSource::Raw => hci::parse_advertising_data(payload, &mut adv)?,
+        }
+        Ok(adv)
+    }
+
+}
+
+/// Decodes a hex payload with every sensor type that accepts it and prints the values.
+fn decode_payload(
+    conf: &config::SensorConf,
+    factory: &BTSensorFactory,
+    source: &PayloadSource,
+    address: &str,
+    payload: &str,
+    json: bool,
+    ) -> Result<(), BoxErr>
+{
+    let payload = payload.trim().trim_start_matches("0x");
+    let raw = hex::decode(payload)
+        .map_err(|e| DecodeError::new(format!("Invalid payload {}: {}", payload, e)))?;
+    let adv = source.advertisement(&raw)?;
+    let mfr_data = Some(adv.mfr_data).filter(|m| !m.is_empty());
+    let svc_data = Some(adv.svc_data).filter(|s| !s.is_empty());
+    let device = Rc::new(RefCell::new(BTDevice::new(
+        address.to_string(),
+        address.to_string(),
+        mfr_data,
+        svc_data,
+        0,
+        Duration::from_secs(0),
+        factory.get_sensor_discovery_mode(address),
+    )));
+    device.borrow_mut().update_link_info(adv.link_info);
+    device.borrow_mut().set_key(conf.get_sensor_key(address).map(|k| k.to_vec()));
+    device.borrow_mut().set_expected_mac(conf.get_sensor_mac(address).map(|m| m.to_string()));
+    let sensors = factory.find_sensor_types(device);
+
+    if json {
+        let mut decoded = HashMap::new();
+        for (name, sensor) in &sensors {
+            let values: Value = sensor.get_sensor_measurements_json_str()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or(Value::Null);
+            decoded.insert(name.to_string(), values);
+        }
+        let mut line = serde_json::Map::new();
+        line.insert("payload".to_string(), Value::String(payload.to_string()));
+        line.insert("sensors".to_string(), serde_json::to_value(decoded)?);
+        println!("{}", Value::Object(line));
+        return Ok(());
+    }
+    println!("Payload: {}", payload);
+    if sensors.is_empty() {
+        println!("No sensor type accepts the payload");
+    }
+    for (name, sensor) in &sensors {
+        match sensor.get_sensor_measurements_str() {
+            Some(values) => println!("{}:\n{}", name, values),
+            None => println!("{}: accepts the payload but cannot decode it", name),
+        }
+    }
+    println!();
+    Ok(())
+}
+
+/// Decodes the payloads, or the lines of stdin if there are none.
+pub fn decode(
+    conf: config::SensorConf,
+    source: PayloadSource,
+    address: &str,
+    payloads: &[String],
+    json: bool,
+    ) -> Result<(), BoxErr>
+{
+    let factory = BTSensorFactory::new(conf.clone());
+    let address = address.to_uppercase();
+    if !payloads.is_empty() {
+        for payload in payloads {
+            decode_payload(&conf, &factory, &source, &address, payload, json)?;
+        }
+        return Ok(());
+    }
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A bad line in a pasted log does not stop the rest.
+        if let Err(e) = decode_payload(&conf, &factory, &source, &address, &line, json) {
+            eprintln!("{}", e);
+        }
+    }
+    Ok(())
+}
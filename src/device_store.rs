@@ -0,0 +1,358 @@
+use std::collections::{HashMap, hash_map::Entry};
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::time::Duration;
+
+use bt_sensor_factory::BTSensorFactory;
+use bt_sensor::BTSensor;
+use bt_device::{BTDevice, LinkInfo};
+use hci::Advertisement;
+use config;
+use consumer::Consumer;
+use recording::{Record, Recorder};
+
+// After this long without a reading a sensor may have restarted its sequence numbers.
+const SEQUENCE_RESET: Duration = Duration::from_secs(30);
+
+type MfrData = HashMap<u16, Vec<u8>>;
+type SvcData = HashMap<String, Vec<u8>>;
+
+/// Which adapter the readings of a device are taken from when several adapters hear it. Any
+/// takes a new reading from whichever adapter hears it first, Best only from the adapter
+/// that has heard the device with the strongest RSSI.
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq, Default)]
+pub enum AdapterPolicy {
+    #[default]
+    Any,
+    Best,
+}
+
+/// What an update of a device was.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Change {
+    Data,
+    // Heard again with the same data.
+    Sighting,
+    // An older reading than the last one, e.g. from an adapter that reports it late.
+    Stale,
+    Nothing,
+}
+
+/// The devices that have a sensor, shared by the capture backends. The devices are identified
+/// by the address, so the same device heard by several adapters is one device.
+pub struct DeviceStore {
+    sensor_factory: BTSensorFactory,
+    device_map: HashMap<String, Rc<RefCell<BTDevice>>>,
+    conf: config::SensorConf,
+    recorder: Option<Recorder>,
+}
+
+impl DeviceStore {
+
+    pub fn new(conf: config::SensorConf) -> DeviceStore {
+        DeviceStore{
+            sensor_factory: BTSensorFactory::new(conf.clone()),
+            device_map: HashMap::new(),
+            conf,
+            recorder: None,
+        }
+    }
+
+    /// Writes every advertisement given to the store to the recorder.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn get_device(&self, address: &str) -> Option<&Rc<RefCell<BTDevice>>> {
+        self.device_map.get(address)
+    }
+
+    fn is_accepted(conf: &config::SensorConf, device: &BTDevice, link_info: &LinkInfo) -> bool {
+        match (conf.get_adapter_policy(), &link_info.adapter) {
+            (AdapterPolicy::Best, Some(adapter)) => device
+                .get_best_adapter(conf.get_last_seen_forget())
+                .is_none_or(|best| best == adapter),
+            _ => true,
+        }
+    }
+
+    /// Checks the sequence number of the reading in the device data, if the sensor has one.
+    fn is_newer_reading(device: &Rc<RefCell<BTDevice>>) -> bool {
+        let sequence = device.borrow().get_sensor().and_then(|s| s.get_sequence());
+        match sequence {
+            Some(s) => device.borrow_mut().accept_sequence(s, SEQUENCE_RESET),
+            None => true,
+        }
+    }
+
+    /// Updates the link info of a known device. The values of another adapter than the one
+    /// the readings are taken from are only remembered for the adapter policy. A changed
+    /// RSSI means that the device was heard again with the same data, which is a sighting
+    /// of it. Returns true if the sighting is a new reading.
+    pub fn update_link_info(&mut self, address: &str, link_info: LinkInfo, meas_timestamp: u64) -> bool {
+        let device = match self.device_map.get(address) {
+            Some(d) => d.clone(),
+            None => return false,
+        };
+        if device.borrow().is_rssi_changed(&link_info) {
+            let device = device.borrow();
+            self.write_record(
+                address, device.get_mfr_data(), device.get_svc_data(), &link_info, meas_timestamp, false);
+        }
+        let mut device = device.borrow_mut();
+        let sighted = device.is_rssi_changed(&link_info)
+            && DeviceStore::is_accepted(&self.conf, &device, &link_info);
+        device.record_adapter(&link_info);
+        if device.get_link_info().adapter.is_none()
+            || device.get_link_info().adapter == link_info.adapter {
+            device.update_link_info(link_info);
+        }
+        if sighted {
+            device.sight(meas_timestamp);
+        }
+        sighted
+    }
+
+    /// True if the device is in the devicemap.
+    pub fn is_configured(&self, address: &str) -> bool {
+        self.conf.get_sensor_if(address).is_some()
+    }
+
+    pub fn remove_device(&mut self, address: &str) -> bool {
+        self.device_map.remove(address).is_some()
+    }
+
+    /// Removes the devices that have not been seen within the forget after time and returns
+    /// their addresses.
+    pub fn evict_unseen(&mut self) -> Vec<String> {
+        let forget_after = self.conf.get_forget_after();
+        let evicted: Vec<String> = self.device_map
+            .iter()
+            .filter(|(_, d)| !d.borrow().seen_more_resently_than(forget_after))
+            .map(|(address, _)| address.to_string())
+            .collect();
+        for address in &evicted {
+            debug!("{} not seen in {} s, forgetting it", address, forget_after.as_secs());
+            self.device_map.remove(address);
+        }
+        evicted
+    }
+
+    /// Fills in the data that is missing from a partial update from the known device.
+    pub fn merge_data(
+        &self,
+        address: &str,
+        mfr_data: Option<MfrData>,
+        svc_data: Option<SvcData>,
+        ) -> (Option<MfrData>, Option<SvcData>)
+    {
+        match self.device_map.get(address) {
+            Some(device) => {
+                let device = device.borrow();
+                (
+                    mfr_data.or_else(|| device.get_mfr_data().cloned()),
+                    svc_data.or_else(|| device.get_svc_data().cloned()),
+                )
+            },
+            None => (mfr_data, svc_data),
+        }
+    }
+
+    fn write_record(
+        &mut self,
+        address: &str,
+        mfr_data: Option<&MfrData>,
+        svc_data: Option<&SvcData>,
+        link_info: &LinkInfo,
+        meas_timestamp: u64,
+        snapshot: bool,
+        )
+    {
+        if let Some(ref mut recorder) = self.recorder {
+            let mut record = Record::new(address, mfr_data, svc_data, link_info, meas_timestamp);
+            record.snapshot = snapshot;
+            if let Err(e) = recorder.record(&record) {
+                warn!("Cannot record the advertisement of {}: {}", address, e);
+            }
+        }
+    }
+
+    /// Updates the device data and returns true if the device has a sensor and the data
+    /// changed. The same data heard by another adapter is not a new reading, neither is a
+    /// reading with an older sequence number than the last one, which deduplicates the
+    /// readings of the adapters.
+    pub fn update_device(
+        &mut self,
+        address: &str,
+        mfr_data: Option<MfrData>,
+        svc_data: Option<SvcData>,
+        link_info: LinkInfo,
+        meas_timestamp: u64,
+        ) -> bool
+    {
+        self.write_record(
+            address, mfr_data.as_ref(), svc_data.as_ref(), &link_info, meas_timestamp, false);
+        self.update(address, mfr_data, svc_data, link_info, meas_timestamp) == Change::Data
+    }
+
+    /// Like update_device for the data a BlueZ poll reads from the device cache, but a device
+    /// that was heard again with the same data is a new reading as well. It is recorded as a
+    /// snapshot, so a replay handles it like a poll and not as an advertisement.
+    pub fn poll_device(
+        &mut self,
+        address: &str,
+        mfr_data: Option<MfrData>,
+        svc_data: Option<SvcData>,
+        link_info: LinkInfo,
+        meas_timestamp: u64,
+        ) -> bool
+    {
+        self.write_record(
+            address, mfr_data.as_ref(), svc_data.as_ref(), &link_info, meas_timestamp, true);
+        match self.update(address, mfr_data, svc_data, link_info, meas_timestamp) {
+            Change::Data | Change::Sighting => true,
+            Change::Stale | Change::Nothing => false,
+        }
+    }
+
+    fn update(
+        &mut self,
+        address: &str,
+        mfr_data: Option<MfrData>,
+        svc_data: Option<SvcData>,
+        link_info: LinkInfo,
+        meas_timestamp: u64,
+        ) -> Change
+    {
+
+        let tag = self.conf.get_sensor_tag(address).unwrap_or(address);
+        match self.device_map.entry(address.to_string()) {
+            Entry::Occupied(e) => {
+                let device = e.get();
+                let sighted = device.borrow().is_rssi_changed(&link_info);
+                device.borrow_mut().record_adapter(&link_info);
+                if !DeviceStore::is_accepted(&self.conf, &device.borrow(), &link_info) {
+                    return Change::Nothing;
+                }
+                let previous = {
+                    let device = device.borrow();
+                    (device.get_mfr_data().cloned(), device.get_svc_data().cloned(), device.get_measurement_timestamp())
+                };
+                let updated = device.borrow_mut().update_data(mfr_data, svc_data, meas_timestamp);
+                if updated && !DeviceStore::is_newer_reading(device) {
+                    debug!("{} older reading from {:?} dropped", address, link_info.adapter);
+                    let (mfr_data, svc_data, previous_timestamp) = previous;
+                    let mut device = device.borrow_mut();
+                    device.set_mfr_data(mfr_data);
+                    device.set_svc_data(svc_data);
+                    device.set_measurement_timestamp(previous_timestamp);
+                    return Change::Stale;
+                }
+                // A poll reads the same data again until the device sends new data. The
+                // device is still seen if BlueZ has heard it since, which keeps the devices
+                // whose data never changes, like the beacons, up to date.
+                if !updated && sighted {
+                    device.borrow_mut().sight(meas_timestamp);
+                }
+                let same_adapter = device.borrow().get_link_info().adapter == link_info.adapter;
+                if updated || same_adapter {
+                    device.borrow_mut().update_link_info(link_info);
+                }
+                self.sensor_factory.set_sensor(device.clone());
+                match (updated, sighted) {
+                    (true, _) => Change::Data,
+                    (false, true) => Change::Sighting,
+                    (false, false) => Change::Nothing,
+                }
+            },
+            Entry::Vacant(e) => {
+                let device = Rc::new(RefCell::new(BTDevice::new(
+                    address.to_string(),
+                    tag.to_string(),
+                    mfr_data,
+                    svc_data,
+                    meas_timestamp,
+                    self.conf.get_last_seen_forget(),
+                    self.sensor_factory.get_sensor_discovery_mode(address),
+                )));
+                device.borrow_mut().set_key(self.conf.get_sensor_key(address).map(|k| k.to_vec()));
+                device.borrow_mut().set_expected_mac(self.conf.get_sensor_mac(address).map(|m| m.to_string()));
+                device.borrow_mut().set_frame_counters(self.conf.get_frame_counters());
+                device.borrow_mut().record_adapter(&link_info);
+                device.borrow_mut().update_link_info(link_info);
+                self.sensor_factory.set_sensor(device.clone());
+                if device.borrow().get_sensor().is_some() {
+                    DeviceStore::is_newer_reading(&device);
+                    e.insert(device);
+                    Change::Data
+                } else {
+                    Change::Nothing
+                }
+            }
+        }
+
+    }
+
+    /// Like update_device, but every advertisement counts as a new reading even if the
+    /// data is the same as in the previous one, unless another adapter than the one of the
+    /// previous reading heard it. Returns true if it is a new reading.
+    pub fn record_advertisement(
+        &mut self,
+        address: &str,
+        mfr_data: Option<MfrData>,
+        svc_data: Option<SvcData>,
+        link_info: LinkInfo,
+        meas_timestamp: u64,
+        ) -> bool
+    {
+        let adapter = link_info.adapter.clone();
+        self.write_record(
+            address, mfr_data.as_ref(), svc_data.as_ref(), &link_info, meas_timestamp, false);
+        let updated = match self.update(address, mfr_data, svc_data, link_info, meas_timestamp) {
+            Change::Data => true,
+            Change::Stale => return false,
+            Change::Sighting | Change::Nothing => false,
+        };
+        match self.device_map.get(address) {
+            Some(device) => {
+                let mut device = device.borrow_mut();
+                if !updated && device.get_link_info().adapter != adapter {
+                    return false;
+                }
+                device.set_measurement_timestamp(meas_timestamp);
+                device.reset_last_seen();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Records a parsed advertisement. An advertisement and its scan response carry
+    /// different data, the data missing from one is kept from the other.
+    pub fn add_advertisement(&mut self, adv: Advertisement, meas_timestamp: u64) -> bool {
+        let mfr_data = Some(adv.mfr_data).filter(|m| !m.is_empty());
+        let svc_data = Some(adv.svc_data).filter(|s| !s.is_empty());
+        let (mfr_data, svc_data) = self.merge_data(&adv.address, mfr_data, svc_data);
+        self.record_advertisement(&adv.address, mfr_data, svc_data, adv.link_info, meas_timestamp)
+    }
+
+    pub fn consume_device(&self, address: &str, consumer: &mut dyn Consumer) {
+        if let Some(device) = self.device_map.get(address) {
+            let device = device.borrow();
+            if let Some(sensor) = device.get_sensor() {
+                consumer.consume(&[sensor]);
+            }
+        }
+    }
+
+    pub fn consume_all(&self, consumer: &mut dyn Consumer) {
+        let devices: Vec<Ref<'_, BTDevice>> = self.device_map.values()
+            .map(|d| d.borrow())
+            .collect();
+        let sensors: Vec<&dyn BTSensor> = devices.iter()
+            .filter_map(|d| d.get_sensor())
+            .collect();
+        consumer.consume(&sensors);
+    }
+
+}
@@ -1,10 +1,7 @@
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum DiscoveryMode {
+    #[default]
     Auto,
     Configured(String),
 }
 
-impl Default for DiscoveryMode {
-    fn default() -> DiscoveryMode { DiscoveryMode::Auto }
-}
-
@@ -0,0 +1,22 @@
+use bt_device::BTDevice;
+
+// See https://github.com/google/eddystone/blob/master/protocol-specification.md for the
+// specification. All Eddystone frames are sent as service data of the same UUID and the
+// first byte tells the frame type.
+pub static SVC_DATA_UUID: &str = "0000feaa-0000-1000-8000-00805f9b34fb";
+pub const URL_FRAME: u8 = 0x10;
+pub const TLM_FRAME: u8 = 0x20;
+
+pub fn get_frame_type(data: &[u8]) -> Option<u8> {
+    data.first().copied()
+}
+
+/// The Eddystone service data of the device if it is a frame of the given type.
+pub fn get_frame(device: &BTDevice, frame_type: u8) -> Option<&[u8]> {
+    let data = device.get_svc_data()?.get(SVC_DATA_UUID)?;
+    if get_frame_type(data) == Some(frame_type) {
+        Some(data)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,208 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Value};
+use bt_device::BTDevice;
+use eddystone;
+
+pub struct EddystoneTLMConstructor;
+
+impl EddystoneTLMConstructor {
+    pub fn new() -> Box<EddystoneTLMConstructor> {
+        Box::new(EddystoneTLMConstructor{})
+    }
+}
+
+impl BTSensorConstructor for EddystoneTLMConstructor {
+    fn get_name(&self) -> &'static str {
+        "EddystoneTLM"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(EddystoneTLM::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        EddystoneTLM::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct EddystoneTLM {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for EddystoneTLM {
+
+    fn is_valid_data(&self) -> bool {
+        EddystoneTLM::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements()
+            .map(|m| {
+                let opt_str = |v: Option<String>| v.unwrap_or_else(|| "N/A".to_string());
+                format!(
+                    "battery {} mV\ttemp {}°C\nadvertisements {}\tuptime {} s",
+                    opt_str(m.battery.map(|b| b.to_string())),
+                    opt_str(m.temperature.map(|t| t.to_string())),
+                    m.advertisement_count,
+                    m.uptime,
+                )
+            })
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        match self._get_measurements() {
+            Some(m) => {
+                let mut map = HashMap::<String, Value>::new();
+                if let Some(battery) = m.battery {
+                    map.insert(
+                        "battery".to_string(),
+                        Value::Integer(battery as i64),
+                    );
+                }
+                if let Some(temperature) = m.temperature {
+                    map.insert(
+                        "temperature".to_string(),
+                        Value::Float(temperature),
+                    );
+                }
+                map.insert(
+                    "advertisement_count".to_string(),
+                    Value::Integer(m.advertisement_count as i64),
+                );
+                map.insert(
+                    "uptime".to_string(),
+                    Value::Float(m.uptime),
+                );
+                Some(map)
+            },
+            None => None,
+        }
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+// Only the unencrypted TLM version is supported.
+const TLM_VERSION: u8 = 0x00;
+const DATA_LEN: usize = 14;
+
+impl EddystoneTLM {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> EddystoneTLM {
+        EddystoneTLM{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        match eddystone::get_frame(device, eddystone::TLM_FRAME) {
+            Some(data) => data.len() == DATA_LEN && data[1] == TLM_VERSION,
+            None => false,
+        }
+    }
+
+    fn u32_at(data: &[u8], index: usize) -> u32 {
+        ((data[index] as u32) << 24) | ((data[index + 1] as u32) << 16) |
+            ((data[index + 2] as u32) << 8) | data[index + 3] as u32
+    }
+
+    // See https://github.com/google/eddystone/blob/master/eddystone-tlm/tlm-plain.md for the
+    // specification. All values are big endian.
+    //
+    // 0       frame type (0x20)
+    // 1       TLM version (0x00)
+    // 2-3     battery voltage, mV, 0 if not supported
+    // 4-5     beacon temperature, signed 8.8 fixed point, 0x8000 if not supported
+    // 6-9     advertisement count since power-on or reboot
+    // 10-13   time since power-on or reboot, 0.1 s
+    pub fn decode(data: &[u8]) -> Option<EddystoneTLMMeas> {
+        if data.len() != DATA_LEN || data[0] != eddystone::TLM_FRAME || data[1] != TLM_VERSION {
+            return None;
+        }
+        let battery = ((data[2] as u16) << 8) | data[3] as u16;
+        let temperature = ((data[4] as u16) << 8) | data[5] as u16;
+        Some(EddystoneTLMMeas{
+            battery: match battery {
+                0 => None,
+                _ => Some(battery),
+            },
+            temperature: match temperature {
+                0x8000 => None,
+                _ => Some(temperature as i16 as f64 / 256.0),
+            },
+            advertisement_count: EddystoneTLM::u32_at(data, 6),
+            uptime: EddystoneTLM::u32_at(data, 10) as f64 / 10.0,
+            ..Default::default()
+        })
+    }
+
+    fn _get_measurements(&self) -> Option<EddystoneTLMMeas> {
+        let device = self.get_bt_device();
+        let data = eddystone::get_frame(&device, eddystone::TLM_FRAME)?;
+        let mut meas = EddystoneTLM::decode(data)?;
+        meas.address = device.get_address().to_string();
+        meas.tag = device.get_tag().to_string();
+        Some(meas)
+    }
+
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct EddystoneTLMMeas {
+    battery: Option<u16>,
+    temperature: Option<f64>,
+    advertisement_count: u32,
+    uptime: f64,
+    address: String,
+    tag: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+
+    #[test]
+    fn decodes_tlm() {
+        // 3000 mV, 24.5 °C, 100 advertisements and 1000 seconds.
+        let meas = EddystoneTLM::decode(&hex::decode("20000BB818800000006400002710").unwrap()).unwrap();
+        assert_eq!(meas.battery, Some(3000));
+        assert_eq!(meas.temperature, Some(24.5));
+        assert_eq!(meas.advertisement_count, 100);
+        assert_eq!(meas.uptime, 1000.0);
+
+        // Without the battery and the temperature, -0.5 °C is 0xFF80.
+        let meas = EddystoneTLM::decode(&hex::decode("200000008000000000640000000A").unwrap()).unwrap();
+        assert_eq!(meas.battery, None);
+        assert_eq!(meas.temperature, None);
+        assert_eq!(meas.uptime, 1.0);
+        let meas = EddystoneTLM::decode(&hex::decode("20000BB8FF800000006400002710").unwrap()).unwrap();
+        assert_eq!(meas.temperature, Some(-0.5));
+
+        // The encrypted TLM version 0x01 is not decoded.
+        assert!(EddystoneTLM::decode(&hex::decode("20010BB818800000006400002710").unwrap()).is_none());
+    }
+
+}
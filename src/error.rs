@@ -1,25 +1,40 @@
 use std::error::Error;
 use std::fmt;
 
-#[derive(Debug)]
-pub struct BlueZError {
-    message: String,
-}
+// The errors are only a message, the type tells where it came from.
+macro_rules! message_error {
+    ($name:ident) => {
+        #[derive(Debug)]
+        pub struct $name {
+            message: String,
+        }
 
-impl BlueZError {
-    pub fn new(message: String) -> BlueZError {
-        BlueZError{message}
-    }
-}
+        impl $name {
+            pub fn new(message: String) -> $name {
+                $name{message}
+            }
+        }
 
-impl Error for BlueZError {
-    fn description(&self) -> &str {
-        &self.message
-    }
-}
+        impl Error for $name {
+            fn description(&self) -> &str {
+                &self.message
+            }
+        }
 
-impl fmt::Display for BlueZError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", &self.message)
-    }
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", &self.message)
+            }
+        }
+    };
 }
+
+message_error!(BlueZError);
+message_error!(DecodeError);
+message_error!(HciError);
+message_error!(ReplayError);
+message_error!(MqttError);
+message_error!(SimulatorError);
+// An error that retrying does not fix, e.g. an address that cannot be listened on. The
+// supervisor stops on it.
+message_error!(FatalError);
@@ -0,0 +1,109 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+
+use serde_json;
+
+/// The last accepted frame counters of the encrypted sensors by address. The counters are
+/// kept when an unseen device is forgotten and, with a file, over restarts, so an old frame
+/// is never accepted again. A sensor that starts counting from zero again, e.g. after a
+/// battery change, is accepted only after its entry is removed from the file.
+#[derive(Debug, Default)]
+pub struct FrameCounters {
+    path: Option<String>,
+    counters: RefCell<HashMap<String, u32>>,
+}
+
+/// How a frame counter compares to the last accepted one.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FrameCheck {
+    New,
+    // The same frame read again, e.g. by the next poll. It is not a new frame.
+    Same,
+    Replayed,
+}
+
+impl FrameCounters {
+
+    /// Reads the counters from the file, a JSON object of the addresses and the counters.
+    /// A missing file is created when the first counter is accepted.
+    pub fn load(path: Option<&str>) -> FrameCounters {
+        let counters = match path.map(File::open) {
+            Some(Ok(f)) => serde_json::from_reader(f)
+                .unwrap_or_else(|e| panic!("JSON error in {}: {}", path.unwrap_or_default(), e)),
+            Some(Err(ref e)) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Some(Err(e)) => panic!("Cannot open file {}: {}", path.unwrap_or_default(), e),
+            None => HashMap::new(),
+        };
+        FrameCounters{
+            path: path.map(|p| p.to_string()),
+            counters: RefCell::new(counters),
+        }
+    }
+
+    /// Accepts a counter after the last accepted one of the address, the last one again is
+    /// the same frame. Any older counter is a replay.
+    pub fn check(&self, address: &str, counter: u32) -> FrameCheck {
+        let last = self.counters.borrow().get(address).cloned();
+        match last {
+            Some(last) if counter < last => FrameCheck::Replayed,
+            Some(last) if counter == last => FrameCheck::Same,
+            _ => {
+                self.counters.borrow_mut().insert(address.to_string(), counter);
+                self.save();
+                FrameCheck::New
+            },
+        }
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return,
+        };
+        // Written to a temporary file first so that a crash does not leave a partial file.
+        let tmp_path = format!("{}.tmp", path);
+        let result = serde_json::to_vec(&*self.counters.borrow())
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&tmp_path, json).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp_path, path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Cannot save the frame counters to {}: {}", path, e);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    const ADDRESS: &str = "54:48:E6:8F:80:A5";
+
+    #[test]
+    fn rejects_older_counters() {
+        let counters = FrameCounters::default();
+        assert_eq!(counters.check(ADDRESS, 5000), FrameCheck::New);
+        assert_eq!(counters.check(ADDRESS, 5000), FrameCheck::Same);
+        assert_eq!(counters.check(ADDRESS, 4999), FrameCheck::Replayed);
+        assert_eq!(counters.check(ADDRESS, 1), FrameCheck::Replayed);
+        assert_eq!(counters.check(ADDRESS, 5001), FrameCheck::New);
+        assert_eq!(counters.check("A4:C1:38:38:83:F4", 1), FrameCheck::New);
+    }
+
+    #[test]
+    fn keeps_counters_over_restarts() {
+        let path = env::temp_dir().join(format!("bt-sensor-counters-{}.json", process::id()));
+        let path = path.to_str().unwrap();
+        let counters = FrameCounters::load(Some(path));
+        assert_eq!(counters.check(ADDRESS, 5000), FrameCheck::New);
+
+        let counters = FrameCounters::load(Some(path));
+        assert_eq!(counters.check(ADDRESS, 4999), FrameCheck::Replayed);
+        assert_eq!(counters.check(ADDRESS, 5000), FrameCheck::Same);
+        fs::remove_file(path).unwrap();
+    }
+}
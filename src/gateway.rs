@@ -0,0 +1,265 @@
+use std::env;
+use std::error;
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tiny_http::{Method, Request, Response, Server};
+use serde_json::{self, Value};
+use hex;
+
+use backend::Backend;
+use config;
+use consumer::Consumer;
+use device_store::DeviceStore;
+use error::{DecodeError, FatalError};
+use hci::{self, Advertisement};
+use recording::Recorder;
+use timestamp::unix_timestamp;
+
+type BoxErr = Box<dyn error::Error>;
+
+// A gateway sends the advertisements it has heard every few seconds, so one request is small.
+const MAX_BODY_LEN: u64 = 1024 * 1024;
+
+/// Receives the advertisements Ruuvi Gateways POST to a custom HTTP server. The body is like
+/// {"data": {"gw_mac": "AA:BB:CC:DD:EE:FF", "timestamp": 1584530427, "tags": {
+/// "CB:B8:33:4C:88:4F": {"rssi": -59, "timestamp": 1584530427, "data": "0201061BFF9904..."}}}},
+/// where the data is the raw advertisement. The MAC address of the gateway is used as the
+/// adapter. If a token is set, the requests must have it as the bearer token.
+pub struct GatewayBackend {
+    address: String,
+    token: Option<String>,
+    store: DeviceStore,
+    sender: Sender<Vec<(u64, Advertisement)>>,
+    receiver: Receiver<Vec<(u64, Advertisement)>>,
+    server: Option<Arc<Server>>,
+}
+
+/// Older gateway firmware sends the numbers as strings.
+pub fn as_i64(value: &Value) -> Option<i64> {
+    value.as_i64().or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+}
+
+/// Parses the advertisements of a gateway request and gives them the timestamp in
+/// milliseconds. The time of the gateway is used if it has one, it may have been sent late.
+fn parse_request(body: &str) -> Result<Vec<(u64, Advertisement)>, DecodeError> {
+    let json: Value = serde_json::from_str(body)
+        .map_err(|e| DecodeError::new(format!("Invalid JSON: {}", e)))?;
+    let data = json.get("data")
+        .ok_or_else(|| DecodeError::new("No data".to_string()))?;
+    let tags = data.get("tags")
+        .and_then(|t| t.as_object())
+        .ok_or_else(|| DecodeError::new("No tags".to_string()))?;
+    let gateway = data.get("gw_mac").and_then(|m| m.as_str()).map(|m| m.to_uppercase());
+    let received = unix_timestamp();
+    let mut advertisements = Vec::new();
+    for (address, tag) in tags {
+        let raw = match tag.get("data").and_then(|d| d.as_str()).map(hex::decode) {
+            Some(Ok(raw)) => raw,
+            _ => {
+                warn!("No valid data for {} from gateway {:?}", address, gateway);
+                continue;
+            },
+        };
+        let mut adv = Advertisement{
+            address: address.to_uppercase(),
+            ..Default::default()
+        };
+        if let Err(e) = hci::parse_advertising_data(&raw, &mut adv) {
+            warn!("Cannot parse the advertisement of {}: {}", address, e);
+            continue;
+        }
+        adv.link_info.rssi = tag.get("rssi").and_then(as_i64).map(|r| r as i16);
+        adv.link_info.adapter = gateway.clone();
+        let timestamp = tag.get("timestamp")
+            .and_then(as_i64)
+            .filter(|t| *t > 0)
+            .map(|t| t as u64 * 1000)
+            .unwrap_or(received);
+        advertisements.push((timestamp, adv));
+    }
+    Ok(advertisements)
+}
+
+/// Compares the whole token so that the time does not tell how much of it matched.
+fn is_authorized(req: &Request, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(t) => t,
+        None => return true,
+    };
+    let expected = format!("Bearer {}", token);
+    req.headers()
+        .iter()
+        .filter(|h| h.field.equiv("Authorization"))
+        .any(|h| {
+            let value = h.value.as_str().as_bytes();
+            value.len() == expected.len()
+                && value.iter().zip(expected.as_bytes()).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+        })
+}
+
+fn handle_request(
+    sender: &Sender<Vec<(u64, Advertisement)>>,
+    token: Option<&str>,
+    req: &mut Request,
+    ) -> u16
+{
+    let remote = req.remote_addr().map_or("unknown".to_string(), |a| a.to_string());
+    if *req.method() != Method::Post {
+        return 405;
+    }
+    if !is_authorized(req, token) {
+        warn!("Unauthorized gateway request from {}", remote);
+        return 401;
+    }
+    let mut body = String::new();
+    if let Err(e) = req.as_reader().take(MAX_BODY_LEN).read_to_string(&mut body) {
+        warn!("Cannot read the gateway request from {}: {}", remote, e);
+        return 400;
+    }
+    match parse_request(&body) {
+        Ok(advertisements) => {
+            debug!("{} advertisements from {}", advertisements.len(), remote);
+            // The receiver lives as long as the server.
+            let _ = sender.send(advertisements);
+            200
+        },
+        Err(e) => {
+            warn!("Invalid gateway request from {}: {}", remote, e);
+            400
+        },
+    }
+}
+
+impl GatewayBackend {
+
+    /// Without the token option the token is read from GATEWAY_TOKEN if set.
+    pub fn new(conf: config::SensorConf, address: &str, token: Option<&str>) -> GatewayBackend {
+        let (sender, receiver) = mpsc::channel();
+        let token = token.map(|t| t.to_string()).or_else(|| env::var("GATEWAY_TOKEN").ok());
+        GatewayBackend{
+            address: address.to_string(),
+            token: token.filter(|t| !t.is_empty()),
+            store: DeviceStore::new(conf),
+            sender,
+            receiver,
+            server: None,
+        }
+    }
+
+    fn read_advertisements(
+        &mut self,
+        mut consumer: Option<&mut dyn Consumer>,
+        wait: Duration,
+        ) -> Result<(), BoxErr>
+    {
+        let start = Instant::now();
+        while start.elapsed() < wait {
+            let advertisements = match self.receiver.recv_timeout(wait.saturating_sub(start.elapsed())) {
+                Ok(a) => a,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(e) => return Err(Box::new(e)),
+            };
+            for (timestamp, adv) in advertisements {
+                let address = adv.address.clone();
+                let is_new = self.store.add_advertisement(adv, timestamp);
+                if let (true, Some(c)) = (is_new, consumer.as_mut()) {
+                    self.store.consume_device(&address, &mut **c);
+                }
+            }
+        }
+        Ok(())
+    }
+
+}
+
+impl Backend for GatewayBackend {
+
+    /// Starts the HTTP server. It keeps running when the capture is restarted.
+    fn initialize(&mut self) -> Result<(), BoxErr> {
+        if self.server.is_none() {
+            let server = Server::http(self.address.as_str())
+                .map_err(|e| FatalError::new(format!("Cannot listen on {}: {}", self.address, e)))?;
+            let server = Arc::new(server);
+            info!("Listening to Ruuvi Gateways on {}", server.server_addr());
+            let sender = self.sender.clone();
+            let token = self.token.clone();
+            let requests = server.clone();
+            thread::spawn(move || {
+                for mut req in requests.incoming_requests() {
+                    let status = handle_request(&sender, token.as_deref(), &mut req);
+                    if let Err(e) = req.respond(Response::empty(status)) {
+                        warn!("Cannot respond to a gateway request: {}", e);
+                    }
+                }
+            });
+            self.server = Some(server);
+        }
+        Ok(())
+    }
+
+    fn consume(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        self.read_advertisements(Some(consumer), wait)?;
+        consumer.flush();
+        self.store.evict_unseen();
+        Ok(())
+    }
+
+    fn list(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        self.read_advertisements(None, wait)?;
+        self.store.consume_all(consumer);
+        consumer.flush();
+        Ok(())
+    }
+
+    fn set_recorder(&mut self, recorder: Recorder) {
+        self.store.set_recorder(recorder);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DF5: &str = "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F";
+
+    #[test]
+    fn parses_documented_body() {
+        let body = format!(r#"{{"data": {{"gw_mac": "aa:bb:cc:dd:ee:ff", "timestamp": 1584530427,
+            "tags": {{"cb:b8:33:4c:88:4f": {{"rssi": -59, "timestamp": 1584530427, "data": "{}"}}}}}}}}"#, DF5);
+        let advertisements = parse_request(&body).unwrap();
+        assert_eq!(advertisements.len(), 1);
+        let (timestamp, ref adv) = advertisements[0];
+        assert_eq!(timestamp, 1584530427000);
+        assert_eq!(adv.address, "CB:B8:33:4C:88:4F");
+        assert_eq!(adv.link_info.rssi, Some(-59));
+        assert_eq!(adv.link_info.adapter.as_deref(), Some("AA:BB:CC:DD:EE:FF"));
+        assert_eq!(adv.mfr_data[&0x0499][0], 0x05);
+    }
+
+    #[test]
+    fn parses_numbers_as_strings() {
+        let body = format!(r#"{{"data": {{"gw_mac": "AA:BB:CC:DD:EE:FF",
+            "tags": {{"CB:B8:33:4C:88:4F": {{"rssi": "-70", "timestamp": "1584530427", "data": "{}"}}}}}}}}"#, DF5);
+        let advertisements = parse_request(&body).unwrap();
+        assert_eq!(advertisements[0].0, 1584530427000);
+        assert_eq!(advertisements[0].1.link_info.rssi, Some(-70));
+    }
+
+    #[test]
+    fn skips_bad_hex() {
+        let body = format!(r#"{{"data": {{"gw_mac": "AA:BB:CC:DD:EE:FF", "tags": {{
+            "11:22:33:44:55:66": {{"rssi": -80, "data": "02010G"}},
+            "CB:B8:33:4C:88:4F": {{"rssi": -59, "data": "{}"}}}}}}}}"#, DF5);
+        let advertisements = parse_request(&body).unwrap();
+        assert_eq!(advertisements.len(), 1);
+        assert_eq!(advertisements[0].1.address, "CB:B8:33:4C:88:4F");
+        assert!(parse_request(r#"{"data": {}}"#).is_err());
+        assert!(parse_request("not json").is_err());
+    }
+
+}
@@ -0,0 +1,249 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Value};
+use bt_device::BTDevice;
+
+pub struct GoveeConstructor;
+
+impl GoveeConstructor {
+    pub fn new() -> Box<GoveeConstructor> {
+        Box::new(GoveeConstructor{})
+    }
+}
+
+impl BTSensorConstructor for GoveeConstructor {
+    fn get_name(&self) -> &'static str {
+        "Govee"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(Govee::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        Govee::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct Govee {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for Govee {
+
+    fn is_valid_data(&self) -> bool {
+        Govee::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements()
+            .map(|m| {
+                format!(
+                    "model {}\tbattery {}%\ntemp {}°C\thumidity {}%",
+                    m.model,
+                    m.battery_percent,
+                    m.temperature,
+                    m.humidity,
+                )
+            })
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        match self._get_measurements() {
+            Some(m) => {
+                let mut map = HashMap::<String, Value>::new();
+                map.insert(
+                    "temperature".to_string(),
+                    Value::Float(m.temperature),
+                );
+                map.insert(
+                    "humidity".to_string(),
+                    Value::Integer(m.humidity.round() as i64),
+                );
+                map.insert(
+                    "humidity_float".to_string(),
+                    Value::Float(m.humidity),
+                );
+                map.insert(
+                    "battery_percent".to_string(),
+                    Value::Integer(m.battery_percent as i64),
+                );
+                Some(map)
+            },
+            None => None,
+        }
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+static H5075_MFR_DATA_FIELD: u16 = 0xEC88;
+const H5075_DATA_LEN: usize = 6;
+static H5179_MFR_DATA_FIELD: u16 = 0x8801;
+const H5179_DATA_LEN: usize = 9;
+
+impl Govee {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> Govee {
+        Govee{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        match device.get_mfr_data() {
+            Some(m) => {
+                m.get(&H5075_MFR_DATA_FIELD).is_some_and(|d| d.len() == H5075_DATA_LEN) ||
+                m.get(&H5179_MFR_DATA_FIELD).is_some_and(|d| d.len() == H5179_DATA_LEN)
+            },
+            None => false,
+        }
+    }
+
+    // H5075, H5072, H5101 and the other models that use company id 0xEC88.
+    //
+    // 0       always zero
+    // 1-3     big endian, the highest bit is the temperature sign. The rest is
+    //         temperature * 10000 + humidity * 10.
+    // 4       battery, %
+    // 5       always zero
+    pub fn decode_h5075(data: &[u8]) -> Option<GoveeMeas> {
+        if data.len() != H5075_DATA_LEN {
+            return None;
+        }
+        let raw = ((data[1] as u32) << 16) | ((data[2] as u32) << 8) | data[3] as u32;
+        let value = raw & 0x7FFFFF;
+        let temperature = (value / 1000) as f64 / 10.0;
+        let temperature = match raw & 0x800000 {
+            0 => temperature,
+            _ => -temperature,
+        };
+        Some(GoveeMeas{
+            model: "H5075".to_string(),
+            temperature,
+            humidity: (value % 1000) as f64 / 10.0,
+            battery_percent: data[4],
+            ..Default::default()
+        })
+    }
+
+    // H5179 with company id 0x8801.
+    //
+    // 0-3     unknown
+    // 4-5     temperature, little endian i16, 0.01 °C
+    // 6-7     humidity, little endian u16, 0.01 %
+    // 8       battery, %
+    pub fn decode_h5179(data: &[u8]) -> Option<GoveeMeas> {
+        if data.len() != H5179_DATA_LEN {
+            return None;
+        }
+        let temperature = (((data[5] as u16) << 8) | data[4] as u16) as i16;
+        let humidity = ((data[7] as u16) << 8) | data[6] as u16;
+        Some(GoveeMeas{
+            model: "H5179".to_string(),
+            temperature: temperature as f64 / 100.0,
+            humidity: humidity as f64 / 100.0,
+            battery_percent: data[8],
+            ..Default::default()
+        })
+    }
+
+    fn _get_measurements(&self) -> Option<GoveeMeas> {
+        let device = self.get_bt_device();
+        let mfr_data = device.get_mfr_data()?;
+        let h5075 = mfr_data.get(&H5075_MFR_DATA_FIELD).and_then(|d| Govee::decode_h5075(d));
+        let mut meas = match h5075 {
+            Some(m) => m,
+            None => Govee::decode_h5179(mfr_data.get(&H5179_MFR_DATA_FIELD)?)?,
+        };
+        meas.address = device.get_address().to_string();
+        meas.tag = device.get_tag().to_string();
+        Some(meas)
+    }
+
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct GoveeMeas {
+    model: String,
+    temperature: f64,
+    humidity: f64,
+    battery_percent: u8,
+    address: String,
+    tag: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+
+    fn device_with_mfr_data(id: u16, data: &[u8]) -> BTDevice {
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(id, data.to_vec());
+        BTDevice::new(
+            "A4:C1:38:00:00:01".to_string(),
+            "govee".to_string(),
+            Some(mfr_data),
+            None,
+            0,
+            Duration::from_secs(60),
+            DiscoveryMode::Auto,
+        )
+    }
+
+    #[test]
+    fn decodes_h5075() {
+        let meas = Govee::decode_h5075(&[0x00, 0x03, 0x41, 0xC2, 0x64, 0x00]).unwrap();
+        assert_eq!(meas.temperature, 21.3);
+        assert_eq!(meas.humidity, 44.2);
+        assert_eq!(meas.battery_percent, 100);
+    }
+
+    #[test]
+    fn decodes_h5075_below_zero() {
+        let meas = Govee::decode_h5075(&[0x00, 0x80, 0x16, 0x5D, 0x4F, 0x00]).unwrap();
+        assert_eq!(meas.temperature, -0.5);
+        assert_eq!(meas.humidity, 72.5);
+        assert_eq!(meas.battery_percent, 79);
+    }
+
+    #[test]
+    fn decodes_h5179() {
+        let meas = Govee::decode_h5179(&[0xEC, 0x00, 0x01, 0x01, 0x0A, 0x0A, 0xA4, 0x06, 0x64]).unwrap();
+        assert_eq!(meas.temperature, 25.7);
+        assert_eq!(meas.humidity, 17.0);
+        assert_eq!(meas.battery_percent, 100);
+    }
+
+    #[test]
+    fn recognizes_models_by_company_id_and_length() {
+        assert!(Govee::_is_valid_data(&device_with_mfr_data(0xEC88, &[0x00, 0x03, 0x41, 0xC2, 0x64, 0x00])));
+        assert!(Govee::_is_valid_data(&device_with_mfr_data(0x8801, &[0xEC, 0x00, 0x01, 0x01, 0x0A, 0x0A, 0xA4, 0x06, 0x64])));
+        assert!(!Govee::_is_valid_data(&device_with_mfr_data(0xEC88, &[0x00, 0x03, 0x41])));
+        assert!(!Govee::_is_valid_data(&device_with_mfr_data(0x0499, &[0x00, 0x03, 0x41, 0xC2, 0x64, 0x00])));
+    }
+
+}
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+
+use bt_device::LinkInfo;
+use error::DecodeError;
+
+// See the Bluetooth Core Specification, Vol 4, Part E, section 5.4 for the packet formats and
+// section 7.7 for the events.
+pub const HCI_COMMAND_PKT: u8 = 0x01;
+pub const HCI_EVENT_PKT: u8 = 0x04;
+
+pub const EVT_CMD_COMPLETE: u8 = 0x0E;
+pub const EVT_CMD_STATUS: u8 = 0x0F;
+pub const EVT_LE_META: u8 = 0x3E;
+
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+const LE_EXTENDED_ADVERTISING_REPORT: u8 = 0x0D;
+
+// The RSSI and the TX power of an extended report when they are not available.
+const EXT_ADV_NOT_AVAILABLE: i8 = 0x7F;
+
+// The data status of an extended report, the rest of the data comes in the next reports or
+// the controller has given up on it.
+const DATA_COMPLETE: u16 = 0x00;
+const DATA_INCOMPLETE: u16 = 0x01;
+// The maximum length of extended advertising data, and how many fragmented advertisements
+// are collected at a time.
+const MAX_EXT_ADV_DATA_LEN: usize = 1650;
+const MAX_FRAGMENTED: usize = 64;
+
+// Advertising data types, see the Assigned Numbers document.
+const AD_SHORT_NAME: u8 = 0x08;
+const AD_COMPLETE_NAME: u8 = 0x09;
+const AD_TX_POWER_LEVEL: u8 = 0x0A;
+pub const AD_MANUFACTURER_DATA: u8 = 0xFF;
+pub const AD_SERVICE_DATA_16: u8 = 0x16;
+const AD_SERVICE_DATA_32: u8 = 0x20;
+const AD_SERVICE_DATA_128: u8 = 0x21;
+
+/// One advertising report with the advertising data split to the same manufacturer and
+/// service data maps that BlueZ reports.
+#[derive(Debug, Default)]
+pub struct Advertisement {
+    pub address: String,
+    pub mfr_data: HashMap<u16, Vec<u8>>,
+    pub svc_data: HashMap<String, Vec<u8>>,
+    pub link_info: LinkInfo,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    CommandComplete { opcode: u16, status: u8 },
+    CommandStatus { opcode: u16, status: u8 },
+    Advertisements(Vec<Advertisement>),
+    Other,
+}
+
+/// The opcode is the OCF in the lowest ten bits and the OGF in the highest six bits.
+pub fn opcode(ogf: u16, ocf: u16) -> u16 {
+    (ogf << 10) | ocf
+}
+
+pub fn command_packet(opcode: u16, params: &[u8]) -> Vec<u8> {
+    let mut packet = vec![HCI_COMMAND_PKT, (opcode & 0xFF) as u8, (opcode >> 8) as u8, params.len() as u8];
+    packet.extend_from_slice(params);
+    packet
+}
+
+fn u16_le(data: &[u8], index: usize) -> Result<u16, DecodeError> {
+    match data.get(index..index + 2) {
+        Some(b) => Ok(((b[1] as u16) << 8) | b[0] as u16),
+        None => Err(DecodeError::new("Truncated HCI event".to_string())),
+    }
+}
+
+fn byte_at(data: &[u8], index: usize) -> Result<u8, DecodeError> {
+    data.get(index)
+        .copied()
+        .ok_or_else(|| DecodeError::new("Truncated HCI event".to_string()))
+}
+
+/// The address types 2 and 3 are resolved private addresses reported with the identity
+/// address, BlueZ reports them as public and random too.
+fn address_type_string(address_type: u8) -> Option<String> {
+    match address_type {
+        0x00 | 0x02 => Some("public".to_string()),
+        0x01 | 0x03 => Some("random".to_string()),
+        _ => None,
+    }
+}
+
+/// Bluetooth addresses are sent least significant byte first.
+pub fn format_address(data: &[u8]) -> String {
+    let parts: Vec<String> = data.iter().rev().map(|b| format!("{:02X}", b)).collect();
+    parts.join(":")
+}
+
+/// Expands a 16 or 32 bit UUID to the 128 bit string form BlueZ uses for the service data
+/// keys.
+pub fn uuid_string(uuid: &[u8]) -> String {
+    let hex: Vec<String> = uuid.iter().rev().map(|b| format!("{:02x}", b)).collect();
+    match uuid.len() {
+        2 => format!("0000{}-0000-1000-8000-00805f9b34fb", hex.concat()),
+        4 => format!("{}-0000-1000-8000-00805f9b34fb", hex.concat()),
+        _ => format!(
+            "{}-{}-{}-{}-{}",
+            hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(),
+            hex[8..10].concat(), hex[10..16].concat(),
+        ),
+    }
+}
+
+/// Parses the advertising data structures: length, type and length - 1 bytes of data.
+pub fn parse_advertising_data(data: &[u8], adv: &mut Advertisement) -> Result<(), DecodeError> {
+    let mut i = 0;
+    while i < data.len() {
+        let len = data[i] as usize;
+        // Zero length marks the end of the significant part, the rest is padding.
+        if len == 0 {
+            break;
+        }
+        let structure = data.get(i + 1..i + 1 + len)
+            .ok_or_else(|| DecodeError::new("Truncated advertising data".to_string()))?;
+        let (ad_type, ad_data) = (structure[0], &structure[1..]);
+        match ad_type {
+            AD_MANUFACTURER_DATA if ad_data.len() >= 2 => {
+                let company_id = ((ad_data[1] as u16) << 8) | ad_data[0] as u16;
+                adv.mfr_data.insert(company_id, ad_data[2..].to_vec());
+            },
+            AD_SERVICE_DATA_16 if ad_data.len() >= 2 => {
+                adv.svc_data.insert(uuid_string(&ad_data[0..2]), ad_data[2..].to_vec());
+            },
+            AD_SERVICE_DATA_32 if ad_data.len() >= 4 => {
+                adv.svc_data.insert(uuid_string(&ad_data[0..4]), ad_data[4..].to_vec());
+            },
+            AD_SERVICE_DATA_128 if ad_data.len() >= 16 => {
+                adv.svc_data.insert(uuid_string(&ad_data[0..16]), ad_data[16..].to_vec());
+            },
+            AD_TX_POWER_LEVEL if ad_data.len() == 1 => {
+                adv.link_info.tx_power = Some(ad_data[0] as i8 as i16);
+            },
+            // The short name is used only if the complete name is not advertised.
+            AD_COMPLETE_NAME => {
+                adv.link_info.name = Some(String::from_utf8_lossy(ad_data).into_owned());
+            },
+            AD_SHORT_NAME if adv.link_info.name.is_none() => {
+                adv.link_info.name = Some(String::from_utf8_lossy(ad_data).into_owned());
+            },
+            _ => (),
+        }
+        i += 1 + len;
+    }
+    Ok(())
+}
+
+// Controllers send one report per event, but the reports are parsed in a loop the same way
+// BlueZ does it.
+//
+// 0       number of reports
+// then for each report:
+// 0       event type
+// 1       address type
+// 2-7     address
+// 8       data length
+// 9-      data, followed by RSSI
+fn parse_advertising_reports(params: &[u8]) -> Result<Vec<Advertisement>, DecodeError> {
+    let count = byte_at(params, 0)?;
+    let mut reports = Vec::new();
+    let mut i = 1;
+    for _ in 0..count {
+        let address_type = byte_at(params, i + 1)?;
+        let address = params.get(i + 2..i + 8)
+            .ok_or_else(|| DecodeError::new("Truncated advertising report".to_string()))?;
+        let data_len = byte_at(params, i + 8)? as usize;
+        let data = params.get(i + 9..i + 9 + data_len)
+            .ok_or_else(|| DecodeError::new("Truncated advertising report".to_string()))?;
+        let rssi = byte_at(params, i + 9 + data_len)? as i8;
+        let mut adv = Advertisement{
+            address: format_address(address),
+            ..Default::default()
+        };
+        adv.link_info.address_type = address_type_string(address_type);
+        adv.link_info.rssi = Some(rssi as i16);
+        parse_advertising_data(data, &mut adv)?;
+        reports.push(adv);
+        i += 9 + data_len + 1;
+    }
+    Ok(reports)
+}
+
+// 0       number of reports
+// then for each report:
+// 0-1     event type, the data status in bits 5 and 6
+// 2       address type
+// 3-8     address
+// 9-10    primary and secondary PHY
+// 11      advertising SID
+// 12      TX power
+// 13      RSSI
+// 14-15   periodic advertising interval
+// 16      direct address type
+// 17-22   direct address
+// 23      data length
+// 24-     data
+impl EventParser {
+
+    fn parse_extended_advertising_reports(&mut self, params: &[u8]) -> Result<Vec<Advertisement>, DecodeError> {
+        let count = byte_at(params, 0)?;
+        let mut reports = Vec::new();
+        let mut i = 1;
+        for _ in 0..count {
+            let data_status = (u16_le(params, i)? >> 5) & 0x03;
+            let address_type = byte_at(params, i + 2)?;
+            let address = params.get(i + 3..i + 9)
+                .ok_or_else(|| DecodeError::new("Truncated extended advertising report".to_string()))?;
+            let sid = byte_at(params, i + 11)?;
+            let tx_power = byte_at(params, i + 12)? as i8;
+            let rssi = byte_at(params, i + 13)? as i8;
+            let data_len = byte_at(params, i + 23)? as usize;
+            let data = params.get(i + 24..i + 24 + data_len)
+                .ok_or_else(|| DecodeError::new("Truncated extended advertising report".to_string()))?;
+            i += 24 + data_len;
+
+            let key = (format_address(address), address_type, sid);
+            let data = match data_status {
+                DATA_COMPLETE => match self.fragments.remove(&key) {
+                    Some(mut fragments) => {
+                        fragments.extend_from_slice(data);
+                        if fragments.len() > MAX_EXT_ADV_DATA_LEN {
+                            debug!("Too long extended advertisement from {} dropped", key.0);
+                            continue;
+                        }
+                        fragments
+                    },
+                    None => data.to_vec(),
+                },
+                DATA_INCOMPLETE => {
+                    if !self.fragments.contains_key(&key) && self.fragments.len() >= MAX_FRAGMENTED {
+                        debug!("Too many fragmented advertisements, dropping {}", key.0);
+                        continue;
+                    }
+                    let fragments = self.fragments.entry(key).or_default();
+                    if fragments.len() <= MAX_EXT_ADV_DATA_LEN {
+                        fragments.extend_from_slice(data);
+                    }
+                    continue;
+                },
+                // The controller could not receive the rest.
+                _ => {
+                    debug!("Truncated extended advertisement from {} dropped", key.0);
+                    self.fragments.remove(&key);
+                    continue;
+                },
+            };
+
+            let mut adv = Advertisement{
+                address: key.0,
+                ..Default::default()
+            };
+            adv.link_info.address_type = address_type_string(address_type);
+            if rssi != EXT_ADV_NOT_AVAILABLE {
+                adv.link_info.rssi = Some(rssi as i16);
+            }
+            // The TX power level in the advertising data is preferred, it is what BlueZ reports.
+            if tx_power != EXT_ADV_NOT_AVAILABLE {
+                adv.link_info.tx_power = Some(tx_power as i16);
+            }
+            // Invalid data of one report does not drop the other reports of the event.
+            match parse_advertising_data(&data, &mut adv) {
+                Ok(()) => reports.push(adv),
+                Err(e) => debug!("Invalid extended advertisement from {}: {}", adv.address, e),
+            }
+        }
+        Ok(reports)
+    }
+
+}
+
+// Advertising physical channel PDUs that carry the advertiser address and advertising data,
+// see the Bluetooth Core Specification, Vol 6, Part B, section 2.3.
+const ADV_IND: u8 = 0x00;
+const ADV_NONCONN_IND: u8 = 0x02;
+const SCAN_RSP: u8 = 0x04;
+const ADV_SCAN_IND: u8 = 0x06;
+
+// 0       PDU type in the lowest four bits, TxAdd in bit 6
+// 1       payload length
+// 2-7     advertiser address
+// 8-      data, followed by the CRC that is not included in the length
+/// Parses a link layer advertising PDU captured by a sniffer, e.g. an nRF or Ubertooth. The
+/// other PDU types return None.
+pub fn parse_advertising_pdu(pdu: &[u8]) -> Result<Option<Advertisement>, DecodeError> {
+    let header = byte_at(pdu, 0)?;
+    match header & 0x0F {
+        ADV_IND | ADV_NONCONN_IND | SCAN_RSP | ADV_SCAN_IND => (),
+        _ => return Ok(None),
+    }
+    let len = byte_at(pdu, 1)? as usize;
+    let payload = pdu.get(2..2 + len)
+        .filter(|p| p.len() >= 6)
+        .ok_or_else(|| DecodeError::new("Truncated advertising PDU".to_string()))?;
+    let mut adv = Advertisement{
+        address: format_address(&payload[0..6]),
+        ..Default::default()
+    };
+    adv.link_info.address_type = address_type_string((header >> 6) & 0x01);
+    parse_advertising_data(&payload[6..], &mut adv)?;
+    Ok(Some(adv))
+}
+
+/// Parses the HCI events of one adapter. The controller reports a long extended
+/// advertisement in several fragments, those are collected until the last one arrives.
+#[derive(Default)]
+pub struct EventParser {
+    fragments: HashMap<(String, u8, u8), Vec<u8>>,
+}
+
+impl EventParser {
+
+    /// Parses an HCI event packet read from a raw HCI socket: packet type, event code,
+    /// parameter length and the parameters.
+    pub fn parse(&mut self, packet: &[u8]) -> Result<Event, DecodeError> {
+        if packet.len() < 3 || packet[0] != HCI_EVENT_PKT {
+            return Ok(Event::Other);
+        }
+        let params = packet.get(3..3 + packet[2] as usize)
+            .ok_or_else(|| DecodeError::new("Truncated HCI event".to_string()))?;
+        match packet[1] {
+            // Number of allowed command packets, opcode and the return parameters that start
+            // with the status.
+            EVT_CMD_COMPLETE => Ok(Event::CommandComplete{
+                opcode: u16_le(params, 1)?,
+                status: byte_at(params, 3)?,
+            }),
+            // Status, number of allowed command packets and opcode.
+            EVT_CMD_STATUS => Ok(Event::CommandStatus{
+                opcode: u16_le(params, 2)?,
+                status: byte_at(params, 0)?,
+            }),
+            EVT_LE_META => {
+                let subevent = byte_at(params, 0)?;
+                match subevent {
+                    LE_ADVERTISING_REPORT => {
+                        Ok(Event::Advertisements(parse_advertising_reports(&params[1..])?))
+                    },
+                    LE_EXTENDED_ADVERTISING_REPORT => {
+                        Ok(Event::Advertisements(self.parse_extended_advertising_reports(&params[1..])?))
+                    },
+                    _ => Ok(Event::Other),
+                }
+            },
+            _ => Ok(Event::Other),
+        }
+    }
+
+}
+
+/// Parses an event without the fragments of the earlier events, e.g. a command response.
+pub fn parse_event(packet: &[u8]) -> Result<Event, DecodeError> {
+    EventParser::default().parse(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+
+    // The flags and the DF5 manufacturer data of CB:B8:33:4C:88:4F.
+    const ADV_DATA: &str = "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F";
+    const ADDRESS: &str = "4F884C33B8CB";
+
+    fn assert_df5(adv: &Advertisement) {
+        assert_eq!(adv.address, "CB:B8:33:4C:88:4F");
+        assert_eq!(adv.mfr_data[&0x0499][0..3], [0x05, 0x12, 0xFC]);
+        assert!(adv.svc_data.is_empty());
+    }
+
+    #[test]
+    fn parses_advertising_report_event() {
+        // LE meta event, advertising report, one ADV_NONCONN_IND from a random address with
+        // RSSI -59.
+        let event = hex::decode(format!("043E2B02010301{}1F{}C5", ADDRESS, ADV_DATA)).unwrap();
+        let advertisements = match parse_event(&event).unwrap() {
+            Event::Advertisements(a) => a,
+            e => panic!("unexpected event {:?}", e),
+        };
+        assert_eq!(advertisements.len(), 1);
+        assert_df5(&advertisements[0]);
+        assert_eq!(advertisements[0].link_info.rssi, Some(-59));
+        assert_eq!(advertisements[0].link_info.address_type.as_deref(), Some("random"));
+
+        assert!(parse_event(&event[..event.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn parses_extended_advertising_report_event() {
+        // Legacy ADV_NONCONN_IND on LE 1M, TX power not available and RSSI -59.
+        let event = hex::decode(format!(
+            "043E390D01100001{}0100FF7FC50000000000000000001F{}", ADDRESS, ADV_DATA)).unwrap();
+        let advertisements = match parse_event(&event).unwrap() {
+            Event::Advertisements(a) => a,
+            e => panic!("unexpected event {:?}", e),
+        };
+        assert_eq!(advertisements.len(), 1);
+        assert_df5(&advertisements[0]);
+        assert_eq!(advertisements[0].link_info.rssi, Some(-59));
+        assert_eq!(advertisements[0].link_info.tx_power, None);
+
+        let params = hex::decode(format!(
+            "01100000{}0100FF04C50000000000000000001F{}", ADDRESS, ADV_DATA)).unwrap();
+        let mut parser = EventParser::default();
+        let advertisements = parser.parse_extended_advertising_reports(&params).unwrap();
+        assert_eq!(advertisements[0].link_info.tx_power, Some(4));
+        assert_eq!(advertisements[0].link_info.address_type.as_deref(), Some("public"));
+        assert!(parser.parse_extended_advertising_reports(&params[..30]).is_err());
+    }
+
+    fn extended_report(event_type: &str, address: &str, data: &str) -> String {
+        format!("{}01{}0100007FC5000000000000000000{:02X}{}", event_type, address, data.len() / 2, data)
+    }
+
+    #[test]
+    fn reassembles_fragmented_extended_advertisements() {
+        // Non-connectable extended advertisements, the first one in two fragments. The
+        // other reports of the events are not affected by the fragments.
+        const OTHER: &str = "C5B8334C88EF";
+        let (head, tail) = ADV_DATA.split_at(20);
+        let mut parser = EventParser::default();
+        let params = hex::decode(format!(
+            "02{}{}", extended_report("2000", ADDRESS, head), extended_report("0000", OTHER, ADV_DATA))).unwrap();
+        let advertisements = parser.parse_extended_advertising_reports(&params).unwrap();
+        assert_eq!(advertisements.len(), 1);
+        assert_eq!(advertisements[0].address, "EF:88:4C:33:B8:C5");
+
+        let params = hex::decode(format!("01{}", extended_report("0000", ADDRESS, tail))).unwrap();
+        let advertisements = parser.parse_extended_advertising_reports(&params).unwrap();
+        assert_eq!(advertisements.len(), 1);
+        assert_df5(&advertisements[0]);
+
+        // The controller gave up on the rest, and invalid data, are dropped alone.
+        let params = hex::decode(format!(
+            "03{}{}{}",
+            extended_report("2000", ADDRESS, head),
+            extended_report("4000", ADDRESS, head),
+            extended_report("0000", OTHER, "05FF9904"))).unwrap();
+        assert!(parser.parse_extended_advertising_reports(&params).unwrap().is_empty());
+        let params = hex::decode(format!("01{}", extended_report("0000", ADDRESS, ADV_DATA))).unwrap();
+        let advertisements = parser.parse_extended_advertising_reports(&params).unwrap();
+        assert_eq!(advertisements.len(), 1);
+        assert_df5(&advertisements[0]);
+    }
+
+    #[test]
+    fn parses_command_events() {
+        // Command complete of LE Set Scan Enable (0x200C) and command status of 0x2041.
+        match parse_event(&hex::decode("040E04010C2000").unwrap()).unwrap() {
+            Event::CommandComplete{opcode, status} => assert_eq!((opcode, status), (0x200C, 0)),
+            e => panic!("unexpected event {:?}", e),
+        }
+        match parse_event(&hex::decode("040F040C014120").unwrap()).unwrap() {
+            Event::CommandStatus{opcode, status} => assert_eq!((opcode, status), (0x2041, 0x0C)),
+            e => panic!("unexpected event {:?}", e),
+        }
+        // Disconnection complete is not parsed, nor are the other packet types.
+        let disconnection = hex::decode("04050400400013").unwrap();
+        assert!(matches!(parse_event(&disconnection).unwrap(), Event::Other));
+    }
+
+    #[test]
+    fn parses_advertising_pdus() {
+        // ADV_NONCONN_IND with TxAdd set, followed by the CRC.
+        let pdu = hex::decode(format!("4225{}{}AABBCC", ADDRESS, ADV_DATA)).unwrap();
+        let adv = parse_advertising_pdu(&pdu).unwrap().unwrap();
+        assert_df5(&adv);
+        assert_eq!(adv.link_info.address_type.as_deref(), Some("random"));
+
+        // SCAN_REQ carries no advertising data.
+        let scan_req = hex::decode(format!("430C{}{}", ADDRESS, ADDRESS)).unwrap();
+        assert!(parse_advertising_pdu(&scan_req).unwrap().is_none());
+        assert!(parse_advertising_pdu(&pdu[..6]).is_err());
+    }
+
+}
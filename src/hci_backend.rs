@@ -0,0 +1,372 @@
+use std::error;
+use std::fs;
+use std::io;
+use std::mem;
+use std::slice;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use adapter_selector::{self, AdapterSelector};
+use backend::Backend;
+use config;
+use consumer::Consumer;
+use device_store::DeviceStore;
+use error::HciError;
+use hci::{self, Event, EventParser};
+use recording::Recorder;
+use timestamp::unix_timestamp;
+
+type BoxErr = Box<dyn error::Error>;
+
+// From the Linux kernel headers include/net/bluetooth/{bluetooth,hci,hci_sock}.h.
+const BTPROTO_HCI: libc::c_int = 1;
+const SOL_HCI: libc::c_int = 0;
+const HCI_FILTER: libc::c_int = 2;
+const HCI_CHANNEL_RAW: u16 = 0;
+// _IOW('H', 201, int)
+const HCIDEVUP: u64 = 0x400448C9;
+
+const OGF_LE_CTL: u16 = 0x08;
+const OCF_LE_SET_SCAN_PARAMETERS: u16 = 0x000B;
+const OCF_LE_SET_SCAN_ENABLE: u16 = 0x000C;
+const OCF_LE_SET_EXT_SCAN_PARAMETERS: u16 = 0x0041;
+const OCF_LE_SET_EXT_SCAN_ENABLE: u16 = 0x0042;
+
+const ACTIVE_SCAN: u8 = 0x01;
+// 10 ms scan interval and window in 0.625 ms units, i.e. scan all the time.
+const SCAN_INTERVAL: u16 = 0x0010;
+const SCAN_WINDOW: u16 = 0x0010;
+const LE_1M_PHY: u8 = 0x01;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+#[repr(C)]
+struct HciFilter {
+    type_mask: u32,
+    event_mask: [u32; 2],
+    opcode: u16,
+}
+
+struct HciSocket {
+    name: String,
+    fd: libc::c_int,
+    parser: EventParser,
+}
+
+impl HciSocket {
+
+    fn open(name: &str, dev_id: u16) -> Result<HciSocket, BoxErr> {
+        let fd = unsafe {
+            libc::socket(libc::AF_BLUETOOTH, libc::SOCK_RAW | libc::SOCK_CLOEXEC, BTPROTO_HCI)
+        };
+        if fd < 0 {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+        let socket = HciSocket{name: name.to_string(), fd, parser: EventParser::default()};
+
+        // The device has to be up for the LE commands, it is fine if it already is.
+        let ret = unsafe { libc::ioctl(fd, HCIDEVUP as libc::Ioctl, dev_id as libc::c_int) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EALREADY) {
+                return Err(Box::new(err));
+            }
+        }
+
+        let addr = SockaddrHci{
+            hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            hci_dev: dev_id,
+            hci_channel: HCI_CHANNEL_RAW,
+        };
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const SockaddrHci as *const libc::sockaddr,
+                mem::size_of::<SockaddrHci>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+
+        let mut filter = HciFilter{type_mask: 1 << hci::HCI_EVENT_PKT, event_mask: [0, 0], opcode: 0};
+        for event in &[hci::EVT_CMD_COMPLETE, hci::EVT_CMD_STATUS, hci::EVT_LE_META] {
+            filter.event_mask[(*event >> 5) as usize] |= 1 << (*event & 0x1F);
+        }
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                SOL_HCI,
+                HCI_FILTER,
+                &filter as *const HciFilter as *const libc::c_void,
+                mem::size_of::<HciFilter>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+
+        Ok(socket)
+    }
+
+    fn write(&self, packet: &[u8]) -> Result<(), BoxErr> {
+        let ret = unsafe {
+            libc::write(self.fd, packet.as_ptr() as *const libc::c_void, packet.len())
+        };
+        if ret < 0 {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Waits until one of the sockets is readable and returns its index, or None if none of
+    /// them is readable within the timeout. A socket whose adapter went away, e.g. was
+    /// unplugged, is an error.
+    fn wait_readable(sockets: &[HciSocket], timeout: Duration) -> Result<Option<usize>, BoxErr> {
+        let mut pollfds: Vec<libc::pollfd> = sockets
+            .iter()
+            .map(|s| libc::pollfd{fd: s.fd, events: libc::POLLIN, revents: 0})
+            .collect();
+        let ret = unsafe {
+            libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout.as_millis() as libc::c_int)
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            return Err(Box::new(err));
+        }
+        let failed = pollfds.iter()
+            .position(|p| p.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0);
+        if let Some(i) = failed {
+            return Err(Box::new(HciError::new(
+                format!("{} cannot be read, the adapter is probably gone", sockets[i].name))));
+        }
+        Ok(pollfds.iter().position(|p| p.revents & libc::POLLIN != 0))
+    }
+
+    fn read(&self) -> Result<Vec<u8>, BoxErr> {
+        let mut buf = [0u8; 1024];
+        let len = unsafe {
+            libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+        };
+        if len < 0 {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+        Ok(buf[..len as usize].to_vec())
+    }
+
+    /// Sends a command and returns the status from the Command Complete or Command Status
+    /// event of the command.
+    fn send_command(&self, ocf: u16, params: &[u8]) -> Result<u8, BoxErr> {
+        let opcode = hci::opcode(OGF_LE_CTL, ocf);
+        self.write(&hci::command_packet(opcode, params))?;
+        let start = Instant::now();
+        while start.elapsed() < COMMAND_TIMEOUT {
+            let timeout = COMMAND_TIMEOUT - start.elapsed();
+            if HciSocket::wait_readable(slice::from_ref(self), timeout)?.is_none() {
+                continue;
+            }
+            match hci::parse_event(&self.read()?) {
+                Ok(Event::CommandComplete{opcode: op, status}) if op == opcode => return Ok(status),
+                Ok(Event::CommandStatus{opcode: op, status}) if op == opcode => return Ok(status),
+                _ => continue,
+            }
+        }
+        Err(Box::new(HciError::new(
+            format!("No response to HCI command 0x{:04X} from {}", opcode, self.name))))
+    }
+
+    fn check_status(&self, ocf: u16, status: u8) -> Result<(), BoxErr> {
+        match status {
+            0 => Ok(()),
+            _ => Err(Box::new(HciError::new(
+                format!("HCI command 0x{:04X} failed on {} with status 0x{:02X}",
+                        hci::opcode(OGF_LE_CTL, ocf), self.name, status)))),
+        }
+    }
+
+    // The extended scan reports the extended advertisements too, but it is not supported by
+    // older controllers. A controller also refuses to mix the legacy and the extended
+    // commands, e.g. if bluetoothd has used the legacy ones.
+    fn start_extended_scan(&self) -> Result<(), BoxErr> {
+        let mut params = vec![0x00, 0x00, LE_1M_PHY, ACTIVE_SCAN];
+        params.extend_from_slice(&SCAN_INTERVAL.to_le_bytes());
+        params.extend_from_slice(&SCAN_WINDOW.to_le_bytes());
+        self.check_status(
+            OCF_LE_SET_EXT_SCAN_PARAMETERS,
+            self.send_command(OCF_LE_SET_EXT_SCAN_PARAMETERS, &params)?,
+        )?;
+        // Enable, no duplicate filtering, scan until disabled.
+        self.check_status(
+            OCF_LE_SET_EXT_SCAN_ENABLE,
+            self.send_command(OCF_LE_SET_EXT_SCAN_ENABLE, &[0x01, 0x00, 0x00, 0x00, 0x00, 0x00])?,
+        )
+    }
+
+    fn start_legacy_scan(&self) -> Result<(), BoxErr> {
+        let mut params = vec![ACTIVE_SCAN];
+        params.extend_from_slice(&SCAN_INTERVAL.to_le_bytes());
+        params.extend_from_slice(&SCAN_WINDOW.to_le_bytes());
+        params.extend_from_slice(&[0x00, 0x00]);
+        self.check_status(
+            OCF_LE_SET_SCAN_PARAMETERS,
+            self.send_command(OCF_LE_SET_SCAN_PARAMETERS, &params)?,
+        )?;
+        // Enable, no duplicate filtering.
+        self.check_status(
+            OCF_LE_SET_SCAN_ENABLE,
+            self.send_command(OCF_LE_SET_SCAN_ENABLE, &[0x01, 0x00])?,
+        )
+    }
+
+    fn start_scan(&self) -> Result<(), BoxErr> {
+        // Scanning may have been left on, e.g. by bluetoothd. The results are ignored because
+        // disabling fails if scanning is not on.
+        self.send_command(OCF_LE_SET_EXT_SCAN_ENABLE, &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00])?;
+        self.send_command(OCF_LE_SET_SCAN_ENABLE, &[0x00, 0x00])?;
+        match self.start_extended_scan() {
+            Ok(()) => info!("Extended LE scan started on {}", self.name),
+            Err(e) => {
+                debug!("Extended LE scan not available on {}: {}", self.name, e);
+                self.start_legacy_scan()?;
+                info!("LE scan started on {}", self.name);
+            },
+        }
+        Ok(())
+    }
+
+}
+
+impl Drop for HciSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+/// Reads the advertisements straight from the controllers through raw HCI sockets. BlueZ is
+/// not needed, and every advertisement is seen, not only the changed ones.
+pub struct HciBackend {
+    selectors: Vec<AdapterSelector>,
+    sockets: Vec<HciSocket>,
+    store: DeviceStore,
+}
+
+impl HciBackend {
+
+    pub fn new(conf: config::SensorConf, bt_devnames: &[String]) -> Result<HciBackend, BoxErr> {
+        let selectors = adapter_selector::parse_selectors(bt_devnames);
+        if let Some(s) = selectors.iter().find(|s| matches!(s, AdapterSelector::Address(_))) {
+            return Err(Box::new(HciError::new(
+                format!("Adapter address {} is not supported by the hci backend", s))));
+        }
+        Ok(HciBackend{
+            selectors,
+            sockets: Vec::new(),
+            store: DeviceStore::new(conf),
+        })
+    }
+
+    /// The names and the device ids of the selected adapters the kernel has.
+    fn find_adapters(&self) -> Result<Vec<(String, u16)>, BoxErr> {
+        let mut adapters = Vec::new();
+        for entry in fs::read_dir("/sys/class/bluetooth")? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let dev_id = match name.trim_start_matches("hci").parse::<u16>() {
+                Ok(id) if name.starts_with("hci") => id,
+                _ => continue,
+            };
+            if adapter_selector::matches_any(&self.selectors, &name, None) {
+                adapters.push((name, dev_id));
+            }
+        }
+        adapters.sort();
+        Ok(adapters)
+    }
+
+    /// Reads advertisements until the wait time is over and gives the updated sensors to the
+    /// consumer if one is given.
+    fn read_advertisements(
+        &mut self,
+        mut consumer: Option<&mut dyn Consumer>,
+        wait: Duration,
+        ) -> Result<(), BoxErr>
+    {
+        let start = Instant::now();
+        while start.elapsed() < wait {
+            let index = match HciSocket::wait_readable(&self.sockets, wait.saturating_sub(start.elapsed()))? {
+                Some(i) => i,
+                None => continue,
+            };
+            let socket = &mut self.sockets[index];
+            let packet = socket.read()?;
+            let meas_timestamp = unix_timestamp();
+            let advertisements = match socket.parser.parse(&packet) {
+                Ok(Event::Advertisements(a)) => a,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("Cannot parse HCI event from {}: {}", socket.name, e);
+                    continue;
+                },
+            };
+            for mut adv in advertisements {
+                adv.link_info.adapter = Some(socket.name.to_string());
+                let address = adv.address.clone();
+                let is_new = self.store.add_advertisement(adv, meas_timestamp);
+                if let (true, Some(c)) = (is_new, consumer.as_mut()) {
+                    self.store.consume_device(&address, &mut **c);
+                }
+            }
+        }
+        Ok(())
+    }
+
+}
+
+impl Backend for HciBackend {
+
+    fn initialize(&mut self) -> Result<(), BoxErr> {
+        // The adapters are looked up again on every restart, because an adapter that was
+        // plugged in again may have got another index.
+        self.sockets.clear();
+        let adapters = self.find_adapters()?;
+        if adapters.is_empty() {
+            let selectors: Vec<String> = self.selectors.iter().map(|s| s.to_string()).collect();
+            return Err(Box::new(HciError::new(
+                format!("No bluetooth adapter {} found", selectors.join(",")))));
+        }
+        for (name, dev_id) in adapters {
+            let socket = HciSocket::open(&name, dev_id)?;
+            socket.start_scan()?;
+            self.sockets.push(socket);
+        }
+        Ok(())
+    }
+
+    fn consume(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        self.read_advertisements(Some(&mut *consumer), wait)?;
+        consumer.flush();
+        self.store.evict_unseen();
+        Ok(())
+    }
+
+    fn list(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        self.read_advertisements(None, wait)?;
+        self.store.consume_all(consumer);
+        consumer.flush();
+        Ok(())
+    }
+
+    fn set_recorder(&mut self, recorder: Recorder) {
+        self.store.set_recorder(recorder);
+    }
+
+}
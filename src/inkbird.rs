@@ -0,0 +1,285 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Value};
+use bt_device::BTDevice;
+use discovery_mode::DiscoveryMode;
+
+pub struct InkbirdConstructor;
+
+impl InkbirdConstructor {
+    pub fn new() -> Box<InkbirdConstructor> {
+        Box::new(InkbirdConstructor{})
+    }
+}
+
+impl BTSensorConstructor for InkbirdConstructor {
+    fn get_name(&self) -> &'static str {
+        "Inkbird"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(Inkbird::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        Inkbird::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct Inkbird {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for Inkbird {
+
+    fn is_valid_data(&self) -> bool {
+        Inkbird::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements()
+            .map(|m| {
+                format!(
+                    "battery {}%\ttype {}\ntemp {}°C\thumidity {}%\texternal probe {}",
+                    m.battery_percent,
+                    m.sensor_type,
+                    m.temperature,
+                    m.humidity,
+                    m.external_probe,
+                )
+            })
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        match self._get_measurements() {
+            Some(m) => {
+                let mut map = HashMap::<String, Value>::new();
+                map.insert(
+                    "temperature".to_string(),
+                    Value::Float(m.temperature),
+                );
+                map.insert(
+                    "humidity".to_string(),
+                    Value::Integer(m.humidity.round() as i64),
+                );
+                map.insert(
+                    "humidity_float".to_string(),
+                    Value::Float(m.humidity),
+                );
+                map.insert(
+                    "battery_percent".to_string(),
+                    Value::Integer(m.battery_percent as i64),
+                );
+                map.insert(
+                    "external_probe".to_string(),
+                    Value::Boolean(m.external_probe),
+                );
+                Some(map)
+            },
+            None => None,
+        }
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+// The company id field is not a fixed id, it carries the temperature.
+const DATA_LEN: usize = 7;
+// The local names of IBS-TH and IBS-TH2.
+const LOCAL_NAMES: &[&str] = &["sps", "tps"];
+
+impl Inkbird {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> Inkbird {
+        Inkbird{bt_device}
+    }
+
+    /// Because the company id changes with the temperature, the data is recognized by its
+    /// length and checksum. A checksum of two bytes matches other data too often, so the
+    /// device must also have an Inkbird local name or be configured as Inkbird.
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        let is_inkbird = match device.get_discovery_mode() {
+            DiscoveryMode::Configured(ref sensor_if) => sensor_if == "Inkbird",
+            DiscoveryMode::Auto => device.get_link_info().name.as_ref()
+                .is_some_and(|n| LOCAL_NAMES.contains(&n.as_str())),
+        };
+        is_inkbird && Inkbird::latest_data(device).is_some()
+    }
+
+    /// The manufacturer data of the latest advertisement. BlueZ keeps an entry for every
+    /// temperature it has heard, so if several entries are valid the one that changed last
+    /// is used, and if that is not known the data is ambiguous.
+    fn latest_data(device: &BTDevice) -> Option<InkbirdMeas> {
+        let mfr_data = device.get_mfr_data()?;
+        let mut valid = mfr_data
+            .iter()
+            .filter_map(|(id, data)| Inkbird::decode(*id, data));
+        match (valid.next(), valid.next()) {
+            (Some(meas), None) => Some(meas),
+            (Some(_), Some(_)) => {
+                let id = device.get_latest_mfr_id()?;
+                Inkbird::decode(id, mfr_data.get(&id)?)
+            },
+            _ => None,
+        }
+    }
+
+    /// CRC-16/MODBUS: reflected polynomial 0xA001 and initial value 0xFFFF.
+    pub fn crc16_modbus(data: &[u8]) -> u16 {
+        let mut crc: u16 = 0xFFFF;
+        for byte in data {
+            crc ^= *byte as u16;
+            for _ in 0..8 {
+                crc = if crc & 0x0001 != 0 {
+                    (crc >> 1) ^ 0xA001
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        crc
+    }
+
+    // IBS-TH and IBS-TH2. All values are little endian, the first two bytes are sent in the
+    // company id field.
+    //
+    // 0-1     temperature, i16, 0.01 °C
+    // 2-3     humidity, u16, 0.01 %
+    // 4       1 if the external probe is connected
+    // 5-6     CRC-16/MODBUS of bytes 0-4
+    // 7       battery, %
+    // 8       sensor type
+    pub fn decode(id: u16, data: &[u8]) -> Option<InkbirdMeas> {
+        if data.len() != DATA_LEN {
+            return None;
+        }
+        let mut bytes = vec![(id & 0xFF) as u8, (id >> 8) as u8];
+        bytes.extend_from_slice(data);
+        let crc = ((bytes[6] as u16) << 8) | bytes[5] as u16;
+        if Inkbird::crc16_modbus(&bytes[0..5]) != crc {
+            return None;
+        }
+        let temperature = id as i16;
+        let humidity = ((bytes[3] as u16) << 8) | bytes[2] as u16;
+        Some(InkbirdMeas{
+            temperature: temperature as f64 / 100.0,
+            humidity: humidity as f64 / 100.0,
+            external_probe: bytes[4] == 1,
+            battery_percent: bytes[7],
+            sensor_type: bytes[8],
+            ..Default::default()
+        })
+    }
+
+    fn _get_measurements(&self) -> Option<InkbirdMeas> {
+        let device = self.get_bt_device();
+        let mut meas = Inkbird::latest_data(&device)?;
+        meas.address = device.get_address().to_string();
+        meas.tag = device.get_tag().to_string();
+        Some(meas)
+    }
+
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct InkbirdMeas {
+    temperature: f64,
+    humidity: f64,
+    external_probe: bool,
+    battery_percent: u8,
+    sensor_type: u8,
+    address: String,
+    tag: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use bt_device::LinkInfo;
+
+    #[test]
+    fn crc16_modbus_check_value() {
+        assert_eq!(Inkbird::crc16_modbus(b"123456789"), 0x4B37);
+    }
+
+    #[test]
+    fn decodes_ibs_th2() {
+        let meas = Inkbird::decode(0x0929, &[0xD7, 0x11, 0x00, 0xC6, 0x32, 0x57, 0x08]).unwrap();
+        assert_eq!(meas.temperature, 23.45);
+        assert_eq!(meas.humidity, 45.67);
+        assert!(!meas.external_probe);
+        assert_eq!(meas.battery_percent, 87);
+        assert_eq!(meas.sensor_type, 8);
+    }
+
+    #[test]
+    fn decodes_external_probe_below_zero() {
+        let meas = Inkbird::decode(0xFE00, &[0x00, 0x00, 0x01, 0xD4, 0x28, 0x40, 0x08]).unwrap();
+        assert_eq!(meas.temperature, -5.12);
+        assert!(meas.external_probe);
+        assert_eq!(meas.battery_percent, 64);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        assert!(Inkbird::decode(0x0929, &[0xD7, 0x11, 0x00, 0xC6, 0x33, 0x57, 0x08]).is_none());
+        assert!(Inkbird::decode(0x0929, &[0xD7, 0x11, 0x00, 0xC6, 0x32, 0x57]).is_none());
+    }
+
+    fn inkbird_device(mfr_data: HashMap<u16, Vec<u8>>, name: Option<&str>, mode: DiscoveryMode) -> BTDevice {
+        let mut device = BTDevice::new(
+            String::new(), String::new(),
+            Some(mfr_data), None, 0, Duration::from_secs(0), mode);
+        device.update_link_info(LinkInfo{name: name.map(|n| n.to_string()), ..Default::default()});
+        device
+    }
+
+    #[test]
+    fn requires_local_name_or_configuration() {
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(0x0929, vec![0xD7, 0x11, 0x00, 0xC6, 0x32, 0x57, 0x08]);
+        assert!(Inkbird::_is_valid_data(&inkbird_device(mfr_data.clone(), Some("sps"), DiscoveryMode::Auto)));
+        assert!(!Inkbird::_is_valid_data(&inkbird_device(mfr_data.clone(), None, DiscoveryMode::Auto)));
+        let configured = DiscoveryMode::Configured("Inkbird".to_string());
+        assert!(Inkbird::_is_valid_data(&inkbird_device(mfr_data, None, configured)));
+    }
+
+    #[test]
+    fn uses_latest_of_several_entries() {
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(0x0929, vec![0xD7, 0x11, 0x00, 0xC6, 0x32, 0x57, 0x08]);
+        let mut device = inkbird_device(mfr_data.clone(), Some("tps"), DiscoveryMode::Auto);
+        // BlueZ keeps the entry of the earlier temperature.
+        mfr_data.insert(0xFE00, vec![0x00, 0x00, 0x01, 0xD4, 0x28, 0x40, 0x08]);
+        device.set_mfr_data(Some(mfr_data.clone()));
+        assert_eq!(Inkbird::latest_data(&device).unwrap().temperature, -5.12);
+
+        let ambiguous = inkbird_device(mfr_data, Some("tps"), DiscoveryMode::Auto);
+        assert!(Inkbird::latest_data(&ambiguous).is_none());
+    }
+
+}
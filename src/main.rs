@@ -5,56 +5,185 @@ extern crate dbus;
 extern crate serde_json;
 extern crate docopt;
 extern crate base64;
+extern crate aes;
+extern crate hex;
+extern crate ccm;
 extern crate influx_db_client;
+extern crate libc;
+extern crate tiny_http;
+extern crate rumqttc;
 
 mod bt_sensor_factory;
 mod discovery_mode;
 mod ruuvitag_df3;
+mod ruuvitag_df5;
+mod ruuvitag_df6;
+mod ruuvitag_df8;
+mod ruuvitag_dfe1;
+mod bthome;
+mod xiaomi_atc;
+mod mibeacon;
+mod govee;
+mod inkbird;
+mod switchbot;
+mod eddystone;
+mod eddystone_tlm;
+mod beacon;
 mod ruuvitag_df2;
+mod ruuvitag_df4;
 mod dbus_bluez;
+mod adv_monitor;
+mod hci;
+mod hci_backend;
+mod backend;
+mod device_store;
+mod supervisor;
+mod recording;
+mod replay;
+mod capture_file;
+mod gateway;
+mod mqtt;
+mod mqtt_backend;
+mod decode;
+mod simulator;
+mod adapter_selector;
 mod bt_device;
 mod bt_sensor;
 mod consumer;
 mod config;
 mod error;
+mod crypto;
+mod frame_counters;
+mod timestamp;
 
-use std::{thread, time};
+use std::time;
 
 use docopt::Docopt;
 
-const USAGE: &'static str = "
+use backend::Backend;
+
+const USAGE: &str = "
 Bluetooth Sensor Collector.
 
 Usage:
   bt-sensor (-h | --help)
   bt-sensor --version
   bt-sensor [options]
+  bt-sensor decode [options] [<payload>...]
   bt-sensor [options] <device>...
 
 Options:
   -h --help                  Show this screen.
   --version                  Show version.
   --devicemap=<conf>         Device address to device type map file.
-  --btdevice=<device>        Bluetooth device name [default: hci0].
+  --btdevice=<device>        Bluetooth adapter name, MAC address or name
+                             pattern like hci*. Comma separated to scan with
+                             several adapters [default: hci0].
+  --adapter-policy=<policy>  Which adapter the readings of a device heard by
+                             several adapters are taken from, any or best
+                             [default: any].
   --manual                   Only search sensors that are configured.
   --interval=<secs>          BT device Poll interval [default: 3].
   --consumer=<type>          The consumer type [default: stdout].
+  --capture=<mode>           How advertisements are read from BlueZ, poll,
+                             signal or monitor [default: poll]. Monitor
+                             does not report Inkbird or AltBeacon sensors.
+  --rssi=<dbm>               Only report devices heard with at least this
+                             RSSI, e.g. --rssi=-80. Not with --pathloss.
+  --pathloss=<db>            Only report devices with at most this pathloss.
+  --duplicate-data=<bool>    Whether BlueZ reports every advertisement, also
+                             the ones with the same data, true or false.
+                             BlueZ reports them by default.
+  --pattern=<prefix>         Only report devices whose address or name starts
+                             with the prefix. BlueZ matches only the address
+                             or the name, not the manufacturer data.
+  --backend=<type>           Capture backend, bluez, hci, gateway, mqtt or
+                             simulator [default: bluez].
+  --listen=<addr>            Address the gateway backend receives the Ruuvi
+                             Gateway requests on [default: 0.0.0.0:8080].
+  --gateway-token=<token>    Bearer token the gateway requests must have,
+                             read from GATEWAY_TOKEN if not given.
+  --broker=<addr>            MQTT broker of the mqtt backend, host:port or
+                             mqtts://host:port for TLS. The user name and the
+                             password are read from MQTT_USER and
+                             MQTT_PASSWORD [default: localhost:1883].
+  --topics=<subscriptions>   Comma separated MQTT topic filters with the
+                             payload format, ruuvi, theengs or esphome
+                             [default: ruuvi:ruuvi/#].
+  --sim-tags=<n>             Number of tags of the simulator backend
+                             [default: 10].
+  --sim-formats=<formats>    Comma separated formats of the simulated tags,
+                             df3, df5 or bthome [default: df5,df3,bthome].
+  --sim-interval=<ms>        Advertising interval of the simulated tags
+                             [default: 1000].
+  --sim-seed=<seed>          Seed of the simulated tags and readings
+                             [default: 1].
+  --forget-after=<secs>      Forget the devices that have not been seen for
+                             this long and remove them from the BlueZ cache
+                             [default: 3600].
+  --frame-counters=<file>   Keep the frame counters of the encrypted sensors
+                             in the file over restarts. A sensor that starts
+                             counting from zero again is accepted after its
+                             entry is removed from the file.
+  --record=<file>            Append every advertisement to the file as JSON
+                             lines.
+  --replay=<file>            Read the advertisements from a recording, or a
+                             btsnoop or pcap capture, instead of Bluetooth.
+  --speed=<factor>           Replay speed, 2 is twice as fast as recorded and
+                             0 as fast as possible [default: 1].
   --list                     List all sensors and exit.
+  --mfr=<id>                 Decode the payloads as the manufacturer data of
+                             the hex id, e.g. 0499.
+  --svc=<uuid>               Decode the payloads as the service data of the
+                             UUID, e.g. fcd2. Without --mfr and --svc the
+                             payloads are the whole advertising data.
+  --address=<mac>            Device address for the decoders, e.g. for the
+                             key of an encrypted sensor in the devicemap
+                             [default: 00:00:00:00:00:00].
+  --json                     Print the decoded values as JSON.
   <device>                   Device address map (MAC,tag,type)
+  <payload>                  Hex payload to decode, read from stdin lines if
+                             none are given.
 ";
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Args {
     flag_devicemap: Option<String>,
     flag_btdevice: String,
+    flag_adapter_policy: device_store::AdapterPolicy,
     flag_manual: bool,
     flag_interval: u64,
+    flag_forget_after: u64,
+    flag_frame_counters: Option<String>,
     flag_consumer: consumer::ConsumerType,
+    flag_capture: dbus_bluez::CaptureMode,
+    flag_rssi: Option<i16>,
+    flag_pathloss: Option<u16>,
+    flag_duplicate_data: Option<String>,
+    flag_pattern: Option<String>,
+    flag_backend: backend::BackendType,
+    flag_listen: String,
+    flag_gateway_token: Option<String>,
+    flag_broker: String,
+    flag_topics: String,
+    flag_sim_tags: usize,
+    flag_sim_formats: String,
+    flag_sim_interval: u64,
+    flag_sim_seed: u64,
+    flag_record: Option<String>,
+    flag_replay: Option<String>,
+    flag_speed: f64,
     flag_list: bool,
+    flag_mfr: Option<String>,
+    flag_svc: Option<String>,
+    flag_address: String,
+    flag_json: bool,
+    cmd_decode: bool,
     arg_device: Vec<String>,
+    arg_payload: Vec<String>,
 }
 
-fn run<'a>() -> Result<(), Box<dyn std::error::Error>> {
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let args: Args = Docopt::new(USAGE)
         .and_then(|docopt| {
             docopt
@@ -63,18 +192,60 @@ fn run<'a>() -> Result<(), Box<dyn std::error::Error>> {
         })
         .unwrap_or_else(|e| e.exit());
     let conf = config::SensorConf::new(&args);
-    let mut dbus = dbus_bluez::DbusBluez::new(conf, args.flag_btdevice.to_string())?;
+    if args.cmd_decode {
+        let source = decode::PayloadSource::new(args.flag_mfr.as_deref(), args.flag_svc.as_deref())?;
+        return decode::decode(conf, source, &args.flag_address, &args.arg_payload, args.flag_json);
+    }
+    let bt_devnames: Vec<String> = args.flag_btdevice
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .collect();
+    let mut backend: Box<dyn Backend> = if let Some(ref path) = args.flag_replay {
+        Box::new(replay::ReplayBackend::new(conf, path, args.flag_speed)?)
+    } else {
+        match args.flag_backend {
+            backend::BackendType::Bluez => {
+                let discovery_filter = dbus_bluez::DiscoveryFilter::new(
+                    args.flag_rssi,
+                    args.flag_pathloss,
+                    args.flag_duplicate_data.as_deref(),
+                    args.flag_pattern.clone(),
+                )?;
+                Box::new(dbus_bluez::DbusBluez::new(
+                    conf, &bt_devnames, args.flag_capture, discovery_filter)?)
+            },
+            backend::BackendType::Hci => Box::new(
+                hci_backend::HciBackend::new(conf, &bt_devnames)?),
+            backend::BackendType::Gateway => Box::new(
+                gateway::GatewayBackend::new(
+                conf, &args.flag_listen, args.flag_gateway_token.as_deref())),
+            backend::BackendType::Mqtt => Box::new(mqtt_backend::MqttBackend::new(
+                conf, &args.flag_broker, mqtt_backend::parse_subscriptions(&args.flag_topics)?)),
+            backend::BackendType::Simulator => Box::new(simulator::SimulatorBackend::new(
+                conf,
+                args.flag_sim_tags,
+                simulator::parse_formats(&args.flag_sim_formats)?,
+                time::Duration::from_millis(args.flag_sim_interval),
+                args.flag_sim_seed,
+            )?),
+        }
+    };
+    if let Some(ref path) = args.flag_record {
+        backend.set_recorder(recording::Recorder::new(path)?);
+    }
     let duration = time::Duration::from_secs(args.flag_interval);
-    dbus.initialize()?;
     if !args.flag_list {
         let mut consumer = consumer::initialize_consumer(&args.flag_consumer)?;
-        loop {
-            dbus.consume(&mut *consumer)?;
-            thread::sleep(duration);
+        let mut supervisor = supervisor::Supervisor::new(backend);
+        supervisor.initialize()?;
+        while !supervisor.is_finished() {
+            supervisor.consume(&mut *consumer, duration)?;
         }
+        Ok(())
     } else {
+        backend.initialize()?;
         let mut consumer = consumer::initialize_consumer(&consumer::ConsumerType::StdOut)?;
-        dbus.consume(&mut *consumer)?;
+        backend.list(&mut *consumer, duration)?;
         Ok(())
     }
 }
@@ -0,0 +1,477 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Value};
+use bt_device::BTDevice;
+use bthome::BTHome;
+use error::DecodeError;
+use crypto;
+use frame_counters::FrameCheck;
+
+pub struct MiBeaconConstructor;
+
+impl MiBeaconConstructor {
+    pub fn new() -> Box<MiBeaconConstructor> {
+        Box::new(MiBeaconConstructor{})
+    }
+}
+
+impl BTSensorConstructor for MiBeaconConstructor {
+    fn get_name(&self) -> &'static str {
+        "MiBeacon"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(MiBeacon::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        MiBeacon::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct MiBeacon {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for MiBeacon {
+
+    fn is_valid_data(&self) -> bool {
+        MiBeacon::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        let values = self._get_measurements()?;
+        let mut map = serde_json::Map::new();
+        for (key, val) in values {
+            map.insert(key, serde_json::to_value(val).ok()?);
+        }
+        map.insert("address".to_string(), serde_json::Value::String(self.get_address()));
+        map.insert("tag".to_string(), serde_json::Value::String(self.get_tag()));
+        serde_json::to_string(&map).ok()
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        let values = self._get_measurements()?;
+        let lines: Vec<String> = values
+            .iter()
+            .map(|(key, val)| format!("{} {}", key, MiBeacon::value_str(val)))
+            .collect();
+        Some(lines.join("\n"))
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        self._get_measurements().map(|values| values.into_iter().collect())
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+}
+
+pub static SVC_DATA_UUID: &str = "0000fe95-0000-1000-8000-00805f9b34fb";
+const HEADER_LEN: usize = 5;
+const MAC_LEN: usize = 6;
+const EXT_COUNTER_LEN: usize = 3;
+const AAD: [u8; 1] = [0x11];
+
+const ENCRYPTED_FLAG: u16 = 0x0008;
+const MAC_INCLUDED_FLAG: u16 = 0x0010;
+const CAPABILITY_INCLUDED_FLAG: u16 = 0x0020;
+const OBJECT_INCLUDED_FLAG: u16 = 0x0040;
+// Set in the capability byte when two bytes of I/O capability follow it.
+const IO_CAPABILITY_FLAG: u8 = 0x20;
+
+/// The fixed part of a MiBeacon frame and the offset where the objects start.
+#[derive(Debug)]
+pub struct MiBeaconHeader {
+    pub frame_control: u16,
+    pub product_id: u16,
+    pub frame_counter: u8,
+    pub mac: Option<Vec<u8>>,
+    pub payload_start: usize,
+}
+
+impl MiBeaconHeader {
+
+    pub fn get_version(&self) -> u8 {
+        (self.frame_control >> 12) as u8
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.frame_control & ENCRYPTED_FLAG != 0
+    }
+
+    pub fn has_objects(&self) -> bool {
+        self.frame_control & OBJECT_INCLUDED_FLAG != 0
+    }
+}
+
+impl MiBeacon {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> MiBeacon {
+        MiBeacon{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        device.get_svc_data()
+            .and_then(|m| m.get(SVC_DATA_UUID))
+            .is_some_and(|data| MiBeacon::parse_header(data).is_ok())
+    }
+
+    fn value_str(val: &Value) -> String {
+        match *val {
+            Value::String(ref s) => s.to_string(),
+            Value::Integer(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Boolean(b) => b.to_string(),
+        }
+    }
+
+    // See https://iot.mi.com/new/doc/accesses/direct-access/embedded-development/ble/ble-mibeacon
+    // for the specification.
+    //
+    // 0-1     frame control, little endian
+    // 2-3     product id, little endian
+    // 4       frame counter
+    // 5-10    MAC address, least significant byte first, if frame control bit 4 is set
+    // next    capability byte, if frame control bit 5 is set, followed by two bytes of I/O
+    //         capability if bit 5 of the capability byte is set
+    // rest    objects, if frame control bit 6 is set
+    pub fn parse_header(data: &[u8]) -> Result<MiBeaconHeader, DecodeError> {
+        if data.len() < HEADER_LEN {
+            return Err(DecodeError::new("MiBeacon frame too short".to_string()));
+        }
+        let frame_control = BTHome::read_int(&data[0..2], false) as u16;
+        let product_id = BTHome::read_int(&data[2..4], false) as u16;
+        let mut i = HEADER_LEN;
+        let mac = if frame_control & MAC_INCLUDED_FLAG != 0 {
+            let mac = data.get(i..i + MAC_LEN)
+                .ok_or_else(|| DecodeError::new("MiBeacon frame truncated in MAC address".to_string()))?;
+            i += MAC_LEN;
+            Some(mac.to_vec())
+        } else {
+            None
+        };
+        if frame_control & CAPABILITY_INCLUDED_FLAG != 0 {
+            let capability = *data.get(i)
+                .ok_or_else(|| DecodeError::new("MiBeacon frame truncated in capability".to_string()))?;
+            i += 1;
+            if capability & IO_CAPABILITY_FLAG != 0 {
+                i += 2;
+            }
+        }
+        if i > data.len() {
+            return Err(DecodeError::new("MiBeacon frame truncated in capability".to_string()));
+        }
+        Ok(MiBeaconHeader{
+            frame_control,
+            product_id,
+            frame_counter: data[4],
+            mac,
+            payload_start: i,
+        })
+    }
+
+    fn door_state(raw: u8) -> &'static str {
+        match raw {
+            0x00 => "open",
+            0x01 => "closed",
+            0x02 => "not_closed_timeout",
+            0x03 => "reset",
+            _ => "unknown",
+        }
+    }
+
+    /// Values of one object, or None for the object ids that are not decoded. Names and
+    /// units match the other decoders, e.g. the battery level is battery_percent.
+    pub fn object_values(id: u16, data: &[u8]) -> Option<Vec<(&'static str, Value)>> {
+        let int_at = |start: usize, len: usize, signed: bool| {
+            data.get(start..start + len).map(|d| BTHome::read_int(d, signed))
+        };
+        let humidity = |raw: i64| {
+            let humidity = BTHome::scale(raw, 0.1);
+            vec![
+                ("humidity", Value::Integer(humidity.round() as i64)),
+                ("humidity_float", Value::Float(humidity)),
+            ]
+        };
+        let values = match id {
+            0x1004 => vec![
+                ("temperature", Value::Float(BTHome::scale(int_at(0, 2, true)?, 0.1))),
+            ],
+            0x1006 => humidity(int_at(0, 2, false)?),
+            0x100D => {
+                let mut values = vec![
+                    ("temperature", Value::Float(BTHome::scale(int_at(0, 2, true)?, 0.1))),
+                ];
+                values.extend(humidity(int_at(2, 2, false)?));
+                values
+            },
+            0x100A => vec![("battery_percent", Value::Integer(int_at(0, 1, false)?))],
+            0x1007 => vec![("illuminance", Value::Integer(int_at(0, 3, false)?))],
+            0x1008 => vec![("moisture", Value::Integer(int_at(0, 1, false)?))],
+            0x1009 => vec![("conductivity", Value::Integer(int_at(0, 2, false)?))],
+            0x1010 => vec![("formaldehyde", Value::Float(BTHome::scale(int_at(0, 2, false)?, 0.01)))],
+            0x1014 => vec![("moisture_detected", Value::Boolean(int_at(0, 1, false)? != 0))],
+            0x1015 => vec![("smoke", Value::Boolean(int_at(0, 1, false)? != 0))],
+            0x1017 => vec![("no_motion_time", Value::Integer(int_at(0, 4, false)?))],
+            0x1018 => vec![("light", Value::Boolean(int_at(0, 1, false)? != 0))],
+            0x1019 => vec![
+                ("door", Value::String(MiBeacon::door_state(*data.first()?).to_string())),
+            ],
+            0x000F => vec![
+                ("motion", Value::Boolean(true)),
+                ("illuminance", Value::Integer(int_at(0, 3, false)?)),
+            ],
+            _ => return None,
+        };
+        Some(values)
+    }
+
+    /// Parses the (decrypted) objects: two byte object id, one byte length and the data.
+    /// Objects that are not decoded are skipped.
+    pub fn parse_objects(payload: &[u8]) -> Result<Vec<(String, Value)>, DecodeError> {
+        let mut values = Vec::new();
+        let mut i = 0;
+        while i < payload.len() {
+            if i + 3 > payload.len() {
+                return Err(DecodeError::new("Truncated MiBeacon object header".to_string()));
+            }
+            let id = BTHome::read_int(&payload[i..i + 2], false) as u16;
+            let len = payload[i + 2] as usize;
+            i += 3;
+            if i + len > payload.len() {
+                return Err(DecodeError::new(format!("Truncated MiBeacon object 0x{:04X}", id)));
+            }
+            match MiBeacon::object_values(id, &payload[i..i + len]) {
+                Some(object_values) => {
+                    values.extend(object_values.into_iter().map(|(k, v)| (k.to_string(), v)));
+                },
+                None => debug!("Skipping MiBeacon object 0x{:04X}", id),
+            }
+            i += len;
+        }
+        Ok(values)
+    }
+
+    // Version 4 and 5 frames end with a three byte extended frame counter and a four byte MIC.
+    // The nonce is the MAC address as sent in the frame, the product id, the frame counter and
+    // the extended frame counter. The frame counter and the extended frame counter together
+    // form the 32 bit counter that is returned for the replay check.
+    pub fn decrypt(data: &[u8], header: &MiBeaconHeader, key: &[u8], mac: &[u8])
+        -> Result<(Vec<u8>, u32), DecodeError>
+    {
+        let version = header.get_version();
+        if version < 4 {
+            return Err(DecodeError::new(
+                format!("Encrypted MiBeacon version {} is not supported", version)));
+        }
+        if data.len() < header.payload_start + EXT_COUNTER_LEN + crypto::MIC_LEN {
+            return Err(DecodeError::new("Encrypted MiBeacon frame too short".to_string()));
+        }
+        let mic_start = data.len() - crypto::MIC_LEN;
+        let counter_start = mic_start - EXT_COUNTER_LEN;
+        let ext_counter = &data[counter_start..mic_start];
+
+        let mut nonce = mac.to_vec();
+        nonce.extend_from_slice(&data[2..5]);
+        nonce.extend_from_slice(ext_counter);
+
+        let payload = crypto::aes_ccm_decrypt(
+            key, &nonce, &AAD, &data[header.payload_start..counter_start], &data[mic_start..])?;
+        let counter = (BTHome::read_int(ext_counter, false) as u32) << 8 | header.frame_counter as u32;
+        Ok((payload, counter))
+    }
+
+    fn decrypt_device_data(device: &BTDevice, data: &[u8], header: &MiBeaconHeader)
+        -> Result<Vec<u8>, DecodeError>
+    {
+        let key = device.get_key()
+            .ok_or_else(|| DecodeError::new("No bindkey configured in the devicemap".to_string()))?;
+        // Frames without the MAC address are encrypted with the address of the sender.
+        let mac = match header.mac {
+            Some(ref mac) => mac.to_vec(),
+            None => {
+                let mut mac = crypto::mac_to_bytes(device.get_expected_mac())
+                    .ok_or_else(|| DecodeError::new(format!("Invalid MAC address {}", device.get_expected_mac())))?;
+                mac.reverse();
+                mac
+            },
+        };
+        let (payload, counter) = MiBeacon::decrypt(data, header, key, &mac)?;
+        // The counter is checked only after the MIC so that forged frames cannot move it.
+        if device.check_frame_counter(counter) == FrameCheck::Replayed {
+            return Err(DecodeError::new(
+                format!("Replayed frame, counter {} is older than the last accepted one", counter)));
+        }
+        Ok(payload)
+    }
+
+    fn _get_measurements(&self) -> Option<Vec<(String, Value)>> {
+        let device = self.get_bt_device();
+        let data = device
+            .get_svc_data()?
+            .get(SVC_DATA_UUID)?;
+        let header = match MiBeacon::parse_header(data) {
+            Ok(h) => h,
+            Err(e) => {
+                warn!("Cannot decode MiBeacon data from {}: {}", device.get_address(), e);
+                return None;
+            },
+        };
+        if !header.has_objects() {
+            debug!("MiBeacon frame from {} has no objects", device.get_address());
+            return None;
+        }
+        let payload = if header.is_encrypted() {
+            match MiBeacon::decrypt_device_data(&device, data, &header) {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Cannot decrypt MiBeacon data from {}: {}", device.get_address(), e);
+                    return None;
+                },
+            }
+        } else {
+            data[header.payload_start..].to_vec()
+        };
+        match MiBeacon::parse_objects(&payload) {
+            Ok(mut values) => {
+                values.push((
+                    "product_id".to_string(),
+                    Value::Integer(header.product_id as i64),
+                ));
+                values.push((
+                    "encrypted".to_string(),
+                    Value::Boolean(header.is_encrypted()),
+                ));
+                Some(values)
+            },
+            Err(e) => {
+                warn!("Cannot decode MiBeacon data from {}: {}", device.get_address(), e);
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+    use hex;
+
+    const KEY: &str = "e9ea895fac7cca6d30532432a516f3a8";
+    const ADDRESS: &str = "A4:C1:38:38:83:F4";
+    // Encrypted with AES-CCM of the Python cryptography package. Version 5 with the MAC
+    // address and humidity 46.7 %, frame counter 0x50 and extended frame counter 0x26.
+    const V5_FRAME: &str = "58585b0550f4833838c1a4c15f81baff26000034b97d86";
+    // Version 4 without the MAC address and temperature 24.1 °C, frame counter 0x51 and
+    // extended frame counter 0x27.
+    const V4_FRAME: &str = "48405b05519b2aeb9e5d270000f03514d6";
+
+    fn measure(data: &str, key: Option<&str>) -> Option<HashMap<String, Value>> {
+        let mut svc_data = HashMap::new();
+        svc_data.insert(SVC_DATA_UUID.to_string(), hex::decode(data).unwrap());
+        let mut device = BTDevice::new(
+            ADDRESS.to_string(), String::new(),
+            None, Some(svc_data), 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        device.set_key(key.map(|k| hex::decode(k).unwrap()));
+        let sensor = MiBeacon::new(Rc::new(RefCell::new(device)));
+        sensor._get_measurements().map(|v| v.into_iter().collect())
+    }
+
+    #[test]
+    fn parses_headers() {
+        // LYWSD03MMC, version 3 with the MAC address and temperature and humidity.
+        let data = hex::decode("50305b05034c94b438c1a40d10041001ea01").unwrap();
+        let header = MiBeacon::parse_header(&data).unwrap();
+        assert_eq!(header.get_version(), 3);
+        assert!(!header.is_encrypted());
+        assert!(header.has_objects());
+        assert_eq!(header.product_id, 0x055B);
+        assert_eq!(header.frame_counter, 3);
+        assert_eq!(header.mac, Some(hex::decode("4c94b438c1a4").unwrap()));
+        assert_eq!(header.payload_start, 11);
+
+        // The capability byte with two bytes of I/O capability.
+        let header = MiBeacon::parse_header(&hex::decode("70305b0503".to_string()
+            + "4c94b438c1a4" + "200000" + "0a100164").unwrap()).unwrap();
+        assert_eq!(header.payload_start, 14);
+        assert!(MiBeacon::parse_header(&hex::decode("70305b05034c94b438c1a420").unwrap()).is_err());
+        assert!(MiBeacon::parse_header(&hex::decode("50305b05034c94").unwrap()).is_err());
+    }
+
+    #[test]
+    fn decodes_unencrypted_objects() {
+        let values = measure("50305b05034c94b438c1a40d10041001ea01", None).unwrap();
+        match (&values["temperature"], &values["humidity_float"]) {
+            (&Value::Float(t), &Value::Float(h)) => {
+                assert!((t - 27.2).abs() < 1e-9);
+                assert!((h - 49.0).abs() < 1e-9);
+            },
+            v => panic!("unexpected values {:?}", v),
+        }
+    }
+
+    #[test]
+    fn decrypts_v5_frame() {
+        let data = hex::decode(V5_FRAME).unwrap();
+        let header = MiBeacon::parse_header(&data).unwrap();
+        assert_eq!(header.get_version(), 5);
+        assert!(header.is_encrypted());
+        let key = hex::decode(KEY).unwrap();
+        let (payload, counter) = MiBeacon::decrypt(&data, &header, &key, header.mac.as_ref().unwrap()).unwrap();
+        assert_eq!(hex::encode(payload), "061002d301");
+        assert_eq!(counter, 0x2650);
+
+        let values = measure(V5_FRAME, Some(KEY)).unwrap();
+        match values["humidity_float"] {
+            Value::Float(h) => assert!((h - 46.7).abs() < 1e-9),
+            ref v => panic!("unexpected humidity {:?}", v),
+        }
+        assert!(measure(V5_FRAME, None).is_none());
+        assert!(measure(V5_FRAME, Some("00000000000000000000000000000000")).is_none());
+    }
+
+    #[test]
+    fn decrypts_v4_frame_with_device_address() {
+        let values = measure(V4_FRAME, Some(KEY)).unwrap();
+        match values["temperature"] {
+            Value::Float(t) => assert!((t - 24.1).abs() < 1e-9),
+            ref v => panic!("unexpected temperature {:?}", v),
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_frame() {
+        let mut svc_data = HashMap::new();
+        svc_data.insert(SVC_DATA_UUID.to_string(), hex::decode(V5_FRAME).unwrap());
+        let mut device = BTDevice::new(
+            ADDRESS.to_string(), String::new(),
+            None, Some(svc_data), 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        device.set_key(Some(hex::decode(KEY).unwrap()));
+        let sensor = MiBeacon::new(Rc::new(RefCell::new(device)));
+        assert!(sensor._get_measurements().is_some());
+        assert!(sensor._get_measurements().is_some());
+
+        // The sensor has sent a newer frame, the captured one is a replay.
+        assert_eq!(sensor.get_bt_device().check_frame_counter(0x2651), FrameCheck::New);
+        assert!(sensor._get_measurements().is_none());
+    }
+}
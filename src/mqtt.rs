@@ -0,0 +1,174 @@
+use std::error;
+use std::time::{Duration, Instant};
+
+use rumqttc::{
+    self, Client, Connection, ConnectionError, ConnectReturnCode, Event, MqttOptions, Packet, QoS,
+    RecvTimeoutError, SubscribeReasonCode, TlsConfiguration, Transport,
+};
+
+use error::{FatalError, MqttError};
+
+type BoxErr = Box<dyn error::Error>;
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_TLS_PORT: u16 = 8883;
+// The largest packet accepted from the broker. The bridges publish one advertisement, or a
+// few of them, per message.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How many requests, i.e. the subscriptions, may wait for the connection.
+const REQUEST_CAPACITY: usize = 16;
+
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// A client that subscribes with QoS 0 and receives the published messages.
+pub struct MqttClient {
+    client: Client,
+    connection: Connection,
+}
+
+fn mqtt_err(message: String) -> BoxErr {
+    Box::new(MqttError::new(message))
+}
+
+/// Splits the broker address to the host, the port and whether TLS is used. The address is
+/// host:port, or an mqtts:// URL for TLS with the system certificates.
+pub fn parse_broker(broker: &str) -> Result<(String, u16, bool), BoxErr> {
+    let (address, tls) = match broker.strip_prefix("mqtts://") {
+        Some(a) => (a, true),
+        None => (broker.strip_prefix("mqtt://").unwrap_or(broker), false),
+    };
+    let default_port = if tls { DEFAULT_TLS_PORT } else { DEFAULT_PORT };
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>()
+            .map_err(|_| FatalError::new(format!("Invalid port in the broker address {}", broker)))?),
+        None => (address, default_port),
+    };
+    if host.is_empty() {
+        return Err(Box::new(FatalError::new(format!("No host in the broker address {}", broker))));
+    }
+    Ok((host.to_string(), port, tls))
+}
+
+/// Matches a topic to a subscription filter where + matches one level and # the rest of
+/// the levels.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    rumqttc::matches(topic, filter)
+}
+
+/// The broker refusing the client or its credentials is not fixed by connecting again, only
+/// an unavailable server may accept the connection later.
+fn connection_err(broker: &str, e: ConnectionError) -> BoxErr {
+    match e {
+        ConnectionError::ConnectionRefused(ConnectReturnCode::ServiceUnavailable) => {
+            mqtt_err(format!("{} is unavailable", broker))
+        },
+        ConnectionError::ConnectionRefused(code) => Box::new(FatalError::new(
+            format!("{} refused the connection: {:?}", broker, code))),
+        e => mqtt_err(format!("Connection to {} failed: {}", broker, e)),
+    }
+}
+
+impl MqttClient {
+
+    pub fn connect(
+        broker: &str,
+        client_id: &str,
+        credentials: Option<(&str, &str)>,
+        keep_alive: Duration,
+        ) -> Result<MqttClient, BoxErr>
+    {
+        let (host, port, tls) = parse_broker(broker)?;
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(keep_alive);
+        options.set_clean_session(true);
+        options.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+        if tls {
+            options.set_transport(Transport::tls_with_config(TlsConfiguration::Native));
+        }
+        if let Some((username, password)) = credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, mut connection) = Client::new(options, REQUEST_CAPACITY);
+
+        // The connection is made when the event loop is polled the first time.
+        let start = Instant::now();
+        loop {
+            let timeout = CONNECT_TIMEOUT.saturating_sub(start.elapsed());
+            match connection.recv_timeout(timeout) {
+                Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => break,
+                Ok(Ok(_)) => continue,
+                Ok(Err(e)) => return Err(connection_err(broker, e)),
+                Err(_) => return Err(mqtt_err(format!("No CONNACK from {}", broker))),
+            }
+        }
+        Ok(MqttClient{client, connection})
+    }
+
+    /// Subscribes to the topic filters with QoS 0. The broker acknowledges them later.
+    pub fn subscribe(&mut self, filters: &[String]) -> Result<(), BoxErr> {
+        for filter in filters {
+            self.client.subscribe(filter.as_str(), QoS::AtMostOnce)
+                .map_err(|e| mqtt_err(format!("Cannot subscribe to {}: {}", filter, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next published message until the timeout. The client pings the broker
+    /// meanwhile, otherwise the broker disconnects after the keep alive time.
+    pub fn read_publish(&mut self, timeout: Duration) -> Result<Option<Publish>, BoxErr> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            let event = match self.connection.recv_timeout(timeout.saturating_sub(start.elapsed())) {
+                Ok(Ok(e)) => e,
+                Ok(Err(e)) => return Err(mqtt_err(format!("Connection to the broker failed: {}", e))),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(mqtt_err("Connection closed".to_string()));
+                },
+            };
+            match event {
+                Event::Incoming(Packet::Publish(p)) => {
+                    return Ok(Some(Publish{topic: p.topic, payload: p.payload.to_vec()}));
+                },
+                Event::Incoming(Packet::SubAck(ack))
+                    if ack.return_codes.contains(&SubscribeReasonCode::Failure) => {
+                    return Err(Box::new(FatalError::new(
+                        "The broker refused the subscription".to_string())));
+                },
+                _ => (),
+            }
+        }
+        Ok(None)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_topic_filters() {
+        assert!(topic_matches("ruuvi/#", "ruuvi/AA:BB:CC:DD:EE:FF/CB:B8:33:4C:88:4F"));
+        assert!(topic_matches("home/+/BTtoMQTT/#", "home/TheengsGateway/BTtoMQTT/C4C1381E2F10"));
+        assert!(topic_matches("esphome/+/adv", "esphome/proxy/adv"));
+        assert!(!topic_matches("esphome/+/adv", "esphome/proxy/adv/more"));
+        assert!(!topic_matches("esphome/+/adv", "esphome/adv"));
+        assert!(!topic_matches("ruuvi/#", "other/topic"));
+    }
+
+    #[test]
+    fn parses_broker_addresses() {
+        assert_eq!(parse_broker("localhost:1883").unwrap(), ("localhost".to_string(), 1883, false));
+        assert_eq!(parse_broker("mqtt://broker").unwrap(), ("broker".to_string(), 1883, false));
+        assert_eq!(parse_broker("mqtts://broker").unwrap(), ("broker".to_string(), 8883, true));
+        assert_eq!(parse_broker("mqtts://broker:8884").unwrap(), ("broker".to_string(), 8884, true));
+        assert!(parse_broker("broker:port").is_err());
+        assert!(parse_broker("mqtts://:8883").is_err());
+    }
+
+}
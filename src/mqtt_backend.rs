@@ -0,0 +1,356 @@
+use std::env;
+use std::error;
+use std::process;
+use std::time::{Duration, Instant};
+
+use serde_json::{self, Value};
+use hex;
+
+use backend::Backend;
+use config;
+use consumer::Consumer;
+use crypto;
+use device_store::DeviceStore;
+use error::{DecodeError, MqttError};
+use gateway::as_i64;
+use hci::{self, Advertisement};
+use mqtt::{self, MqttClient, Publish};
+use recording::Recorder;
+use timestamp::unix_timestamp;
+
+type BoxErr = Box<dyn error::Error>;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// How the payloads of a topic are read.
+///
+/// Ruuvi is the MQTT format of the Ruuvi Gateway, topic ruuvi/<gateway MAC>/<tag MAC> and
+/// payload {"gw_mac": "...", "rssi": -62, "ts": "1700000000", "data": "0201061BFF9904..."}.
+///
+/// Theengs is the raw mode of Theengs Gateway and OpenMQTTGateway, {"id": "<tag MAC>",
+/// "rssi": -70, "manufacturerdata": "9904...", "servicedata": "...", "servicedatauuid":
+/// "0xfcd2"}, where the manufacturer data starts with the company id.
+///
+/// Esphome is a JSON form of the raw advertisements of an ESPHome bluetooth_proxy,
+/// {"address": "<tag MAC>", "rssi": -70, "address_type": 1, "data": "0201061BFF9904..."} or
+/// an array of them. The address may also be the 48 bit integer ESPHome uses.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PayloadFormat {
+    Ruuvi,
+    Theengs,
+    Esphome,
+}
+
+/// A topic filter and the format of the payloads published to it, given as format:filter,
+/// e.g. theengs:home/TheengsGateway/BTtoMQTT/#.
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    format: PayloadFormat,
+    filter: String,
+}
+
+impl Subscription {
+
+    pub fn parse(subscription: &str) -> Result<Subscription, BoxErr> {
+        let (format, filter) = subscription.split_once(':')
+            .ok_or_else(|| MqttError::new(
+                format!("Invalid subscription {}, expected format:topic", subscription)))?;
+        let format = match format {
+            "ruuvi" => PayloadFormat::Ruuvi,
+            "theengs" => PayloadFormat::Theengs,
+            "esphome" => PayloadFormat::Esphome,
+            _ => return Err(Box::new(MqttError::new(format!("Unknown payload format {}", format)))),
+        };
+        Ok(Subscription{format, filter: filter.to_string()})
+    }
+
+}
+
+pub fn parse_subscriptions(subscriptions: &str) -> Result<Vec<Subscription>, BoxErr> {
+    subscriptions.split(',').map(|s| Subscription::parse(s.trim())).collect()
+}
+
+fn raw_advertisement(address: &str, data: &str) -> Result<Advertisement, DecodeError> {
+    let raw = hex::decode(data)
+        .map_err(|e| DecodeError::new(format!("Invalid data of {}: {}", address, e)))?;
+    let mut adv = Advertisement{
+        address: address.to_uppercase(),
+        ..Default::default()
+    };
+    hci::parse_advertising_data(&raw, &mut adv)?;
+    Ok(adv)
+}
+
+fn str_field<'a>(json: &'a Value, field: &str) -> Result<&'a str, DecodeError> {
+    json.get(field)
+        .and_then(|f| f.as_str())
+        .ok_or_else(|| DecodeError::new(format!("No {}", field)))
+}
+
+fn parse_ruuvi(topic: &str, json: &Value) -> Result<Vec<(u64, Advertisement)>, DecodeError> {
+    let address = topic.rsplit('/').next().unwrap_or_default();
+    // ruuvi/# also matches the status topics of the gateway, e.g. ruuvi/<gateway MAC>/gw_status.
+    if crypto::mac_to_bytes(address).is_none() {
+        debug!("Skipping {}, not a tag topic", topic);
+        return Ok(Vec::new());
+    }
+    let mut adv = raw_advertisement(address, str_field(json, "data")?)?;
+    adv.link_info.rssi = json.get("rssi").and_then(as_i64).map(|r| r as i16);
+    adv.link_info.adapter = json.get("gw_mac").and_then(|m| m.as_str()).map(|m| m.to_uppercase());
+    let timestamp = json.get("ts")
+        .or_else(|| json.get("gwts"))
+        .and_then(as_i64)
+        .filter(|t| *t > 0)
+        .map(|t| t as u64 * 1000)
+        .unwrap_or_else(unix_timestamp);
+    Ok(vec!((timestamp, adv)))
+}
+
+fn parse_theengs(json: &Value) -> Result<Vec<(u64, Advertisement)>, DecodeError> {
+    let mut adv = Advertisement{
+        address: str_field(json, "id")?.to_uppercase(),
+        ..Default::default()
+    };
+    if let Ok(mfr_data) = str_field(json, "manufacturerdata") {
+        let mfr_data = hex::decode(mfr_data)
+            .map_err(|e| DecodeError::new(format!("Invalid manufacturerdata: {}", e)))?;
+        if mfr_data.len() >= 2 {
+            let company_id = ((mfr_data[1] as u16) << 8) | mfr_data[0] as u16;
+            adv.mfr_data.insert(company_id, mfr_data[2..].to_vec());
+        }
+    }
+    if let (Ok(svc_data), Ok(uuid)) = (str_field(json, "servicedata"), str_field(json, "servicedatauuid")) {
+        let svc_data = hex::decode(svc_data)
+            .map_err(|e| DecodeError::new(format!("Invalid servicedata: {}", e)))?;
+        let mut uuid = hex::decode(uuid.trim_start_matches("0x").replace('-', ""))
+            .map_err(|e| DecodeError::new(format!("Invalid servicedatauuid: {}", e)))?;
+        // The UUID is written most significant byte first.
+        uuid.reverse();
+        if [2, 4, 16].contains(&uuid.len()) {
+            adv.svc_data.insert(hci::uuid_string(&uuid), svc_data);
+        }
+    }
+    adv.link_info.rssi = json.get("rssi").and_then(as_i64).map(|r| r as i16);
+    adv.link_info.tx_power = json.get("txpower").and_then(as_i64).map(|t| t as i16);
+    adv.link_info.name = json.get("name").and_then(|n| n.as_str()).map(|n| n.to_string());
+    Ok(vec!((unix_timestamp(), adv)))
+}
+
+fn parse_esphome(json: &Value) -> Result<Vec<(u64, Advertisement)>, DecodeError> {
+    if let Some(advertisements) = json.as_array() {
+        let mut all = Vec::new();
+        for adv in advertisements {
+            all.extend(parse_esphome(adv)?);
+        }
+        return Ok(all);
+    }
+    let address = match json.get("address") {
+        Some(Value::String(a)) => a.to_string(),
+        Some(Value::Number(n)) => {
+            let bytes = n.as_u64()
+                .ok_or_else(|| DecodeError::new("Invalid address".to_string()))?
+                .to_le_bytes();
+            hci::format_address(&bytes[0..6])
+        },
+        _ => return Err(DecodeError::new("No address".to_string())),
+    };
+    let mut adv = raw_advertisement(&address, str_field(json, "data")?)?;
+    adv.link_info.rssi = json.get("rssi").and_then(as_i64).map(|r| r as i16);
+    adv.link_info.address_type = match json.get("address_type") {
+        Some(Value::String(t)) => Some(t.to_string()),
+        Some(t) => match as_i64(t) {
+            Some(0) => Some("public".to_string()),
+            Some(1) => Some("random".to_string()),
+            _ => None,
+        },
+        None => None,
+    };
+    Ok(vec!((unix_timestamp(), adv)))
+}
+
+/// Subscribes to the topics of BLE bridges, e.g. Ruuvi Gateways, Theengs Gateways or ESPHome
+/// devices, that publish the raw advertisements to an MQTT broker. The user name and the
+/// password are read from MQTT_USER and MQTT_PASSWORD if set.
+pub struct MqttBackend {
+    broker: String,
+    subscriptions: Vec<Subscription>,
+    client: Option<MqttClient>,
+    store: DeviceStore,
+}
+
+impl MqttBackend {
+
+    pub fn new(
+        conf: config::SensorConf,
+        broker: &str,
+        subscriptions: Vec<Subscription>,
+        ) -> MqttBackend
+    {
+        MqttBackend{
+            broker: broker.to_string(),
+            subscriptions,
+            client: None,
+            store: DeviceStore::new(conf),
+        }
+    }
+
+    fn parse_publish(&self, publish: &Publish) -> Result<Vec<(u64, Advertisement)>, DecodeError> {
+        let subscription = self.subscriptions
+            .iter()
+            .find(|s| mqtt::topic_matches(&s.filter, &publish.topic))
+            .ok_or_else(|| DecodeError::new("No subscription matches".to_string()))?;
+        let json: Value = serde_json::from_slice(&publish.payload)
+            .map_err(|e| DecodeError::new(format!("Invalid JSON: {}", e)))?;
+        match subscription.format {
+            PayloadFormat::Ruuvi => parse_ruuvi(&publish.topic, &json),
+            PayloadFormat::Theengs => parse_theengs(&json),
+            PayloadFormat::Esphome => parse_esphome(&json),
+        }
+    }
+
+    fn read_advertisements(
+        &mut self,
+        mut consumer: Option<&mut dyn Consumer>,
+        wait: Duration,
+        ) -> Result<(), BoxErr>
+    {
+        let start = Instant::now();
+        while start.elapsed() < wait {
+            let client = self.client
+                .as_mut()
+                .ok_or_else(|| MqttError::new("Not connected".to_string()))?;
+            let publish = match client.read_publish(wait.saturating_sub(start.elapsed()))? {
+                Some(p) => p,
+                None => continue,
+            };
+            let advertisements = match self.parse_publish(&publish) {
+                Ok(a) => a,
+                Err(e) => {
+                    warn!("Cannot parse the message of {}: {}", publish.topic, e);
+                    continue;
+                },
+            };
+            for (timestamp, adv) in advertisements {
+                let address = adv.address.clone();
+                let is_new = self.store.add_advertisement(adv, timestamp);
+                if let (true, Some(c)) = (is_new, consumer.as_mut()) {
+                    self.store.consume_device(&address, &mut **c);
+                }
+            }
+        }
+        Ok(())
+    }
+
+}
+
+impl Backend for MqttBackend {
+
+    /// Connects to the broker and subscribes to the topics. A lost connection is not used
+    /// again, so this also reconnects.
+    fn initialize(&mut self) -> Result<(), BoxErr> {
+        self.client = None;
+        let user = env::var("MQTT_USER").ok();
+        let password = env::var("MQTT_PASSWORD").unwrap_or_default();
+        let credentials = user.as_deref().map(|u| (u, password.as_str()));
+        let client_id = format!("bt-sensor-{}", process::id());
+        let mut client = MqttClient::connect(&self.broker, &client_id, credentials, KEEP_ALIVE)?;
+        let filters: Vec<String> = self.subscriptions.iter().map(|s| s.filter.clone()).collect();
+        client.subscribe(&filters)?;
+        info!("Subscribed to {} on {}", filters.join(","), self.broker);
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn consume(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        self.read_advertisements(Some(consumer), wait)?;
+        consumer.flush();
+        self.store.evict_unseen();
+        Ok(())
+    }
+
+    fn list(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        self.read_advertisements(None, wait)?;
+        self.store.consume_all(consumer);
+        consumer.flush();
+        Ok(())
+    }
+
+    fn set_recorder(&mut self, recorder: Recorder) {
+        self.store.set_recorder(recorder);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DF5_DATA: &str = "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F";
+    const DF5_MFR_DATA: &str = "0512fc5394c37c0004fffc040cac364200cdcbb8334c884f";
+
+    fn json(payload: &str) -> Value {
+        serde_json::from_str(payload).unwrap()
+    }
+
+    #[test]
+    fn parses_ruuvi_payload() {
+        let payload = json(&format!(
+            r#"{{"gw_mac": "aa:bb:cc:dd:ee:ff", "rssi": -62, "ts": "1700000000", "data": "{}"}}"#,
+            DF5_DATA));
+        let advs = parse_ruuvi("ruuvi/AA:BB:CC:DD:EE:FF/c8:25:2d:8e:aa:c4", &payload).unwrap();
+        assert_eq!(advs.len(), 1);
+        let (timestamp, adv) = &advs[0];
+        assert_eq!(*timestamp, 1_700_000_000_000);
+        assert_eq!(adv.address, "C8:25:2D:8E:AA:C4");
+        assert_eq!(hex::encode(&adv.mfr_data[&0x0499]), DF5_MFR_DATA);
+        assert_eq!(adv.link_info.rssi, Some(-62));
+        assert_eq!(adv.link_info.adapter.as_deref(), Some("AA:BB:CC:DD:EE:FF"));
+    }
+
+    #[test]
+    fn skips_ruuvi_gateway_topics() {
+        let status = json(r#"{"state": "online"}"#);
+        assert!(parse_ruuvi("ruuvi/AA:BB:CC:DD:EE:FF/gw_status", &status).unwrap().is_empty());
+        let no_data = json(r#"{"rssi": -62}"#);
+        assert!(parse_ruuvi("ruuvi/AA:BB:CC:DD:EE:FF/C8:25:2D:8E:AA:C4", &no_data).is_err());
+    }
+
+    #[test]
+    fn parses_theengs_payload() {
+        let payload = json(&format!(
+            r#"{{"id": "c8:25:2d:8e:aa:c4", "rssi": -70, "txpower": 4, "name": "Ruuvi AAC4",
+                "manufacturerdata": "9904{}", "servicedata": "40020a01", "servicedatauuid": "0xfcd2"}}"#,
+            DF5_MFR_DATA));
+        let advs = parse_theengs(&payload).unwrap();
+        assert_eq!(advs.len(), 1);
+        let adv = &advs[0].1;
+        assert_eq!(adv.address, "C8:25:2D:8E:AA:C4");
+        assert_eq!(hex::encode(&adv.mfr_data[&0x0499]), DF5_MFR_DATA);
+        assert_eq!(adv.svc_data["0000fcd2-0000-1000-8000-00805f9b34fb"], vec!(0x40, 0x02, 0x0a, 0x01));
+        assert_eq!(adv.link_info.rssi, Some(-70));
+        assert_eq!(adv.link_info.tx_power, Some(4));
+        assert_eq!(adv.link_info.name.as_deref(), Some("Ruuvi AAC4"));
+
+        assert!(parse_theengs(&json(r#"{"rssi": -70}"#)).is_err());
+        assert!(parse_theengs(&json(r#"{"id": "C8:25:2D:8E:AA:C4", "manufacturerdata": "99x4"}"#)).is_err());
+    }
+
+    #[test]
+    fn parses_esphome_payload() {
+        let payload = json(&format!(
+            r#"[{{"address": "c8:25:2d:8e:aa:c4", "rssi": -70, "address_type": 1, "data": "{0}"}},
+                {{"address": 220062003669700, "rssi": -75, "address_type": "public", "data": "{0}"}}]"#,
+            DF5_DATA));
+        let advs = parse_esphome(&payload).unwrap();
+        assert_eq!(advs.len(), 2);
+        for (adv, rssi, address_type) in [(&advs[0].1, -70, "random"), (&advs[1].1, -75, "public")] {
+            assert_eq!(adv.address, "C8:25:2D:8E:AA:C4");
+            assert_eq!(hex::encode(&adv.mfr_data[&0x0499]), DF5_MFR_DATA);
+            assert_eq!(adv.link_info.rssi, Some(rssi));
+            assert_eq!(adv.link_info.address_type.as_deref(), Some(address_type));
+        }
+
+        assert!(parse_esphome(&json(r#"{"data": "020106"}"#)).is_err());
+        assert!(parse_esphome(&json(r#"{"address": "C8:25:2D:8E:AA:C4", "data": "0x"}"#)).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::error;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Lines, Write};
+
+use serde_json;
+use hex;
+
+use bt_device::LinkInfo;
+
+type BoxErr = Box<dyn error::Error>;
+
+/// One raw advertisement as it was given to the device store, written as one line of JSON.
+/// The data is hex encoded, e.g. {"timestamp":1700000000000,"adapter":"hci0",
+/// "address":"F0:0D:00:00:00:01","rssi":-70,"mfr_data":{"1177":"0512fc5394c37c..."}}.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Record {
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_power: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mfr_data: Option<HashMap<u16, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub svc_data: Option<HashMap<String, String>>,
+    /// True for the data a BlueZ poll read from the device cache. The poll reads every
+    /// device again, also when it has not been heard since, so these are not advertisements.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub snapshot: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl Record {
+
+    pub fn new(
+        address: &str,
+        mfr_data: Option<&HashMap<u16, Vec<u8>>>,
+        svc_data: Option<&HashMap<String, Vec<u8>>>,
+        link_info: &LinkInfo,
+        timestamp: u64,
+        ) -> Record
+    {
+        Record{
+            timestamp,
+            adapter: link_info.adapter.clone(),
+            address: address.to_string(),
+            rssi: link_info.rssi,
+            tx_power: link_info.tx_power,
+            address_type: link_info.address_type.clone(),
+            name: link_info.name.clone(),
+            mfr_data: mfr_data.map(|m| m.iter().map(|(k, v)| (*k, hex::encode(v))).collect()),
+            svc_data: svc_data.map(|m| m.iter().map(|(k, v)| (k.clone(), hex::encode(v))).collect()),
+            snapshot: false,
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<Record, BoxErr> {
+        Ok(serde_json::from_str(line)?)
+    }
+
+    pub fn get_mfr_data(&self) -> Result<Option<HashMap<u16, Vec<u8>>>, BoxErr> {
+        match self.mfr_data {
+            Some(ref m) => {
+                let mut map = HashMap::new();
+                for (k, v) in m {
+                    map.insert(*k, hex::decode(v)?);
+                }
+                Ok(Some(map))
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_svc_data(&self) -> Result<Option<HashMap<String, Vec<u8>>>, BoxErr> {
+        match self.svc_data {
+            Some(ref m) => {
+                let mut map = HashMap::new();
+                for (k, v) in m {
+                    map.insert(k.clone(), hex::decode(v)?);
+                }
+                Ok(Some(map))
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_link_info(&self) -> LinkInfo {
+        LinkInfo{
+            rssi: self.rssi,
+            tx_power: self.tx_power,
+            address_type: self.address_type.clone(),
+            name: self.name.clone(),
+            adapter: self.adapter.clone(),
+        }
+    }
+
+}
+
+/// Appends the records to a file. Every line is written right away, so the recording is
+/// complete up to the last advertisement even if the collector is killed.
+pub struct Recorder {
+    writer: LineWriter<File>,
+}
+
+impl Recorder {
+
+    pub fn new(path: &str) -> Result<Recorder, BoxErr> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        Ok(Recorder{writer: LineWriter::new(file)})
+    }
+
+    pub fn record(&mut self, record: &Record) -> Result<(), BoxErr> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+}
+
+/// Reads the records of a recording. The lines that are not valid records are skipped.
+pub struct RecordReader {
+    path: String,
+    lines: Lines<BufReader<File>>,
+}
+
+impl RecordReader {
+
+    pub fn open(path: &str) -> Result<RecordReader, BoxErr> {
+        let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        Ok(RecordReader{path: path.to_string(), lines: BufReader::new(file).lines()})
+    }
+
+}
+
+impl Iterator for RecordReader {
+    type Item = Result<Record, BoxErr>;
+
+    fn next(&mut self) -> Option<Result<Record, BoxErr>> {
+        for line in self.lines.by_ref() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => return Some(Err(Box::new(e))),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Record::parse(&line) {
+                Ok(r) => return Some(Ok(r)),
+                Err(e) => warn!("Invalid record in {}: {}", self.path, e),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_survives_a_round_trip() {
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(0x0499, vec!(0x05, 0x12, 0xFC));
+        let link_info = LinkInfo{
+            rssi: Some(-70),
+            adapter: Some("hci0".to_string()),
+            ..LinkInfo::default()
+        };
+        let record = Record::new("F0:0D:00:00:00:01", Some(&mfr_data), None, &link_info, 1700000000000);
+        let line = serde_json::to_string(&record).unwrap();
+        let parsed = Record::parse(&line).unwrap();
+        assert_eq!(parsed, record);
+        assert_eq!(parsed.get_mfr_data().unwrap(), Some(mfr_data));
+        assert_eq!(parsed.get_svc_data().unwrap(), None);
+        assert_eq!(parsed.get_link_info(), link_info);
+    }
+
+}
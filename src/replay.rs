@@ -0,0 +1,144 @@
+use std::error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backend::Backend;
+use capture_file::CaptureReader;
+use config;
+use consumer::Consumer;
+use device_store::DeviceStore;
+use error::{FatalError, ReplayError};
+use recording::{Record, RecordReader, Recorder};
+
+type BoxErr = Box<dyn error::Error>;
+
+/// Feeds a recording made with --record, or a btsnoop or pcap capture, to the sensors and the
+/// consumer as if the advertisements were captured now. The measurements keep the timestamps
+/// of the recording. The speed multiplies the pace of the recording, 0 replays it as fast as
+/// possible.
+pub struct ReplayBackend {
+    path: String,
+    records: Box<dyn Iterator<Item = Result<Record, BoxErr>>>,
+    store: DeviceStore,
+    speed: f64,
+    // The timestamp of the first record and when it was replayed.
+    start: Option<(u64, Instant)>,
+    finished: bool,
+}
+
+impl ReplayBackend {
+
+    pub fn new(conf: config::SensorConf, path: &str, speed: f64) -> Result<ReplayBackend, BoxErr> {
+        if !(speed >= 0.0 && speed.is_finite()) {
+            return Err(Box::new(ReplayError::new(format!("Invalid replay speed {}", speed))));
+        }
+        // Opened here, the supervisor would retry a missing file forever.
+        let records: Box<dyn Iterator<Item = Result<Record, BoxErr>>> = match CaptureReader::open(path)? {
+            Some(capture) => Box::new(capture),
+            None => Box::new(RecordReader::open(path)?),
+        };
+        Ok(ReplayBackend{
+            path: path.to_string(),
+            records,
+            store: DeviceStore::new(conf),
+            speed,
+            start: None,
+            finished: false,
+        })
+    }
+
+    /// The next record, None at the end of the recording.
+    fn next_record(&mut self) -> Result<Option<Record>, BoxErr> {
+        match self.records.next() {
+            Some(r) => r.map(Some),
+            None => {
+                self.finished = true;
+                Ok(None)
+            },
+        }
+    }
+
+    /// Sleeps until the record is due at the replay speed.
+    fn wait_until_due(&mut self, timestamp: u64) {
+        if self.speed == 0.0 {
+            return;
+        }
+        let (first_ts, first_instant) = *self.start.get_or_insert((timestamp, Instant::now()));
+        let offset = timestamp.saturating_sub(first_ts) as f64 / 1000.0 / self.speed;
+        let due = first_instant + Duration::from_secs_f64(offset);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+
+    /// Gives the record to the store and returns true if it was a new reading of a sensor. A
+    /// scan response in a capture has only some of the data, the rest is kept from the
+    /// advertisement. A snapshot of a BlueZ poll is a new reading only if the data changed or
+    /// the device was heard again, like in the poll that recorded it.
+    fn replay(&mut self, record: &Record) -> Result<bool, BoxErr> {
+        if record.snapshot {
+            return Ok(self.store.poll_device(
+                &record.address, record.get_mfr_data()?, record.get_svc_data()?,
+                record.get_link_info(), record.timestamp));
+        }
+        let (mfr_data, svc_data) = self.store.merge_data(
+            &record.address, record.get_mfr_data()?, record.get_svc_data()?);
+        Ok(self.store.record_advertisement(
+            &record.address, mfr_data, svc_data, record.get_link_info(), record.timestamp))
+    }
+
+}
+
+impl Backend for ReplayBackend {
+
+    /// The recording is opened when the backend is created, the replay continues where it was.
+    fn initialize(&mut self) -> Result<(), BoxErr> {
+        info!("Replaying {}", self.path);
+        Ok(())
+    }
+
+    fn consume(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        let start = Instant::now();
+        while start.elapsed() < wait {
+            // Reading the file again does not help.
+            let record = match self.next_record()
+                .map_err(|e| FatalError::new(format!("Cannot read {}: {}", self.path, e)))? {
+                Some(r) => r,
+                None => {
+                    info!("End of {}", self.path);
+                    break;
+                },
+            };
+            self.wait_until_due(record.timestamp);
+            match self.replay(&record) {
+                Ok(true) => self.store.consume_device(&record.address, consumer),
+                Ok(false) => (),
+                Err(e) => warn!("Cannot replay the record of {}: {}", record.address, e),
+            }
+        }
+        consumer.flush();
+        Ok(())
+    }
+
+    /// Reads the whole recording without waiting.
+    fn list(&mut self, consumer: &mut dyn Consumer, _wait: Duration) -> Result<(), BoxErr> {
+        while let Some(record) = self.next_record()? {
+            if let Err(e) = self.replay(&record) {
+                warn!("Cannot replay the record of {}: {}", record.address, e);
+            }
+        }
+        self.store.consume_all(consumer);
+        consumer.flush();
+        Ok(())
+    }
+
+    fn set_recorder(&mut self, recorder: Recorder) {
+        self.store.set_recorder(recorder);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+}
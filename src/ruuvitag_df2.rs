@@ -7,8 +7,8 @@ use base64;
 use serde_json;
 
 use bt_sensor::{BTSensor, BTSensorConstructor, Value};
-use discovery_mode::DiscoveryMode;
 use bt_device::BTDevice;
+use eddystone;
 
 pub struct RuuvitagDF2Constructor;
 
@@ -22,8 +22,8 @@ impl BTSensorConstructor for RuuvitagDF2Constructor {
     fn get_name(&self) -> &'static str {
         "RuuvitagDF2"
     }
-    fn construct(&self, device: Rc<RefCell<BTDevice>>, discovery_mode: DiscoveryMode) -> Box<dyn BTSensor> {
-        Box::new(RuuvitagDF2::new(device, discovery_mode))
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(RuuvitagDF2::new(device))
     }
     fn is_valid_data(&self, device: &BTDevice) -> bool {
         RuuvitagDF2::_is_valid_data(device)
@@ -32,7 +32,6 @@ impl BTSensorConstructor for RuuvitagDF2Constructor {
 
 #[derive(Clone)]
 pub struct RuuvitagDF2 {
-    discovery_mode: DiscoveryMode,
     bt_device: Rc<RefCell<BTDevice>>,
 }
 
@@ -42,15 +41,15 @@ impl BTSensor for RuuvitagDF2 {
         RuuvitagDF2::_is_valid_data(&self.bt_device.borrow())
     }
 
-    fn get_measurements_str(&self) -> Option<String> {
+    fn get_sensor_measurements_str(&self) -> Option<String> {
         self._get_measurements_str()
     }
 
-    fn get_measurements_json_str(&self) -> Option<String> {
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
         self._get_measurements_json_str()
     }
 
-    fn get_measurements(&self) -> Option<HashMap<String, Value>> {
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
         match self._get_measurements() {
             Some(m) => {
                 let mut map = HashMap::<String, Value>::new();
@@ -72,7 +71,7 @@ impl BTSensor for RuuvitagDF2 {
         }
     }
 
-    fn get_bt_device(&self) -> Ref<BTDevice> {
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
         self.bt_device.borrow()
     }
 
@@ -90,47 +89,96 @@ impl BTSensor for RuuvitagDF2 {
 
 }
 
-static SVC_DATA_UUID: &'static str = "0000feaa-0000-1000-8000-00805f9b34fb";
+static URL_PREFIX: &str = "ruu.vi/#";
+const DATA_FORMAT: u8 = 2;
+const FRAGMENT_LEN: usize = 8;
 
 impl RuuvitagDF2 {
 
-    pub fn new(bt_device: Rc<RefCell<BTDevice>>, discovery_mode: DiscoveryMode) -> RuuvitagDF2 {
-        RuuvitagDF2{bt_device, discovery_mode}
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> RuuvitagDF2 {
+        RuuvitagDF2{bt_device}
     }
 
     pub fn _is_valid_data(device: &BTDevice) -> bool {
-        match device.get_svc_data().and_then(|m| m.get(SVC_DATA_UUID)) {
-            Some(data) => {
-                if data.len() == 20 {
-                    return true;
-                }
-                false
+        eddystone::get_frame(device, eddystone::URL_FRAME)
+            .and_then(|data| RuuvitagDF2::decode_url_data(data, FRAGMENT_LEN))
+            .is_some_and(|data| RuuvitagDF2::get_data_format(&data) == Some(DATA_FORMAT))
+    }
+
+    // The measurements are sent as an Eddystone URL frame: frame type, TX power and URL scheme
+    // bytes followed by "ruu.vi/#" and the base64 encoded measurement data. Data format 2 has
+    // an eight character fragment, data format 4 appends one more character for the tag id.
+    // Anything else is not a valid Ruuvi URL.
+    pub fn get_url_fragment(data: &[u8]) -> Option<&str> {
+        if data.len() < 3 || data[0] != eddystone::URL_FRAME {
+            return None;
+        }
+        let url = str::from_utf8(&data[3..]).ok()?;
+        if !url.starts_with(URL_PREFIX) {
+            return None;
+        }
+        Some(&url[URL_PREFIX.len()..])
+    }
+
+    /// The tags encode the fragment with the URL safe alphabet, the standard one is accepted
+    /// as well.
+    pub fn base64_value(c: u8) -> Option<u8> {
+        match c {
+            b'A'..=b'Z' => Some(c - b'A'),
+            b'a'..=b'z' => Some(c - b'a' + 26),
+            b'0'..=b'9' => Some(c - b'0' + 52),
+            b'-' | b'+' => Some(62),
+            b'_' | b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    /// Decodes the measurement bytes from the URL frame if the fragment has the expected
+    /// length. The first eight characters decode to six whole bytes. A ninth character
+    /// carries only six bits and is decoded to the most significant bits of one more byte.
+    pub fn decode_url_data(data: &[u8], fragment_len: usize) -> Option<Vec<u8>> {
+        let fragment = RuuvitagDF2::get_url_fragment(data)?;
+        if fragment.len() != fragment_len || fragment_len < FRAGMENT_LEN || !fragment.is_ascii() {
+            debug!("Unexpected Ruuvi URL fragment {:?}", fragment);
+            return None;
+        }
+        let (whole, rest) = fragment.split_at(FRAGMENT_LEN);
+        let whole = whole.replace('-', "+").replace('_', "/");
+        let mut decoded = match base64::decode_config(&whole, base64::STANDARD) {
+            Ok(d) => d,
+            Err(e) => {
+                debug!("Malformed Ruuvi URL fragment {:?}: {}", fragment, e);
+                return None;
             },
-            None => {
-                false
+        };
+        for c in rest.bytes() {
+            match RuuvitagDF2::base64_value(c) {
+                Some(v) => decoded.push(v << 2),
+                None => {
+                    debug!("Malformed Ruuvi URL fragment {:?}", fragment);
+                    return None;
+                },
             }
         }
+        Some(decoded)
     }
 
-
     // See https://github.com/ruuvi/ruuvi-sensor-protocols#data-format-3-protocol-specification
     // for the specification
-    pub fn get_data_format(data: &Vec<u8>) -> Option<u8> {
-        data.get(0).map(|v| *v)
+    pub fn get_data_format(data: &[u8]) -> Option<u8> {
+        data.first().copied()
     }
 
-    pub fn get_humidity(data: &Vec<u8>) -> Option<f32> {
-        let humidity = data.get(1).map(|v| *v)?;
+    pub fn get_humidity(data: &[u8]) -> Option<f32> {
+        let humidity = *data.get(1)?;
         Some((humidity as f32) * 0.5_f32)
     }
 
-    pub fn get_temp_wholes(data: &Vec<u8>) -> Option<u8> {
-        data.get(2).map(|u8_temp| {
-                (0x7F & u8_temp)
-            })
+    pub fn get_temp_wholes(data: &[u8]) -> Option<u8> {
+        data.get(2).map(|u8_temp| 0x7F & u8_temp)
     }
 
-    pub fn get_temp_sign(data: &Vec<u8>) -> Option<i8> {
+    pub fn get_temp_sign(data: &[u8]) -> Option<i8> {
         data.get(2).map(|raw_temp| {
                 match raw_temp & 0x80 {
                     0 => 1,
@@ -139,46 +187,31 @@ impl RuuvitagDF2 {
             })
     }
 
-    pub fn get_temp_fractions(data: &Vec<u8>) -> Option<u8> {
-        data.get(3).map(|v| *v)
+    pub fn get_temp_fractions(data: &[u8]) -> Option<u8> {
+        data.get(3).copied()
     }
 
-    pub fn get_pressure(data: &Vec<u8>) -> Option<u16> {
+    pub fn get_pressure(data: &[u8]) -> Option<u16> {
         let pressure_top = data.get(4)?;
         let pressure_bottom = data.get(5)?;
         Some(((*pressure_top as u16) << 8) | *pressure_bottom as u16)
     }
 
-
-    pub fn get_id(data: &Vec<u8>) -> Option<u8> {
-        data.get(6).map(|v| *v)
-    }
-
     fn _get_measurements(&self) -> Option<RuuvitagDF2Meas> {
         let device = self.get_bt_device();
-        let data_vec = device
-            .get_svc_data()?
-            .get(SVC_DATA_UUID)?;
-
-        if data_vec.len() < 4 {
-            return None
-        }
+        let data_vec = eddystone::get_frame(&device, eddystone::URL_FRAME)?;
 
-        let slice = &data_vec[3..];
-        let uri = str::from_utf8(slice).ok()?;
-        let cuts = uri.split("#").collect::<Vec<&str>>();
-        // Not really sure about this... apparently the string is too short and the base64 package
-        // cant decode it. I am pretty sure this fixes it.
-        let fixed_len = format!("{}A", cuts.get(1)?);
-        let data = base64::decode_config(&fixed_len, base64::STANDARD_NO_PAD).ok()?;
+        // Earlier versions decoded the nine character fragments of data format 4 as
+        // RuuvitagDF2, so devicemaps that name it for those tags still get the readings.
+        let data = RuuvitagDF2::decode_url_data(data_vec, FRAGMENT_LEN)
+            .or_else(|| RuuvitagDF2::decode_url_data(data_vec, FRAGMENT_LEN + 1))?;
 
         if let (
             Some(format), Some(hum), Some(temp_wholes), Some(temp_fract), Some(temp_sign),
-            Some(press), Some(id)) = (
+            Some(press)) = (
             RuuvitagDF2::get_data_format(&data), RuuvitagDF2::get_humidity(&data),
             RuuvitagDF2::get_temp_wholes(&data), RuuvitagDF2::get_temp_fractions(&data),
-            RuuvitagDF2::get_temp_sign(&data), RuuvitagDF2::get_pressure(&data),
-            RuuvitagDF2::get_id(&data)) {
+            RuuvitagDF2::get_temp_sign(&data), RuuvitagDF2::get_pressure(&data)) {
 
             let press_corr = 50000 + press as u32;
 
@@ -192,9 +225,9 @@ impl RuuvitagDF2 {
                 temperature_sign: temp_sign,
                 temperature_fractions: temp_fract,
                 pressure: press_corr,
-                id: id,
-                address: address,
-                tag: tag,
+                id: data.get(6).copied(),
+                address,
+                tag,
             };
             Some(meas)
         } else {
@@ -206,11 +239,10 @@ impl RuuvitagDF2 {
         self._get_measurements()
             .map(|m| {
                 format!(
-                    "temp {}°C\thumidity {:.1}%\tpressure {} Pa\nid {}\n",
+                    "temp {}°C\thumidity {:.1}%\tpressure {} Pa\n",
                     m.get_temperature_float(),
                     m.humidity,
                     m.pressure,
-                    m.id,
                 )
             })
     }
@@ -232,7 +264,8 @@ pub struct RuuvitagDF2Meas {
     temperature_sign: i8,
     temperature_fractions: u8,
     pressure: u32,
-    id: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u8>,
     address: String,
     tag: String,
 }
@@ -242,3 +275,61 @@ impl RuuvitagDF2Meas {
         (self.temperature as f64 + (self.temperature_fractions as f64 / 100.0)) * self.temperature_sign as f64
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+
+    fn url_frame(fragment: &str) -> Vec<u8> {
+        // Frame type, TX power and the https:// scheme.
+        let mut frame = vec![eddystone::URL_FRAME, 0xEE, 0x03];
+        frame.extend_from_slice(URL_PREFIX.as_bytes());
+        frame.extend_from_slice(fragment.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn decodes_url_fragments() {
+        let data = RuuvitagDF2::decode_url_data(&url_frame("AjwYAMFc"), FRAGMENT_LEN).unwrap();
+        assert_eq!(data, vec![0x02, 0x3C, 0x18, 0x00, 0xC1, 0x5C]);
+        // A data format 4 tag, the fragment is URL safe base64.
+        let data = RuuvitagDF2::decode_url_data(&url_frame("BEwYA-FsQ"), FRAGMENT_LEN + 1).unwrap();
+        assert_eq!(data, vec![0x04, 0x4C, 0x18, 0x03, 0xE1, 0x6C, 0x40]);
+        assert!(RuuvitagDF2::decode_url_data(&url_frame("BEwYA-FsQ"), FRAGMENT_LEN).is_none());
+        assert!(RuuvitagDF2::decode_url_data(&url_frame("AjwYA*Fc"), FRAGMENT_LEN).is_none());
+    }
+
+    fn sensor(fragment: &str) -> RuuvitagDF2 {
+        let mut svc_data = HashMap::new();
+        svc_data.insert(eddystone::SVC_DATA_UUID.to_string(), url_frame(fragment));
+        let device = BTDevice::new(
+            String::new(), String::new(),
+            None, Some(svc_data), 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        RuuvitagDF2::new(Rc::new(RefCell::new(device)))
+    }
+
+    #[test]
+    fn decodes_df2() {
+        // The example URL of the specification, https://ruu.vi/#AjwYAMFc.
+        let tag = sensor("AjwYAMFc");
+        assert!(tag.is_valid_data());
+        let meas = tag._get_measurements().unwrap();
+        assert_eq!(meas.data_format, 2);
+        assert_eq!(meas.humidity, 30.0);
+        assert_eq!(meas.get_temperature_float(), 24.0);
+        assert_eq!(meas.pressure, 99500);
+        assert_eq!(meas.id, None);
+
+        let meas = sensor("AjyYMsFc")._get_measurements().unwrap();
+        assert_eq!(meas.get_temperature_float(), -24.5);
+
+        // The nine character fragment is data format 4, it is decoded only for the
+        // devicemaps that name RuuvitagDF2.
+        let tag = sensor("BEwYAMFsQ");
+        assert!(!tag.is_valid_data());
+        assert_eq!(tag._get_measurements().unwrap().id, Some(0x40));
+    }
+
+}
@@ -6,7 +6,6 @@ use serde_json;
 
 use bt_sensor::{BTSensor, BTSensorConstructor, Value};
 use bt_device::BTDevice;
-use discovery_mode::DiscoveryMode;
 
 pub struct RuuvitagDF3Constructor;
 
@@ -20,8 +19,8 @@ impl BTSensorConstructor for RuuvitagDF3Constructor {
     fn get_name(&self) -> &'static str {
         "RuuvitagDF3"
     }
-    fn construct(&self, device: Rc<RefCell<BTDevice>>, discovery_mode: DiscoveryMode) -> Box<dyn BTSensor> {
-        Box::new(RuuvitagDF3::new(device, discovery_mode))
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(RuuvitagDF3::new(device))
     }
     fn is_valid_data(&self, device: &BTDevice) -> bool {
         RuuvitagDF3::_is_valid_data(device)
@@ -30,7 +29,6 @@ impl BTSensorConstructor for RuuvitagDF3Constructor {
 
 #[derive(Clone)]
 pub struct RuuvitagDF3 {
-    discovery_mode: DiscoveryMode,
     bt_device: Rc<RefCell<BTDevice>>,
 }
 
@@ -40,15 +38,15 @@ impl BTSensor for RuuvitagDF3 {
         RuuvitagDF3::_is_valid_data(&self.bt_device.borrow())
     }
 
-    fn get_measurements_json_str(&self) -> Option<String> {
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
         self._get_measurements_json_str()
     }
 
-    fn get_measurements_str(&self) -> Option<String> {
+    fn get_sensor_measurements_str(&self) -> Option<String> {
         self._get_measurements_str()
     }
 
-    fn get_measurements(&self) -> Option<HashMap<String, Value>> {
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
         match self._get_measurements() {
             Some(m) => {
                 let mut map = HashMap::<String, Value>::new();
@@ -90,7 +88,7 @@ impl BTSensor for RuuvitagDF3 {
         }
     }
 
-    fn get_bt_device(&self) -> Ref<BTDevice> {
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
         self.bt_device.borrow()
     }
 
@@ -112,8 +110,8 @@ static MFR_DATA_FIELD: u16 = 0x0499;
 
 impl RuuvitagDF3 {
 
-    pub fn new(bt_device: Rc<RefCell<BTDevice>>, discovery_mode: DiscoveryMode) -> RuuvitagDF3 {
-        RuuvitagDF3{bt_device, discovery_mode}
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> RuuvitagDF3 {
+        RuuvitagDF3{bt_device}
     }
 
     pub fn _is_valid_data(device: &BTDevice) -> bool {
@@ -136,8 +134,8 @@ impl RuuvitagDF3 {
         self.get_bt_device()
             .get_mfr_data()?
             .get(&MFR_DATA_FIELD)?
-            .get(0)
-            .map(|v| *v)
+            .first()
+            .copied()
     }
 
     pub fn get_humidity(&self) -> Option<f32> {
@@ -153,9 +151,7 @@ impl RuuvitagDF3 {
             .get_mfr_data()?
             .get(&MFR_DATA_FIELD)?
             .get(2)
-            .map(|raw_temp| {
-                (0x7F & raw_temp)
-            })
+            .map(|raw_temp| 0x7F & raw_temp)
     }
 
     pub fn get_temp_sign(&self) -> Option<i8> {
@@ -176,11 +172,7 @@ impl RuuvitagDF3 {
             .get_mfr_data()?
             .get(&MFR_DATA_FIELD)?
             .get(3)
-            .map(|v| *v)
-    }
-
-    pub fn get_temp_float(&self) -> Option<f64> {
-        self._get_measurements().map(|m| m.get_temperature_float())
+            .copied()
     }
 
     pub fn get_pressure(&self) -> Option<u16> {
@@ -298,8 +290,8 @@ impl RuuvitagDF3 {
                 acceleration_x: acc_x,
                 acceleration_y: acc_y,
                 acceleration_z: acc_z,
-                address: address,
-                tag: tag,
+                address,
+                tag,
             };
             Some(meas)
         } else {
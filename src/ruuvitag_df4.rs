@@ -0,0 +1,230 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Value};
+use bt_device::BTDevice;
+use ruuvitag_df2::RuuvitagDF2;
+use eddystone;
+
+pub struct RuuvitagDF4Constructor;
+
+impl RuuvitagDF4Constructor {
+    pub fn new() -> Box<RuuvitagDF4Constructor> {
+        Box::new(RuuvitagDF4Constructor{})
+    }
+}
+
+impl BTSensorConstructor for RuuvitagDF4Constructor {
+    fn get_name(&self) -> &'static str {
+        "RuuvitagDF4"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(RuuvitagDF4::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        RuuvitagDF4::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct RuuvitagDF4 {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for RuuvitagDF4 {
+
+    fn is_valid_data(&self) -> bool {
+        RuuvitagDF4::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements_str()
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements_json_str()
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        match self._get_measurements() {
+            Some(m) => {
+                let mut map = HashMap::<String, Value>::new();
+                map.insert(
+                    "humidity".to_string(),
+                    Value::Integer(m.humidity as i64),
+                );
+                map.insert(
+                    "temperature".to_string(),
+                    Value::Float(m.get_temperature_float()),
+                );
+                map.insert(
+                    "pressure".to_string(),
+                    Value::Integer(m.pressure as i64),
+                );
+                map.insert(
+                    "id".to_string(),
+                    Value::Integer(m.id as i64),
+                );
+                Some(map)
+            },
+            None => None,
+        }
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+const DATA_FORMAT: u8 = 4;
+const FRAGMENT_LEN: usize = 9;
+
+impl RuuvitagDF4 {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> RuuvitagDF4 {
+        RuuvitagDF4{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        eddystone::get_frame(device, eddystone::URL_FRAME)
+            .and_then(|data| RuuvitagDF2::decode_url_data(data, FRAGMENT_LEN))
+            .is_some_and(|data| RuuvitagDF2::get_data_format(&data) == Some(DATA_FORMAT))
+    }
+
+    // See https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_04.md
+    // for the specification. The layout is the same as in data format 2 with the random tag
+    // id as the last byte. Only the six most significant bits of the id are transmitted.
+    pub fn get_id(data: &[u8]) -> Option<u8> {
+        data.get(6).copied()
+    }
+
+    fn _get_measurements(&self) -> Option<RuuvitagDF4Meas> {
+        let device = self.get_bt_device();
+        let data_vec = eddystone::get_frame(&device, eddystone::URL_FRAME)?;
+
+        let data = RuuvitagDF2::decode_url_data(data_vec, FRAGMENT_LEN)?;
+
+        if let (
+            Some(format), Some(hum), Some(temp_wholes), Some(temp_fract), Some(temp_sign),
+            Some(press), Some(id)) = (
+            RuuvitagDF2::get_data_format(&data), RuuvitagDF2::get_humidity(&data),
+            RuuvitagDF2::get_temp_wholes(&data), RuuvitagDF2::get_temp_fractions(&data),
+            RuuvitagDF2::get_temp_sign(&data), RuuvitagDF2::get_pressure(&data),
+            RuuvitagDF4::get_id(&data)) {
+
+            let press_corr = 50000 + press as u32;
+
+            let meas = RuuvitagDF4Meas{
+                data_format: format,
+                humidity: hum,
+                temperature: temp_wholes,
+                temperature_sign: temp_sign,
+                temperature_fractions: temp_fract,
+                pressure: press_corr,
+                id,
+                address: device.get_address().to_string(),
+                tag: device.get_tag().to_string(),
+            };
+            Some(meas)
+        } else {
+            None
+        }
+    }
+
+    pub fn _get_measurements_str(&self) -> Option<String> {
+        self._get_measurements()
+            .map(|m| {
+                format!(
+                    "temp {}°C\thumidity {:.1}%\tpressure {} Pa\nid {}\n",
+                    m.get_temperature_float(),
+                    m.humidity,
+                    m.pressure,
+                    m.id,
+                )
+            })
+    }
+
+    fn _get_measurements_json_str(&self) -> Option<String> {
+        match self._get_measurements() {
+            Some(meas) => serde_json::to_string(&meas).ok(),
+            None => None
+        }
+    }
+
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct RuuvitagDF4Meas {
+    data_format: u8,
+    humidity: f32,
+    temperature: u8,
+    temperature_sign: i8,
+    temperature_fractions: u8,
+    pressure: u32,
+    id: u8,
+    address: String,
+    tag: String,
+}
+
+impl RuuvitagDF4Meas {
+    fn get_temperature_float(&self) -> f64 {
+        (self.temperature as f64 + (self.temperature_fractions as f64 / 100.0)) * self.temperature_sign as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+
+    fn sensor(fragment: &str) -> RuuvitagDF4 {
+        // Frame type, TX power and the https:// scheme.
+        let mut frame = vec![eddystone::URL_FRAME, 0xEE, 0x03];
+        frame.extend_from_slice(b"ruu.vi/#");
+        frame.extend_from_slice(fragment.as_bytes());
+        let mut svc_data = HashMap::new();
+        svc_data.insert(eddystone::SVC_DATA_UUID.to_string(), frame);
+        let device = BTDevice::new(
+            String::new(), String::new(),
+            None, Some(svc_data), 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        RuuvitagDF4::new(Rc::new(RefCell::new(device)))
+    }
+
+    #[test]
+    fn decodes_df4() {
+        // The data format 2 example of the specification with the data format 4 header,
+        // humidity 38% and the id 0x40.
+        let tag = sensor("BEwYAMFsQ");
+        assert!(tag.is_valid_data());
+        let meas = tag._get_measurements().unwrap();
+        assert_eq!(meas.data_format, 4);
+        assert_eq!(meas.humidity, 38.0);
+        assert_eq!(meas.get_temperature_float(), 24.0);
+        assert_eq!(meas.pressure, 99516);
+        // Only the six most significant bits of the id are sent.
+        assert_eq!(meas.id, 0x40);
+
+        // The eight character fragment of data format 2 has no id.
+        let tag = sensor("AjwYAMFc");
+        assert!(!tag.is_valid_data());
+        assert!(tag._get_measurements().is_none());
+    }
+
+}
@@ -0,0 +1,354 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{opt_str, BTSensor, BTSensorConstructor, Sequence, Value};
+use bt_device::BTDevice;
+
+pub struct RuuvitagDF5Constructor;
+
+impl RuuvitagDF5Constructor {
+    pub fn new() -> Box<RuuvitagDF5Constructor> {
+        Box::new(RuuvitagDF5Constructor{})
+    }
+}
+
+impl BTSensorConstructor for RuuvitagDF5Constructor {
+    fn get_name(&self) -> &'static str {
+        "RuuvitagDF5"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(RuuvitagDF5::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        RuuvitagDF5::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct RuuvitagDF5 {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for RuuvitagDF5 {
+
+    fn get_sequence(&self) -> Option<Sequence> {
+        self._get_measurements()?
+            .measurement_sequence
+            .map(|s| Sequence{value: s as u32, modulus: 0x10000})
+    }
+
+    fn is_valid_data(&self) -> bool {
+        RuuvitagDF5::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements().map(|m| m.get_measurements_str())
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        self._get_measurements().map(|m| m.get_values())
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+static MFR_DATA_FIELD: u16 = 0x0499;
+const DATA_FORMAT: u8 = 5;
+const DATA_LEN: usize = 24;
+
+impl RuuvitagDF5 {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> RuuvitagDF5 {
+        RuuvitagDF5{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        match device.get_mfr_data().and_then(|m| m.get(&MFR_DATA_FIELD)) {
+            Some(data) => data.len() == DATA_LEN && data[0] == DATA_FORMAT,
+            None => false,
+        }
+    }
+
+    // See https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_05.md
+    // for the specification. Every field has a reserved value that the tag uses when the
+    // measurement is not available, those are decoded as None.
+    pub fn u16_at(data: &[u8], index: usize) -> Option<u16> {
+        let top = data.get(index)?;
+        let bottom = data.get(index + 1)?;
+        Some(((*top as u16) << 8) | *bottom as u16)
+    }
+
+    pub fn decode_temperature(raw: u16) -> Option<f64> {
+        match raw {
+            0x8000 => None,
+            _ => Some(raw as i16 as f64 * 0.005),
+        }
+    }
+
+    pub fn decode_humidity(raw: u16) -> Option<f64> {
+        match raw {
+            0xFFFF => None,
+            _ => Some(raw as f64 * 0.0025),
+        }
+    }
+
+    pub fn decode_pressure(raw: u16) -> Option<u32> {
+        match raw {
+            0xFFFF => None,
+            _ => Some(50000 + raw as u32),
+        }
+    }
+
+    pub fn decode_acceleration(raw: u16) -> Option<i16> {
+        match raw {
+            0x8000 => None,
+            _ => Some(raw as i16),
+        }
+    }
+
+    /// Returns the battery voltage in millivolts and the TX power in dBm.
+    pub fn decode_power_info(raw: u16) -> (Option<u16>, Option<i8>) {
+        let battery = match raw >> 5 {
+            0x07FF => None,
+            b => Some(1600 + b),
+        };
+        let tx_power = match raw & 0x1F {
+            0x1F => None,
+            t => Some(-40 + 2 * t as i8),
+        };
+        (battery, tx_power)
+    }
+
+    pub fn decode_movement_counter(raw: u8) -> Option<u8> {
+        match raw {
+            0xFF => None,
+            _ => Some(raw),
+        }
+    }
+
+    pub fn decode_measurement_sequence(raw: u16) -> Option<u16> {
+        match raw {
+            0xFFFF => None,
+            _ => Some(raw),
+        }
+    }
+
+    pub fn decode_mac(data: &[u8]) -> Option<String> {
+        if data.len() != 6 || data.iter().all(|b| *b == 0xFF) {
+            return None;
+        }
+        let parts: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+        Some(parts.join(":"))
+    }
+
+    fn _get_measurements(&self) -> Option<RuuvitagDF5Meas> {
+        let device = self.get_bt_device();
+        let data = device
+            .get_mfr_data()?
+            .get(&MFR_DATA_FIELD)?;
+        if data.len() != DATA_LEN {
+            return None;
+        }
+
+        let (battery, tx_power) = RuuvitagDF5::decode_power_info(RuuvitagDF5::u16_at(data, 13)?);
+
+        let meas = RuuvitagDF5Meas{
+            data_format: data[0],
+            temperature: RuuvitagDF5::decode_temperature(RuuvitagDF5::u16_at(data, 1)?),
+            humidity: RuuvitagDF5::decode_humidity(RuuvitagDF5::u16_at(data, 3)?),
+            pressure: RuuvitagDF5::decode_pressure(RuuvitagDF5::u16_at(data, 5)?),
+            acceleration_x: RuuvitagDF5::decode_acceleration(RuuvitagDF5::u16_at(data, 7)?),
+            acceleration_y: RuuvitagDF5::decode_acceleration(RuuvitagDF5::u16_at(data, 9)?),
+            acceleration_z: RuuvitagDF5::decode_acceleration(RuuvitagDF5::u16_at(data, 11)?),
+            battery,
+            tx_power,
+            movement_counter: RuuvitagDF5::decode_movement_counter(data[15]),
+            measurement_sequence: RuuvitagDF5::decode_measurement_sequence(
+                RuuvitagDF5::u16_at(data, 16)?
+            ),
+            mac: RuuvitagDF5::decode_mac(&data[18..24]),
+            address: device.get_address().to_string(),
+            tag: device.get_tag().to_string(),
+        };
+        Some(meas)
+    }
+
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct RuuvitagDF5Meas {
+    pub data_format: u8,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub pressure: Option<u32>,
+    pub acceleration_x: Option<i16>,
+    pub acceleration_y: Option<i16>,
+    pub acceleration_z: Option<i16>,
+    pub battery: Option<u16>,
+    pub tx_power: Option<i8>,
+    pub movement_counter: Option<u8>,
+    pub measurement_sequence: Option<u16>,
+    pub mac: Option<String>,
+    pub address: String,
+    pub tag: String,
+}
+
+impl RuuvitagDF5Meas {
+
+    pub fn get_values(&self) -> HashMap<String, Value> {
+        let mut map = HashMap::<String, Value>::new();
+        if let Some(t) = self.temperature {
+            map.insert("temperature".to_string(), Value::Float(t));
+        }
+        // Humidity is written as integer by the older formats, keep the same field types so
+        // that the fields do not conflict in the database.
+        if let Some(h) = self.humidity {
+            map.insert("humidity".to_string(), Value::Integer(h.round() as i64));
+            map.insert("humidity_float".to_string(), Value::Float(h));
+        }
+        if let Some(p) = self.pressure {
+            map.insert("pressure".to_string(), Value::Integer(p as i64));
+        }
+        if let Some(a) = self.acceleration_x {
+            map.insert("acceleration_x".to_string(), Value::Integer(a as i64));
+        }
+        if let Some(a) = self.acceleration_y {
+            map.insert("acceleration_y".to_string(), Value::Integer(a as i64));
+        }
+        if let Some(a) = self.acceleration_z {
+            map.insert("acceleration_z".to_string(), Value::Integer(a as i64));
+        }
+        if let Some(b) = self.battery {
+            map.insert("battery".to_string(), Value::Integer(b as i64));
+        }
+        if let Some(t) = self.tx_power {
+            map.insert("tx_power".to_string(), Value::Integer(t as i64));
+        }
+        if let Some(m) = self.movement_counter {
+            map.insert("movement_counter".to_string(), Value::Integer(m as i64));
+        }
+        if let Some(s) = self.measurement_sequence {
+            map.insert("measurement_sequence".to_string(), Value::Integer(s as i64));
+        }
+        if let Some(ref mac) = self.mac {
+            map.insert("mac".to_string(), Value::String(mac.to_string()));
+        }
+        map
+    }
+
+    pub fn get_measurements_str(&self) -> String {
+        format!(
+            "battery {} mV\ttx power {} dBm\ntemp {}°C\thumidity {}%\tpressure {} Pa\n\
+             acc-x {}\tacc-y {}\tacc-z {}\nmovement {}\tsequence {}\tmac {}",
+            opt_str(&self.battery),
+            opt_str(&self.tx_power),
+            opt_str(&self.temperature.map(|t| format!("{:.3}", t))),
+            opt_str(&self.humidity.map(|h| format!("{:.2}", h))),
+            opt_str(&self.pressure),
+            opt_str(&self.acceleration_x),
+            opt_str(&self.acceleration_y),
+            opt_str(&self.acceleration_z),
+            opt_str(&self.movement_counter),
+            opt_str(&self.measurement_sequence),
+            opt_str(&self.mac),
+        )
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+    use hex;
+
+    // The decoded values have the float rounding errors of the scaling.
+    fn rounded(value: Option<f64>) -> Option<f64> {
+        value.map(|v| (v * 1000.0).round() / 1000.0)
+    }
+
+    fn measure(data: &str) -> RuuvitagDF5Meas {
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(MFR_DATA_FIELD, hex::decode(data).unwrap());
+        let device = BTDevice::new(
+            String::new(), String::new(),
+            Some(mfr_data), None, 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        let sensor = RuuvitagDF5::new(Rc::new(RefCell::new(device)));
+        assert!(sensor.is_valid_data());
+        sensor._get_measurements().unwrap()
+    }
+
+    // The test vectors of the specification.
+    #[test]
+    fn decodes_df5() {
+        let meas = measure("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F");
+        assert_eq!(rounded(meas.temperature), Some(24.3));
+        assert_eq!(rounded(meas.humidity), Some(53.49));
+        assert_eq!(meas.pressure, Some(100044));
+        assert_eq!(meas.acceleration_x, Some(4));
+        assert_eq!(meas.acceleration_y, Some(-4));
+        assert_eq!(meas.acceleration_z, Some(1036));
+        assert_eq!(meas.battery, Some(2977));
+        assert_eq!(meas.tx_power, Some(4));
+        assert_eq!(meas.movement_counter, Some(66));
+        assert_eq!(meas.measurement_sequence, Some(205));
+        assert_eq!(meas.mac.as_deref(), Some("CB:B8:33:4C:88:4F"));
+    }
+
+    #[test]
+    fn decodes_df5_max_values() {
+        let meas = measure("057FFFFFFEFFFE7FFF7FFF7FFFFFDEFEFFFECBB8334C884F");
+        assert_eq!(rounded(meas.temperature), Some(163.835));
+        assert_eq!(rounded(meas.humidity), Some(163.835));
+        assert_eq!(meas.pressure, Some(115534));
+        assert_eq!(meas.acceleration_x, Some(32767));
+        assert_eq!(meas.acceleration_y, Some(32767));
+        assert_eq!(meas.acceleration_z, Some(32767));
+        assert_eq!(meas.battery, Some(3646));
+        assert_eq!(meas.tx_power, Some(20));
+        assert_eq!(meas.movement_counter, Some(254));
+        assert_eq!(meas.measurement_sequence, Some(65534));
+    }
+
+    #[test]
+    fn decodes_df5_invalid_values() {
+        let meas = measure("058000FFFFFFFF800080008000FFFFFFFFFFFFFFFFFFFFFF");
+        assert_eq!(meas.temperature, None);
+        assert_eq!(meas.humidity, None);
+        assert_eq!(meas.pressure, None);
+        assert_eq!(meas.acceleration_x, None);
+        assert_eq!(meas.acceleration_y, None);
+        assert_eq!(meas.acceleration_z, None);
+        assert_eq!(meas.battery, None);
+        assert_eq!(meas.tx_power, None);
+        assert_eq!(meas.movement_counter, None);
+        assert_eq!(meas.measurement_sequence, None);
+        assert_eq!(meas.mac, None);
+        assert!(meas.get_values().is_empty());
+    }
+
+}
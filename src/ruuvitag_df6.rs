@@ -0,0 +1,196 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Sequence, Value};
+use bt_device::BTDevice;
+use ruuvitag_df5::RuuvitagDF5;
+use ruuvitag_dfe1::{RuuvitagDFE1, RuuviAirMeas};
+
+pub struct RuuvitagDF6Constructor;
+
+impl RuuvitagDF6Constructor {
+    pub fn new() -> Box<RuuvitagDF6Constructor> {
+        Box::new(RuuvitagDF6Constructor{})
+    }
+}
+
+impl BTSensorConstructor for RuuvitagDF6Constructor {
+    fn get_name(&self) -> &'static str {
+        "RuuvitagDF6"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(RuuvitagDF6::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        RuuvitagDF6::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct RuuvitagDF6 {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for RuuvitagDF6 {
+
+    /// Only the lowest byte of the sequence number is sent.
+    fn get_sequence(&self) -> Option<Sequence> {
+        self._get_measurements()?
+            .measurement_sequence
+            .map(|s| Sequence{value: s, modulus: 0x100})
+    }
+
+    fn is_valid_data(&self) -> bool {
+        RuuvitagDF6::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements().map(|m| m.get_measurements_str())
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        self._get_measurements().map(|m| m.get_values())
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+static MFR_DATA_FIELD: u16 = 0x0499;
+const DATA_FORMAT: u8 = 0x06;
+const DATA_LEN: usize = 20;
+
+impl RuuvitagDF6 {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> RuuvitagDF6 {
+        RuuvitagDF6{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        match device.get_mfr_data().and_then(|m| m.get(&MFR_DATA_FIELD)) {
+            Some(data) => data.len() == DATA_LEN && data[0] == DATA_FORMAT,
+            None => false,
+        }
+    }
+
+    /// Luminosity is compressed to one byte on a logarithmic scale from 0 to 65535 lux.
+    pub fn decode_luminosity(raw: u8) -> Option<f64> {
+        match raw {
+            0xFF => None,
+            _ => Some(((raw as f64) * (65536.0_f64).ln() / 254.0).exp() - 1.0),
+        }
+    }
+
+    // See https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-6 for the
+    // specification.
+    //
+    // 0       data format (0x06)
+    // 1-6     temperature, humidity and pressure as in data format 5
+    // 7-8     PM2.5
+    // 9-10    CO2
+    // 11-12   VOC and NOx index, LSBs in flag bits 6 and 7
+    // 13      luminosity
+    // 14      average sound level, LSB in flag bit 4
+    // 15      lowest byte of the measurement sequence number
+    // 16      flags, bit 0 is set while the sensors are calibrating
+    // 17-19   three lowest bytes of the MAC address
+    fn _get_measurements(&self) -> Option<RuuviAirMeas> {
+        let device = self.get_bt_device();
+        let data = device
+            .get_mfr_data()?
+            .get(&MFR_DATA_FIELD)?;
+        if data.len() != DATA_LEN {
+            return None;
+        }
+        let flags = data[16];
+        let mac_suffix: Vec<String> = data[17..20].iter().map(|b| format!("{:02X}", b)).collect();
+
+        let meas = RuuviAirMeas{
+            data_format: data[0],
+            temperature: RuuvitagDF5::decode_temperature(RuuvitagDF5::u16_at(data, 1)?),
+            humidity: RuuvitagDF5::decode_humidity(RuuvitagDF5::u16_at(data, 3)?),
+            pressure: RuuvitagDF5::decode_pressure(RuuvitagDF5::u16_at(data, 5)?),
+            pm2_5: RuuvitagDFE1::decode_pm(RuuvitagDF5::u16_at(data, 7)?),
+            co2: RuuvitagDFE1::decode_co2(RuuvitagDF5::u16_at(data, 9)?),
+            voc_index: RuuvitagDFE1::decode_index(
+                RuuvitagDFE1::nine_bit_value(data[11], flags, 6)
+            ),
+            nox_index: RuuvitagDFE1::decode_index(
+                RuuvitagDFE1::nine_bit_value(data[12], flags, 7)
+            ),
+            luminosity: RuuvitagDF6::decode_luminosity(data[13]),
+            sound_level_avg: RuuvitagDFE1::decode_sound_level(
+                RuuvitagDFE1::nine_bit_value(data[14], flags, 4)
+            ),
+            measurement_sequence: Some(data[15] as u32),
+            calibration_in_progress: flags & 0x01 != 0,
+            mac: Some(mac_suffix.join(":")),
+            address: device.get_address().to_string(),
+            tag: device.get_tag().to_string(),
+            ..Default::default()
+        };
+        Some(meas)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+    use hex;
+
+    // The decoded values have the float rounding errors of the scaling.
+    fn rounded(value: Option<f64>) -> Option<f64> {
+        value.map(|v| (v * 1000.0).round() / 1000.0)
+    }
+
+    #[test]
+    fn decodes_df6() {
+        // The example of the specification. Its sound level is not given, so byte 14 and
+        // flag bit 4 make it 87 here.
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(MFR_DATA_FIELD, hex::decode("06170C5668C79E007000C90501D92BCD104C884F").unwrap());
+        let device = BTDevice::new(
+            String::new(), String::new(),
+            Some(mfr_data), None, 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        let sensor = RuuvitagDF6::new(Rc::new(RefCell::new(device)));
+        let meas = sensor._get_measurements().unwrap();
+        assert_eq!(rounded(meas.temperature), Some(29.5));
+        assert_eq!(rounded(meas.humidity), Some(55.3));
+        assert_eq!(meas.pressure, Some(101102));
+        assert_eq!(rounded(meas.pm2_5), Some(11.2));
+        assert_eq!(meas.co2, Some(201));
+        assert_eq!(meas.voc_index, Some(10));
+        assert_eq!(meas.nox_index, Some(2));
+        assert_eq!(rounded(meas.luminosity), Some(13026.669));
+        assert_eq!(rounded(meas.sound_level_avg), Some(35.4));
+        assert_eq!(meas.measurement_sequence, Some(205));
+        assert!(!meas.calibration_in_progress);
+        assert_eq!(meas.mac.as_deref(), Some("4C:88:4F"));
+    }
+
+}
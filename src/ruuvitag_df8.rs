@@ -0,0 +1,280 @@
+use std::rc::Rc;
+use std::cell::{Cell, RefCell, Ref};
+use std::collections::HashMap;
+
+use aes::Aes128;
+use aes::cipher::{BlockDecrypt, KeyInit, generic_array::GenericArray};
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Sequence, Value};
+use bt_device::BTDevice;
+use error::DecodeError;
+use ruuvitag_df5::{RuuvitagDF5, RuuvitagDF5Meas};
+
+pub struct RuuvitagDF8Constructor;
+
+impl RuuvitagDF8Constructor {
+    pub fn new() -> Box<RuuvitagDF8Constructor> {
+        Box::new(RuuvitagDF8Constructor{})
+    }
+}
+
+impl BTSensorConstructor for RuuvitagDF8Constructor {
+    fn get_name(&self) -> &'static str {
+        "RuuvitagDF8"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(RuuvitagDF8::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        RuuvitagDF8::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct RuuvitagDF8 {
+    bt_device: Rc<RefCell<BTDevice>>,
+    // The measurements are read several times per advertisement, so a failing tag is logged
+    // only when it starts and stops failing.
+    failing: Cell<bool>,
+}
+
+impl BTSensor for RuuvitagDF8 {
+
+    fn get_sequence(&self) -> Option<Sequence> {
+        self._get_measurements()?
+            .measurement_sequence
+            .map(|s| Sequence{value: s as u32, modulus: 0x10000})
+    }
+
+    fn is_valid_data(&self) -> bool {
+        RuuvitagDF8::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements().map(|m| m.get_measurements_str())
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        self._get_measurements().map(|m| m.get_values())
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+static MFR_DATA_FIELD: u16 = 0x0499;
+const DATA_FORMAT: u8 = 8;
+const DATA_LEN: usize = 24;
+const KEY_LEN: usize = 16;
+
+impl RuuvitagDF8 {
+
+    /// A tag without a key is reported here once, its measurements are skipped.
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> RuuvitagDF8 {
+        if bt_device.borrow().get_key().is_none() {
+            warn!(
+                "No key configured in the devicemap for the data format 8 tag {}",
+                bt_device.borrow().get_address(),
+            );
+        }
+        RuuvitagDF8{bt_device, failing: Cell::new(false)}
+    }
+
+    // The data is recognized without the key so that tags with a missing or a wrong key get
+    // reported.
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        match device.get_mfr_data().and_then(|m| m.get(&MFR_DATA_FIELD)) {
+            Some(data) => data.len() == DATA_LEN && data[0] == DATA_FORMAT,
+            None => false,
+        }
+    }
+
+    /// CRC-8 with polynomial 0x07 and zero initial value.
+    pub fn crc8(data: &[u8]) -> u8 {
+        let mut crc: u8 = 0;
+        for byte in data {
+            crc ^= *byte;
+            for _ in 0..8 {
+                crc = if crc & 0x80 != 0 {
+                    (crc << 1) ^ 0x07
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+
+    // See https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-8 for the
+    // specification. Bytes 1-16 are one AES-128-ECB encrypted block, byte 17 is the CRC8 of
+    // the decrypted block and bytes 18-23 are the MAC address in plain text.
+    pub fn decrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        if data.len() != DATA_LEN {
+            return Err(DecodeError::new(format!("Expected {} bytes, got {}", DATA_LEN, data.len())));
+        }
+        if key.len() != KEY_LEN {
+            return Err(DecodeError::new(format!("Expected {} byte AES key, got {} bytes", KEY_LEN, key.len())));
+        }
+        let cipher = Aes128::new_from_slice(key)
+            .map_err(|_| DecodeError::new("Invalid AES key".to_string()))?;
+        let mut block = GenericArray::clone_from_slice(&data[1..17]);
+        cipher.decrypt_block(&mut block);
+        if RuuvitagDF8::crc8(&block) != data[17] {
+            return Err(DecodeError::new("CRC mismatch, the key is probably wrong".to_string()));
+        }
+        Ok(block.to_vec())
+    }
+
+    fn _get_measurements(&self) -> Option<RuuvitagDF5Meas> {
+        let device = self.get_bt_device();
+        let data = device
+            .get_mfr_data()?
+            .get(&MFR_DATA_FIELD)?;
+
+        let key = device.get_key()?;
+        let payload = match RuuvitagDF8::decrypt(data, key) {
+            Ok(p) => p,
+            Err(e) => {
+                if !self.failing.replace(true) {
+                    error!("Cannot decode data format 8 from {}: {}", device.get_address(), e);
+                }
+                return None;
+            },
+        };
+        if self.failing.replace(false) {
+            info!("Data format 8 from {} decodes again", device.get_address());
+        }
+
+        let (battery, tx_power) = RuuvitagDF5::decode_power_info(RuuvitagDF5::u16_at(&payload, 6)?);
+
+        let meas = RuuvitagDF5Meas{
+            data_format: data[0],
+            temperature: RuuvitagDF5::decode_temperature(RuuvitagDF5::u16_at(&payload, 0)?),
+            humidity: RuuvitagDF5::decode_humidity(RuuvitagDF5::u16_at(&payload, 2)?),
+            pressure: RuuvitagDF5::decode_pressure(RuuvitagDF5::u16_at(&payload, 4)?),
+            acceleration_x: None,
+            acceleration_y: None,
+            acceleration_z: None,
+            battery,
+            tx_power,
+            movement_counter: RuuvitagDF5::decode_movement_counter(payload[8]),
+            measurement_sequence: RuuvitagDF5::decode_measurement_sequence(
+                RuuvitagDF5::u16_at(&payload, 9)?
+            ),
+            mac: RuuvitagDF5::decode_mac(&data[18..24]),
+            address: device.get_address().to_string(),
+            tag: device.get_tag().to_string(),
+        };
+        Some(meas)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+    use aes::cipher::BlockEncrypt;
+    use hex;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f";
+    // 24.3 °C, 53.49 %, 100044 Pa, 2977 mV and 4 dBm, movement 66, sequence 205, then
+    // padding. The encrypted block is checked against openssl enc -aes-128-ecb -nopad.
+    const PLAIN: &str = "12fc5394c37cac364200cd0000000000";
+    const ENCRYPTED: &str = "c6b0f8cfb51b39ac3551741dc04dd9d1";
+
+    fn advertisement(key: &[u8]) -> Vec<u8> {
+        let plain = hex::decode(PLAIN).unwrap();
+        let mut block = GenericArray::clone_from_slice(&plain);
+        Aes128::new_from_slice(key).unwrap().encrypt_block(&mut block);
+        let mut data = vec![DATA_FORMAT];
+        data.extend_from_slice(&block);
+        data.push(RuuvitagDF8::crc8(&plain));
+        data.extend_from_slice(&hex::decode("CBB8334C884F").unwrap());
+        data
+    }
+
+    #[test]
+    fn crc8_check_value() {
+        assert_eq!(RuuvitagDF8::crc8(b"123456789"), 0xF4);
+    }
+
+    #[test]
+    fn decrypts_known_block() {
+        let key = hex::decode(KEY).unwrap();
+        let data = advertisement(&key);
+        assert_eq!(hex::encode(&data[1..17]), ENCRYPTED);
+        assert_eq!(data[17], 0xBC);
+        assert_eq!(hex::encode(RuuvitagDF8::decrypt(&data, &key).unwrap()), PLAIN);
+
+        let mut wrong_key = key.clone();
+        wrong_key[15] ^= 1;
+        assert!(RuuvitagDF8::decrypt(&data, &wrong_key).is_err());
+        assert!(RuuvitagDF8::decrypt(&data, &key[..15]).is_err());
+    }
+
+    #[test]
+    fn decodes_measurements() {
+        let key = hex::decode(KEY).unwrap();
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(MFR_DATA_FIELD, advertisement(&key));
+        let device = Rc::new(RefCell::new(BTDevice::new(
+            "CB:B8:33:4C:88:4F".to_string(), String::new(),
+            Some(mfr_data), None, 0, Duration::from_secs(0), DiscoveryMode::Auto)));
+        assert!(RuuvitagDF8::_is_valid_data(&device.borrow()));
+        let sensor = RuuvitagDF8::new(device.clone());
+        assert!(sensor._get_measurements().is_none());
+
+        device.borrow_mut().set_key(Some(key));
+        let meas = sensor._get_measurements().unwrap();
+        assert_eq!(meas.temperature, Some(24.3));
+        assert_eq!(meas.humidity, Some(53.49));
+        assert_eq!(meas.pressure, Some(100044));
+        assert_eq!(meas.measurement_sequence, Some(205));
+        assert_eq!(meas.mac.as_deref(), Some("CB:B8:33:4C:88:4F"));
+        assert_eq!(sensor.get_sequence().map(|s| s.value), Some(205));
+    }
+
+    #[test]
+    fn reports_failing_tag_once() {
+        let key = hex::decode(KEY).unwrap();
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(MFR_DATA_FIELD, advertisement(&key));
+        let device = Rc::new(RefCell::new(BTDevice::new(
+            "CB:B8:33:4C:88:4F".to_string(), String::new(),
+            Some(mfr_data), None, 0, Duration::from_secs(0), DiscoveryMode::Auto)));
+        let mut wrong_key = key.clone();
+        wrong_key[0] ^= 1;
+        device.borrow_mut().set_key(Some(wrong_key));
+        let sensor = RuuvitagDF8::new(device.clone());
+        assert!(sensor._get_measurements().is_none());
+        assert!(sensor.failing.get());
+        assert!(sensor.get_sequence().is_none());
+
+        device.borrow_mut().set_key(Some(key));
+        assert!(sensor._get_measurements().is_some());
+        assert!(!sensor.failing.get());
+    }
+
+}
@@ -0,0 +1,399 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{opt_str, BTSensor, BTSensorConstructor, Sequence, Value};
+use bt_device::BTDevice;
+use ruuvitag_df5::RuuvitagDF5;
+
+pub struct RuuvitagDFE1Constructor;
+
+impl RuuvitagDFE1Constructor {
+    pub fn new() -> Box<RuuvitagDFE1Constructor> {
+        Box::new(RuuvitagDFE1Constructor{})
+    }
+}
+
+impl BTSensorConstructor for RuuvitagDFE1Constructor {
+    fn get_name(&self) -> &'static str {
+        "RuuvitagDFE1"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(RuuvitagDFE1::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        RuuvitagDFE1::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct RuuvitagDFE1 {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for RuuvitagDFE1 {
+
+    fn get_sequence(&self) -> Option<Sequence> {
+        self._get_measurements()?
+            .measurement_sequence
+            .map(|s| Sequence{value: s, modulus: 0x1000000})
+    }
+
+    fn is_valid_data(&self) -> bool {
+        RuuvitagDFE1::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements().map(|m| m.get_measurements_str())
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        self._get_measurements().map(|m| m.get_values())
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+static MFR_DATA_FIELD: u16 = 0x0499;
+const DATA_FORMAT: u8 = 0xE1;
+const DATA_LEN: usize = 40;
+
+impl RuuvitagDFE1 {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> RuuvitagDFE1 {
+        RuuvitagDFE1{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        match device.get_mfr_data().and_then(|m| m.get(&MFR_DATA_FIELD)) {
+            Some(data) => data.len() == DATA_LEN && data[0] == DATA_FORMAT,
+            None => false,
+        }
+    }
+
+    pub fn u24_at(data: &[u8], index: usize) -> Option<u32> {
+        let top = *data.get(index)? as u32;
+        let middle = *data.get(index + 1)? as u32;
+        let bottom = *data.get(index + 2)? as u32;
+        Some((top << 16) | (middle << 8) | bottom)
+    }
+
+    /// PM concentrations are sent in 0.1 µg/m³ units.
+    pub fn decode_pm(raw: u16) -> Option<f64> {
+        match raw {
+            0xFFFF => None,
+            _ => Some(raw as f64 / 10.0),
+        }
+    }
+
+    pub fn decode_co2(raw: u16) -> Option<u16> {
+        match raw {
+            0xFFFF => None,
+            _ => Some(raw),
+        }
+    }
+
+    /// The nine bit values are sent as the eight most significant bits in their own byte and
+    /// the least significant bit in the flags byte.
+    pub fn nine_bit_value(msb: u8, flags: u8, flag_bit: u8) -> u16 {
+        ((msb as u16) << 1) | ((flags >> flag_bit) & 1) as u16
+    }
+
+    /// VOC and NOx indexes are nine bit values.
+    pub fn decode_index(raw: u16) -> Option<u16> {
+        match raw {
+            0x01FF => None,
+            _ => Some(raw),
+        }
+    }
+
+    /// Sound levels are nine bit values in 0.2 dBA steps starting from 18 dBA.
+    pub fn decode_sound_level(raw: u16) -> Option<f64> {
+        match raw {
+            0x01FF => None,
+            _ => Some(18.0 + raw as f64 * 0.2),
+        }
+    }
+
+    pub fn decode_luminosity(raw: u32) -> Option<f64> {
+        match raw {
+            0xFFFFFF => None,
+            _ => Some(raw as f64 / 100.0),
+        }
+    }
+
+    pub fn decode_measurement_sequence(raw: u32) -> Option<u32> {
+        match raw {
+            0xFFFFFF => None,
+            _ => Some(raw),
+        }
+    }
+
+    // See https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-e1 for
+    // the specification.
+    //
+    // 0       data format (0xE1)
+    // 1-6     temperature, humidity and pressure as in data format 5
+    // 7-14    PM1.0, PM2.5, PM4.0 and PM10.0
+    // 15-16   CO2
+    // 17-18   VOC and NOx index, LSBs in flag bits 6 and 7
+    // 19-21   luminosity
+    // 22-24   instant, average and peak sound level, LSBs in flag bits 3, 4 and 5
+    // 25-27   measurement sequence number
+    // 28      flags, bit 0 is set while the sensors are calibrating
+    // 29-33   reserved
+    // 34-39   MAC address
+    fn _get_measurements(&self) -> Option<RuuviAirMeas> {
+        let device = self.get_bt_device();
+        let data = device
+            .get_mfr_data()?
+            .get(&MFR_DATA_FIELD)?;
+        if data.len() != DATA_LEN {
+            return None;
+        }
+        let flags = data[28];
+
+        let meas = RuuviAirMeas{
+            data_format: data[0],
+            temperature: RuuvitagDF5::decode_temperature(RuuvitagDF5::u16_at(data, 1)?),
+            humidity: RuuvitagDF5::decode_humidity(RuuvitagDF5::u16_at(data, 3)?),
+            pressure: RuuvitagDF5::decode_pressure(RuuvitagDF5::u16_at(data, 5)?),
+            pm1_0: RuuvitagDFE1::decode_pm(RuuvitagDF5::u16_at(data, 7)?),
+            pm2_5: RuuvitagDFE1::decode_pm(RuuvitagDF5::u16_at(data, 9)?),
+            pm4_0: RuuvitagDFE1::decode_pm(RuuvitagDF5::u16_at(data, 11)?),
+            pm10_0: RuuvitagDFE1::decode_pm(RuuvitagDF5::u16_at(data, 13)?),
+            co2: RuuvitagDFE1::decode_co2(RuuvitagDF5::u16_at(data, 15)?),
+            voc_index: RuuvitagDFE1::decode_index(
+                RuuvitagDFE1::nine_bit_value(data[17], flags, 6)
+            ),
+            nox_index: RuuvitagDFE1::decode_index(
+                RuuvitagDFE1::nine_bit_value(data[18], flags, 7)
+            ),
+            luminosity: RuuvitagDFE1::decode_luminosity(RuuvitagDFE1::u24_at(data, 19)?),
+            sound_level_instant: RuuvitagDFE1::decode_sound_level(
+                RuuvitagDFE1::nine_bit_value(data[22], flags, 3)
+            ),
+            sound_level_avg: RuuvitagDFE1::decode_sound_level(
+                RuuvitagDFE1::nine_bit_value(data[23], flags, 4)
+            ),
+            sound_level_peak: RuuvitagDFE1::decode_sound_level(
+                RuuvitagDFE1::nine_bit_value(data[24], flags, 5)
+            ),
+            measurement_sequence: RuuvitagDFE1::decode_measurement_sequence(
+                RuuvitagDFE1::u24_at(data, 25)?
+            ),
+            calibration_in_progress: flags & 0x01 != 0,
+            mac: RuuvitagDF5::decode_mac(&data[34..40]),
+            address: device.get_address().to_string(),
+            tag: device.get_tag().to_string(),
+        };
+        Some(meas)
+    }
+
+}
+
+/// Measurements of the Ruuvi Air formats. The shorter data format 6 leaves out the fields
+/// that do not fit in a legacy advertisement.
+#[derive(Default, Debug, Serialize)]
+pub struct RuuviAirMeas {
+    pub data_format: u8,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub pressure: Option<u32>,
+    pub pm1_0: Option<f64>,
+    pub pm2_5: Option<f64>,
+    pub pm4_0: Option<f64>,
+    pub pm10_0: Option<f64>,
+    pub co2: Option<u16>,
+    pub voc_index: Option<u16>,
+    pub nox_index: Option<u16>,
+    pub luminosity: Option<f64>,
+    pub sound_level_instant: Option<f64>,
+    pub sound_level_avg: Option<f64>,
+    pub sound_level_peak: Option<f64>,
+    pub measurement_sequence: Option<u32>,
+    pub calibration_in_progress: bool,
+    pub mac: Option<String>,
+    pub address: String,
+    pub tag: String,
+}
+
+impl RuuviAirMeas {
+
+    pub fn get_values(&self) -> HashMap<String, Value> {
+        let mut map = HashMap::<String, Value>::new();
+        if let Some(t) = self.temperature {
+            map.insert("temperature".to_string(), Value::Float(t));
+        }
+        if let Some(h) = self.humidity {
+            map.insert("humidity".to_string(), Value::Integer(h.round() as i64));
+            map.insert("humidity_float".to_string(), Value::Float(h));
+        }
+        if let Some(p) = self.pressure {
+            map.insert("pressure".to_string(), Value::Integer(p as i64));
+        }
+        let floats = vec![
+            ("pm1_0", self.pm1_0),
+            ("pm2_5", self.pm2_5),
+            ("pm4_0", self.pm4_0),
+            ("pm10_0", self.pm10_0),
+            ("luminosity", self.luminosity),
+            ("sound_level_instant", self.sound_level_instant),
+            ("sound_level_avg", self.sound_level_avg),
+            ("sound_level_peak", self.sound_level_peak),
+        ];
+        for (key, val) in floats {
+            if let Some(v) = val {
+                map.insert(key.to_string(), Value::Float(v));
+            }
+        }
+        let integers = vec![
+            ("co2", self.co2.map(|v| v as i64)),
+            ("voc_index", self.voc_index.map(|v| v as i64)),
+            ("nox_index", self.nox_index.map(|v| v as i64)),
+            ("measurement_sequence", self.measurement_sequence.map(|v| v as i64)),
+        ];
+        for (key, val) in integers {
+            if let Some(v) = val {
+                map.insert(key.to_string(), Value::Integer(v));
+            }
+        }
+        map.insert(
+            "calibration_in_progress".to_string(),
+            Value::Boolean(self.calibration_in_progress),
+        );
+        if let Some(ref mac) = self.mac {
+            map.insert("mac".to_string(), Value::String(mac.to_string()));
+        }
+        map
+    }
+
+    pub fn get_measurements_str(&self) -> String {
+        format!(
+            "temp {}°C\thumidity {}%\tpressure {} Pa\n\
+             pm1.0 {}\tpm2.5 {}\tpm4.0 {}\tpm10.0 {} µg/m³\n\
+             co2 {} ppm\tvoc {}\tnox {}\tluminosity {} lx\n\
+             sound {} / {} / {} dBA\tsequence {}\tcalibrating {}",
+            opt_str(&self.temperature.map(|t| format!("{:.3}", t))),
+            opt_str(&self.humidity.map(|h| format!("{:.2}", h))),
+            opt_str(&self.pressure),
+            opt_str(&self.pm1_0),
+            opt_str(&self.pm2_5),
+            opt_str(&self.pm4_0),
+            opt_str(&self.pm10_0),
+            opt_str(&self.co2),
+            opt_str(&self.voc_index),
+            opt_str(&self.nox_index),
+            opt_str(&self.luminosity.map(|l| format!("{:.2}", l))),
+            opt_str(&self.sound_level_instant.map(|s| format!("{:.1}", s))),
+            opt_str(&self.sound_level_avg.map(|s| format!("{:.1}", s))),
+            opt_str(&self.sound_level_peak.map(|s| format!("{:.1}", s))),
+            opt_str(&self.measurement_sequence),
+            self.calibration_in_progress,
+        )
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discovery_mode::DiscoveryMode;
+    use std::time::Duration;
+    use hex;
+
+    // The decoded values have the float rounding errors of the scaling.
+    fn rounded(value: Option<f64>) -> Option<f64> {
+        value.map(|v| (v * 1000.0).round() / 1000.0)
+    }
+
+    fn sensor(data: &str) -> RuuvitagDFE1 {
+        let mut mfr_data = HashMap::new();
+        mfr_data.insert(MFR_DATA_FIELD, hex::decode(data).unwrap());
+        let device = BTDevice::new(
+            String::new(), String::new(),
+            Some(mfr_data), None, 0, Duration::from_secs(0), DiscoveryMode::Auto);
+        RuuvitagDFE1::new(Rc::new(RefCell::new(device)))
+    }
+
+    #[test]
+    fn extracts_nine_bit_values() {
+        assert_eq!(RuuvitagDFE1::nine_bit_value(0x0A, 0x00, 6), 20);
+        assert_eq!(RuuvitagDFE1::nine_bit_value(0x0A, 0x40, 6), 21);
+        assert_eq!(RuuvitagDFE1::nine_bit_value(0x0A, 0xBF, 6), 20);
+        assert_eq!(RuuvitagDFE1::nine_bit_value(0xFF, 0x80, 7), 0x1FF);
+        assert_eq!(RuuvitagDFE1::decode_index(0x1FF), None);
+        assert_eq!(RuuvitagDFE1::decode_sound_level(0x1FF), None);
+        assert_eq!(RuuvitagDFE1::decode_sound_level(85), Some(35.0));
+    }
+
+    #[test]
+    fn decodes_e1() {
+        // The example of the specification. Its sound levels are not given, so bytes 22-24
+        // and the flag bits 3-5 make them 85, 86 and 89 here.
+        let meas = sensor(concat!(
+            "E1170C5668C79E0065007004BD11CA00C90A0213E0AC",
+            "2A2B2C", "DECDEE", "28", "FFFFFFFFFF", "CBB8334C884F",
+        ))._get_measurements().unwrap();
+        assert_eq!(rounded(meas.temperature), Some(29.5));
+        assert_eq!(rounded(meas.humidity), Some(55.3));
+        assert_eq!(meas.pressure, Some(101102));
+        assert_eq!(rounded(meas.pm1_0), Some(10.1));
+        assert_eq!(rounded(meas.pm2_5), Some(11.2));
+        assert_eq!(rounded(meas.pm4_0), Some(121.3));
+        assert_eq!(rounded(meas.pm10_0), Some(455.4));
+        assert_eq!(meas.co2, Some(201));
+        assert_eq!(meas.voc_index, Some(20));
+        assert_eq!(meas.nox_index, Some(4));
+        assert_eq!(rounded(meas.luminosity), Some(13027.0));
+        assert_eq!(rounded(meas.sound_level_instant), Some(35.0));
+        assert_eq!(rounded(meas.sound_level_avg), Some(35.2));
+        assert_eq!(rounded(meas.sound_level_peak), Some(35.8));
+        assert_eq!(meas.measurement_sequence, Some(14601710));
+        assert!(!meas.calibration_in_progress);
+        assert_eq!(meas.mac.as_deref(), Some("CB:B8:33:4C:88:4F"));
+    }
+
+    #[test]
+    fn decodes_e1_invalid_values() {
+        let meas = sensor(concat!(
+            "E18000FFFFFFFF", "FFFFFFFFFFFFFFFF", "FFFF", "FFFF", "FFFFFF",
+            "FFFFFF", "FFFFFF", "F9", "FFFFFFFFFF", "CBB8334C884F",
+        ))._get_measurements().unwrap();
+        assert_eq!(meas.temperature, None);
+        assert_eq!(meas.humidity, None);
+        assert_eq!(meas.pressure, None);
+        assert_eq!(meas.pm2_5, None);
+        assert_eq!(meas.co2, None);
+        assert_eq!(meas.voc_index, None);
+        assert_eq!(meas.nox_index, None);
+        assert_eq!(meas.luminosity, None);
+        assert_eq!(meas.sound_level_peak, None);
+        assert_eq!(meas.measurement_sequence, None);
+        assert!(meas.calibration_in_progress);
+        assert!(sensor("E1170C5668")._get_measurements().is_none());
+    }
+
+}
@@ -0,0 +1,442 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backend::Backend;
+use config;
+use consumer::Consumer;
+use device_store::DeviceStore;
+use error::SimulatorError;
+use hci::{self, Advertisement};
+use recording::Recorder;
+use timestamp::unix_timestamp;
+
+type BoxErr = Box<dyn error::Error>;
+
+// The chances are per advertisement. At the default interval of one second a tag moves about
+// every eight minutes and is out of range for a while about twice an hour.
+const DROP_PROBABILITY: f64 = 0.05;
+const DUPLICATE_PROBABILITY: f64 = 0.03;
+const OUTAGE_PROBABILITY: f64 = 0.0005;
+const MOVEMENT_PROBABILITY: f64 = 0.002;
+// Millivolts per advertisement, far faster than a real tag so that the drain shows in a demo.
+const BATTERY_DRAIN: f64 = 0.002;
+const BATTERY_EMPTY: f64 = 1800.;
+// The advertisers add a random delay of up to 10 ms to every interval.
+const MAX_ADV_DELAY_MS: f64 = 10.;
+
+const ADAPTER: &str = "simulator";
+const RUUVI_COMPANY_ID: [u8; 2] = [0x99, 0x04];
+const BTHOME_UUID16: [u8; 2] = [0xD2, 0xFC];
+const BTHOME_V2: u8 = 0x40;
+const DF3_LEN: usize = 18;
+const DF5_TX_POWER: i8 = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TagFormat {
+    DF3,
+    DF5,
+    BTHome,
+}
+
+/// Parses a comma separated list of df3, df5 and bthome.
+pub fn parse_formats(formats: &str) -> Result<Vec<TagFormat>, BoxErr> {
+    formats.split(',')
+        .map(|f| match f.trim() {
+            "df3" => Ok(TagFormat::DF3),
+            "df5" => Ok(TagFormat::DF5),
+            "bthome" => Ok(TagFormat::BTHome),
+            f => Err(Box::new(SimulatorError::new(format!("Unknown tag format {}", f))) as BoxErr),
+        })
+        .collect()
+}
+
+/// Xorshift64*, the same seed gives the same tags and the same readings.
+struct Rng(u64);
+
+impl Rng {
+
+    fn new(seed: u64) -> Rng {
+        // Zero would stay zero.
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in [0, 1).
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn range(&mut self, low: f64, high: f64) -> f64 {
+        low + (high - low) * self.uniform()
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.uniform() < probability
+    }
+
+    /// Roughly normal noise with the standard deviation.
+    fn noise(&mut self, deviation: f64) -> f64 {
+        let sum: f64 = (0..12).map(|_| self.uniform()).sum();
+        (sum - 6.) * deviation
+    }
+
+}
+
+/// The state of one virtual tag. The measurements drift around the values the tag started
+/// with, like the readings of a tag in a room.
+struct VirtualTag {
+    address: [u8; 6],
+    format: TagFormat,
+    temperature: f64,
+    base_temperature: f64,
+    humidity: f64,
+    base_humidity: f64,
+    pressure: f64,
+    resting_acceleration: [f64; 3],
+    acceleration: [f64; 3],
+    // Advertisements left of the current movement.
+    moving: u32,
+    movement_counter: u8,
+    sequence: u16,
+    battery: f64,
+    rssi: f64,
+    next_due: Instant,
+    silent_until: Option<Instant>,
+}
+
+impl VirtualTag {
+
+    fn new(rng: &mut Rng, index: usize, format: TagFormat, interval: Duration) -> VirtualTag {
+        // A random static address has the two highest bits set. The index keeps them unique.
+        let random = rng.next_u64().to_be_bytes();
+        let address = [random[0] | 0xC0, random[1], random[2], random[3], (index >> 8) as u8, index as u8];
+        let base_temperature = rng.range(15., 25.);
+        let base_humidity = rng.range(30., 60.);
+        // Lying a little tilted, the acceleration is 1 g in total.
+        let x = rng.range(-200., 200.);
+        let y = rng.range(-200., 200.);
+        let resting_acceleration = [x, y, (1000f64.powi(2) - x * x - y * y).sqrt()];
+        // The tags start spread over the first interval.
+        let offset = interval.mul_f64(rng.uniform());
+        VirtualTag{
+            address,
+            format,
+            temperature: base_temperature,
+            base_temperature,
+            humidity: base_humidity,
+            base_humidity,
+            pressure: rng.range(99000., 103000.),
+            resting_acceleration,
+            acceleration: resting_acceleration,
+            moving: 0,
+            movement_counter: 0,
+            // 0xFFFF is the reserved value of the sequence number.
+            sequence: (rng.next_u64() % 0xFFFF) as u16,
+            battery: rng.range(2900., 3100.),
+            rssi: rng.range(-90., -50.),
+            next_due: Instant::now() + offset,
+            silent_until: None,
+        }
+    }
+
+    /// Takes the next measurement.
+    fn step(&mut self, rng: &mut Rng) {
+        self.temperature += (self.base_temperature - self.temperature) * 0.002 + rng.noise(0.02);
+        self.humidity += (self.base_humidity - self.humidity) * 0.002 + rng.noise(0.05);
+        self.humidity = self.humidity.clamp(0., 100.);
+        self.pressure += (101325. - self.pressure) * 0.0005 + rng.noise(2.);
+        self.battery = (self.battery - BATTERY_DRAIN).max(BATTERY_EMPTY);
+        self.sequence = match self.sequence {
+            0xFFFE | 0xFFFF => 0,
+            s => s + 1,
+        };
+
+        if self.moving > 0 {
+            self.moving -= 1;
+        } else if rng.chance(MOVEMENT_PROBABILITY) {
+            self.moving = rng.range(3., 10.) as u32;
+            // 0xFF is the reserved value of the movement counter.
+            self.movement_counter = match self.movement_counter {
+                0xFE => 0,
+                c => c + 1,
+            };
+        }
+        let deviation = if self.moving > 0 { 400. } else { 8. };
+        for (acc, resting) in self.acceleration.iter_mut().zip(self.resting_acceleration.iter()) {
+            *acc = resting + rng.noise(deviation);
+        }
+    }
+
+    /// The address is kept most significant byte first, the way DF5 carries it.
+    fn address_string(&self) -> String {
+        let parts: Vec<String> = self.address.iter().map(|b| format!("{:02X}", b)).collect();
+        parts.join(":")
+    }
+
+    fn battery_percent(&self) -> u8 {
+        ((self.battery - 2000.) / 10.).clamp(0., 100.) as u8
+    }
+
+    /// The data format 3 of RuuviTag, see
+    /// https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_03.md
+    fn df3(&self) -> Vec<u8> {
+        let mut data = vec![3, (self.humidity * 2.).round() as u8];
+        let temperature = self.temperature.abs().min(127.99);
+        let sign = if self.temperature < 0. { 0x80 } else { 0 };
+        data.push(sign | temperature.trunc() as u8);
+        data.push((temperature.fract() * 100.).round().min(99.) as u8);
+        data.extend_from_slice(&((self.pressure - 50000.).round().clamp(0., 65534.) as u16).to_be_bytes());
+        for acc in &self.acceleration {
+            data.extend_from_slice(&(acc.round() as i16).to_be_bytes());
+        }
+        data.extend_from_slice(&(self.battery.round() as u16).to_be_bytes());
+        // Padded to the length RuuvitagDF3 accepts.
+        data.resize(DF3_LEN, 0);
+        data
+    }
+
+    /// The data format 5 of RuuviTag, see
+    /// https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_05.md
+    fn df5(&self) -> Vec<u8> {
+        let mut data = vec![5];
+        data.extend_from_slice(&((self.temperature / 0.005).round() as i16).to_be_bytes());
+        data.extend_from_slice(&((self.humidity / 0.0025).round() as u16).to_be_bytes());
+        data.extend_from_slice(&((self.pressure - 50000.).round().clamp(0., 65534.) as u16).to_be_bytes());
+        for acc in &self.acceleration {
+            data.extend_from_slice(&(acc.round() as i16).to_be_bytes());
+        }
+        let battery = (self.battery - 1600.).round().clamp(0., 2046.) as u16;
+        let tx_power = ((DF5_TX_POWER + 40) / 2) as u16;
+        data.extend_from_slice(&(battery << 5 | tx_power).to_be_bytes());
+        data.push(self.movement_counter);
+        data.extend_from_slice(&self.sequence.to_be_bytes());
+        data.extend_from_slice(&self.address);
+        data
+    }
+
+    /// BTHome v2 service data, the objects in the order of their ids, see https://bthome.io/format/
+    fn bthome(&self) -> Vec<u8> {
+        let mut data = vec![BTHOME_V2, 0x00, self.sequence as u8, 0x01, self.battery_percent(), 0x02];
+        data.extend_from_slice(&((self.temperature * 100.).round() as i16).to_le_bytes());
+        data.push(0x03);
+        data.extend_from_slice(&((self.humidity * 100.).round() as u16).to_le_bytes());
+        data.push(0x04);
+        data.extend_from_slice(&(self.pressure.round() as u32).to_le_bytes()[0..3]);
+        data.push(0x0C);
+        data.extend_from_slice(&(self.battery.round() as u16).to_le_bytes());
+        data.extend_from_slice(&[0x22, (self.moving > 0) as u8]);
+        data
+    }
+
+    /// The advertising data the tag sends, with the flags and the sensor data.
+    fn advertising_data(&self) -> Vec<u8> {
+        let (ad_type, id, payload) = match self.format {
+            TagFormat::DF3 => (hci::AD_MANUFACTURER_DATA, RUUVI_COMPANY_ID, self.df3()),
+            TagFormat::DF5 => (hci::AD_MANUFACTURER_DATA, RUUVI_COMPANY_ID, self.df5()),
+            TagFormat::BTHome => (hci::AD_SERVICE_DATA_16, BTHOME_UUID16, self.bthome()),
+        };
+        let mut data = vec![0x02, 0x01, 0x06, (payload.len() + 3) as u8, ad_type];
+        data.extend_from_slice(&id);
+        data.extend_from_slice(&payload);
+        data
+    }
+
+}
+
+/// Advertisements of virtual tags for demos and load testing. The tags send real DF3, DF5 or
+/// BTHome payloads, so they go through the decoders like the ones of real tags. Some
+/// advertisements are lost, some are received twice and now and then a tag is out of range.
+pub struct SimulatorBackend {
+    tag_count: usize,
+    formats: Vec<TagFormat>,
+    interval: Duration,
+    rng: Rng,
+    tags: Vec<VirtualTag>,
+    // The indexes of the tags by their next advertisement, the next one first.
+    schedule: BinaryHeap<Reverse<(Instant, usize)>>,
+    store: DeviceStore,
+}
+
+impl SimulatorBackend {
+
+    pub fn new(
+        conf: config::SensorConf,
+        tag_count: usize,
+        formats: Vec<TagFormat>,
+        interval: Duration,
+        seed: u64,
+        ) -> Result<SimulatorBackend, BoxErr>
+    {
+        if formats.is_empty() {
+            return Err(Box::new(SimulatorError::new("No tag formats".to_string())));
+        }
+        if interval.as_millis() == 0 {
+            return Err(Box::new(SimulatorError::new("The interval must be at least 1 ms".to_string())));
+        }
+        Ok(SimulatorBackend{
+            tag_count,
+            formats,
+            interval,
+            rng: Rng::new(seed),
+            tags: Vec::new(),
+            schedule: BinaryHeap::new(),
+            store: DeviceStore::new(conf),
+        })
+    }
+
+    /// Sends the advertisement of a tag that is due. Returns the address if the store has new
+    /// data from it.
+    fn advertise(&mut self, index: usize) -> Option<String> {
+        let rng = &mut self.rng;
+        let tag = &mut self.tags[index];
+        let now = Instant::now();
+        let delay = Duration::from_secs_f64(rng.range(0., MAX_ADV_DELAY_MS) / 1000.);
+        tag.next_due = (tag.next_due + self.interval + delay).max(now);
+        tag.step(rng);
+
+        if tag.silent_until.is_some_and(|t| t > now) {
+            return None;
+        }
+        tag.silent_until = None;
+        if rng.chance(OUTAGE_PROBABILITY) {
+            tag.silent_until = Some(now + Duration::from_secs_f64(rng.range(30., 120.)));
+            return None;
+        }
+        if rng.chance(DROP_PROBABILITY) {
+            return None;
+        }
+
+        let data = tag.advertising_data();
+        let address = tag.address_string();
+        let copies = if rng.chance(DUPLICATE_PROBABILITY) { 2 } else { 1 };
+        let mut is_new = false;
+        for _ in 0..copies {
+            let mut adv = Advertisement{
+                address: address.clone(),
+                ..Default::default()
+            };
+            if let Err(e) = hci::parse_advertising_data(&data, &mut adv) {
+                warn!("Invalid advertising data of {}: {}", address, e);
+                return None;
+            }
+            adv.link_info.rssi = Some((tag.rssi + rng.noise(3.)).round() as i16);
+            adv.link_info.address_type = Some("random".to_string());
+            adv.link_info.adapter = Some(ADAPTER.to_string());
+            is_new |= self.store.add_advertisement(adv, unix_timestamp());
+        }
+        if is_new {
+            Some(address)
+        } else {
+            None
+        }
+    }
+
+    fn run(&mut self, mut consumer: Option<&mut dyn Consumer>, wait: Duration) {
+        let end = Instant::now() + wait;
+        loop {
+            let (due, index) = match self.schedule.peek() {
+                Some(&Reverse((due, index))) if due < end => (due, index),
+                _ => {
+                    thread::sleep(end.saturating_duration_since(Instant::now()));
+                    return;
+                },
+            };
+            self.schedule.pop();
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+            let address = self.advertise(index);
+            self.schedule.push(Reverse((self.tags[index].next_due, index)));
+            if let (Some(address), Some(c)) = (address, consumer.as_mut()) {
+                self.store.consume_device(&address, &mut **c);
+            }
+        }
+    }
+
+}
+
+impl Backend for SimulatorBackend {
+
+    /// Creates the tags. They are kept when reconnecting.
+    fn initialize(&mut self) -> Result<(), BoxErr> {
+        if self.tags.is_empty() {
+            for index in 0..self.tag_count {
+                let format = self.formats[index % self.formats.len()];
+                let tag = VirtualTag::new(&mut self.rng, index, format, self.interval);
+                self.schedule.push(Reverse((tag.next_due, index)));
+                self.tags.push(tag);
+            }
+            info!("Simulating {} tags", self.tags.len());
+        }
+        Ok(())
+    }
+
+    fn consume(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        self.run(Some(consumer), wait);
+        consumer.flush();
+        self.store.evict_unseen();
+        Ok(())
+    }
+
+    fn list(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        self.run(None, wait);
+        self.store.consume_all(consumer);
+        consumer.flush();
+        Ok(())
+    }
+
+    fn set_recorder(&mut self, recorder: Recorder) {
+        self.store.set_recorder(recorder);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use bt_device::BTDevice;
+    use bt_sensor::Value;
+    use bt_sensor_factory::BTSensorFactory;
+    use discovery_mode::DiscoveryMode;
+
+    #[test]
+    fn decodes_simulated_tags() {
+        let factory = BTSensorFactory::new(config::SensorConf::default());
+        let mut rng = Rng::new(1);
+        let formats = [(TagFormat::DF3, "RuuvitagDF3"), (TagFormat::DF5, "RuuvitagDF5"), (TagFormat::BTHome, "BTHome")];
+        for (index, &(format, name)) in formats.iter().enumerate() {
+            let mut tag = VirtualTag::new(&mut rng, index, format, Duration::from_secs(1));
+            tag.step(&mut rng);
+            let mut adv = Advertisement::default();
+            hci::parse_advertising_data(&tag.advertising_data(), &mut adv).unwrap();
+            let address = tag.address_string();
+            let device = Rc::new(RefCell::new(BTDevice::new(
+                address,
+                name.to_string(),
+                Some(adv.mfr_data).filter(|m| !m.is_empty()),
+                Some(adv.svc_data).filter(|s| !s.is_empty()),
+                0,
+                Duration::from_secs(60),
+                DiscoveryMode::Auto,
+            )));
+            let sensors = factory.find_sensor_types(device);
+            let sensor = &sensors.iter().find(|s| s.0 == name).unwrap().1;
+            let temperature = match sensor.get_sensor_measurements().unwrap().get("temperature") {
+                Some(Value::Float(t)) => *t,
+                _ => panic!("No temperature from {}", name),
+            };
+            assert!((temperature - tag.temperature).abs() < 0.01, "{} {}", name, temperature);
+        }
+    }
+
+}
@@ -0,0 +1,126 @@
+use std::cmp;
+use std::error;
+use std::thread;
+use std::time::Duration;
+
+use backend::Backend;
+use consumer::Consumer;
+use error::FatalError;
+
+type BoxErr = Box<dyn error::Error>;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// With the backoff this waits for about ten minutes at start, e.g. for bluetoothd or an
+// adapter that is plugged in late.
+const MAX_INITIALIZE_ATTEMPTS: u32 = 15;
+
+/// Keeps the backend running. When the backend fails, e.g. bluetoothd restarts or an adapter
+/// is reset, the backend is reconnected with an exponential backoff instead of exiting. The
+/// backend keeps its devices and the consumer its buffered measurements meanwhile. A
+/// FatalError, e.g. an address that cannot be listened on, is not retried.
+pub struct Supervisor {
+    backend: Box<dyn Backend>,
+    backoff: Duration,
+}
+
+fn is_fatal(e: &BoxErr) -> bool {
+    e.downcast_ref::<FatalError>().is_some()
+}
+
+impl Supervisor {
+
+    pub fn new(backend: Box<dyn Backend>) -> Supervisor {
+        Supervisor{backend, backoff: MIN_BACKOFF}
+    }
+
+    fn wait_backoff(&mut self) {
+        info!("Retrying in {} s", self.backoff.as_secs());
+        thread::sleep(self.backoff);
+        self.backoff = cmp::min(self.backoff * 2, MAX_BACKOFF);
+    }
+
+    /// Initializes the backend, retrying a number of times. BlueZ may not be up yet when the
+    /// collector starts, but an error that does not go away is returned.
+    pub fn initialize(&mut self) -> Result<(), BoxErr> {
+        let mut attempts = 1;
+        while let Err(e) = self.backend.initialize() {
+            if is_fatal(&e) || attempts >= MAX_INITIALIZE_ATTEMPTS {
+                return Err(e);
+            }
+            error!("Cannot start capture: {}", e);
+            self.wait_backoff();
+            attempts += 1;
+        }
+        self.backoff = MIN_BACKOFF;
+        Ok(())
+    }
+
+    fn recover(&mut self) -> Result<(), BoxErr> {
+        loop {
+            self.wait_backoff();
+            match self.backend.reconnect() {
+                Ok(()) => {
+                    info!("Capture restarted");
+                    self.backoff = MIN_BACKOFF;
+                    return Ok(());
+                },
+                Err(e) if is_fatal(&e) => return Err(e),
+                Err(e) => error!("Cannot restart capture: {}", e),
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.backend.is_finished()
+    }
+
+    pub fn consume(&mut self, consumer: &mut dyn Consumer, wait: Duration) -> Result<(), BoxErr> {
+        match self.backend.consume(consumer, wait) {
+            Err(e) if is_fatal(&e) => Err(e),
+            Err(e) => {
+                error!("Capture failed: {}", e);
+                self.recover()
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::BlueZError;
+    use recording::Recorder;
+
+    // Fails initialize with the errors, then succeeds.
+    struct FailingBackend {
+        errors: Vec<BoxErr>,
+    }
+
+    impl Backend for FailingBackend {
+        fn initialize(&mut self) -> Result<(), BoxErr> {
+            match self.errors.pop() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+        fn consume(&mut self, _consumer: &mut dyn Consumer, _wait: Duration) -> Result<(), BoxErr> {
+            Ok(())
+        }
+        fn list(&mut self, _consumer: &mut dyn Consumer, _wait: Duration) -> Result<(), BoxErr> {
+            Ok(())
+        }
+        fn set_recorder(&mut self, _recorder: Recorder) {}
+    }
+
+    #[test]
+    fn returns_fatal_error() {
+        let errors: Vec<BoxErr> = vec!(Box::new(FatalError::new("Cannot listen".to_string())));
+        let mut supervisor = Supervisor::new(Box::new(FailingBackend{errors}));
+        let e = supervisor.initialize().unwrap_err();
+        assert!(is_fatal(&e));
+        assert!(!is_fatal(&(Box::new(BlueZError::new("Gone".to_string())) as BoxErr)));
+    }
+}
@@ -0,0 +1,254 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Value};
+use bt_device::BTDevice;
+
+pub struct SwitchBotConstructor;
+
+impl SwitchBotConstructor {
+    pub fn new() -> Box<SwitchBotConstructor> {
+        Box::new(SwitchBotConstructor{})
+    }
+}
+
+impl BTSensorConstructor for SwitchBotConstructor {
+    fn get_name(&self) -> &'static str {
+        "SwitchBot"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(SwitchBot::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        SwitchBot::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct SwitchBot {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for SwitchBot {
+
+    fn is_valid_data(&self) -> bool {
+        SwitchBot::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements()
+            .map(|m| {
+                format!(
+                    "model {}\tbattery {}%\ntemp {}°C\thumidity {}%",
+                    m.model,
+                    m.battery_percent,
+                    m.temperature,
+                    m.humidity,
+                )
+            })
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        match self._get_measurements() {
+            Some(m) => {
+                let mut map = HashMap::<String, Value>::new();
+                map.insert(
+                    "temperature".to_string(),
+                    Value::Float(m.temperature),
+                );
+                map.insert(
+                    "humidity".to_string(),
+                    Value::Integer(m.humidity as i64),
+                );
+                map.insert(
+                    "humidity_float".to_string(),
+                    Value::Float(m.humidity as f64),
+                );
+                map.insert(
+                    "battery_percent".to_string(),
+                    Value::Integer(m.battery_percent as i64),
+                );
+                Some(map)
+            },
+            None => None,
+        }
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+pub static SVC_DATA_UUID: &str = "0000fd3d-0000-1000-8000-00805f9b34fb";
+pub static OLD_SVC_DATA_UUID: &str = "00000d00-0000-1000-8000-00805f9b34fb";
+static MFR_DATA_FIELD: u16 = 0x0969;
+const SVC_DATA_LEN: usize = 6;
+// The MAC address, a sequence number and two unknown bytes come before the measurement.
+const MFR_MEAS_OFFSET: usize = 8;
+const MEAS_LEN: usize = 3;
+
+impl SwitchBot {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> SwitchBot {
+        SwitchBot{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        SwitchBot::decode(device.get_svc_data(), device.get_mfr_data()).is_some()
+    }
+
+    /// All SwitchBot devices use the same company id, the model is only in the first byte of
+    /// the service data.
+    pub fn get_model(svc_data: &[u8]) -> Option<&'static str> {
+        match svc_data.first()? & 0x7F {
+            0x54 => Some("Meter"),
+            0x69 => Some("Meter Plus"),
+            0x77 => Some("Outdoor Meter"),
+            _ => None,
+        }
+    }
+
+    // 0       temperature decimals in the lowest four bits
+    // 1       temperature wholes, the highest bit is set for positive temperatures
+    // 2       humidity, % in the lowest seven bits
+    pub fn decode_meas(data: &[u8]) -> Option<(f64, u8)> {
+        if data.len() < MEAS_LEN {
+            return None;
+        }
+        let temperature = (data[1] & 0x7F) as f64 + (data[0] & 0x0F) as f64 / 10.0;
+        let temperature = match data[1] & 0x80 {
+            0 => -temperature,
+            _ => temperature,
+        };
+        Some((temperature, data[2] & 0x7F))
+    }
+
+    // Service data:
+    //
+    // 0       model, see get_model
+    // 1       status flags
+    // 2       battery, % in the lowest seven bits
+    // 3-5     measurement, see decode_meas
+    //
+    // Newer firmwares send a shorter service data and put the measurement in the manufacturer
+    // data at offset 8 instead. It is used whenever it is present.
+    pub fn decode(
+        svc_data: Option<&HashMap<String, Vec<u8>>>,
+        mfr_data: Option<&HashMap<u16, Vec<u8>>>,
+        ) -> Option<SwitchBotMeas>
+    {
+        let svc = svc_data.and_then(|m| m.get(SVC_DATA_UUID).or_else(|| m.get(OLD_SVC_DATA_UUID)))?;
+        let model = SwitchBot::get_model(svc)?;
+        let battery = *svc.get(2)? & 0x7F;
+        let mfr_meas = mfr_data
+            .and_then(|m| m.get(&MFR_DATA_FIELD))
+            .and_then(|d| d.get(MFR_MEAS_OFFSET..MFR_MEAS_OFFSET + MEAS_LEN));
+        let (temperature, humidity) = match mfr_meas {
+            Some(meas) => SwitchBot::decode_meas(meas)?,
+            None if svc.len() >= SVC_DATA_LEN => SwitchBot::decode_meas(&svc[3..SVC_DATA_LEN])?,
+            None => return None,
+        };
+        Some(SwitchBotMeas{
+            model: model.to_string(),
+            temperature,
+            humidity,
+            battery_percent: battery,
+            ..Default::default()
+        })
+    }
+
+    fn _get_measurements(&self) -> Option<SwitchBotMeas> {
+        let device = self.get_bt_device();
+        let mut meas = SwitchBot::decode(device.get_svc_data(), device.get_mfr_data())?;
+        meas.address = device.get_address().to_string();
+        meas.tag = device.get_tag().to_string();
+        Some(meas)
+    }
+
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct SwitchBotMeas {
+    model: String,
+    temperature: f64,
+    humidity: u8,
+    battery_percent: u8,
+    address: String,
+    tag: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn svc_data(uuid: &str, data: &[u8]) -> HashMap<String, Vec<u8>> {
+        let mut map = HashMap::new();
+        map.insert(uuid.to_string(), data.to_vec());
+        map
+    }
+
+    #[test]
+    fn decodes_meter_service_data() {
+        let svc = svc_data(OLD_SVC_DATA_UUID, &[0x54, 0x00, 0x64, 0x01, 0x94, 0x2B]);
+        let meas = SwitchBot::decode(Some(&svc), None).unwrap();
+        assert_eq!(meas.model, "Meter");
+        assert_eq!(meas.temperature, 20.1);
+        assert_eq!(meas.humidity, 43);
+        assert_eq!(meas.battery_percent, 100);
+    }
+
+    #[test]
+    fn decodes_below_zero() {
+        let svc = svc_data(SVC_DATA_UUID, &[0x69, 0x00, 0xD7, 0x05, 0x03, 0x50]);
+        let meas = SwitchBot::decode(Some(&svc), None).unwrap();
+        assert_eq!(meas.model, "Meter Plus");
+        assert_eq!(meas.temperature, -3.5);
+        assert_eq!(meas.humidity, 80);
+        assert_eq!(meas.battery_percent, 87);
+    }
+
+    #[test]
+    fn prefers_manufacturer_data() {
+        let svc = svc_data(SVC_DATA_UUID, &[0x77, 0x00, 0x5A]);
+        let mut mfr = HashMap::new();
+        mfr.insert(MFR_DATA_FIELD, vec![
+            0xD4, 0x0F, 0x68, 0x3A, 0x5C, 0x71, 0x0B, 0x00, 0x08, 0x97, 0x37, 0x00,
+        ]);
+        let meas = SwitchBot::decode(Some(&svc), Some(&mfr)).unwrap();
+        assert_eq!(meas.model, "Outdoor Meter");
+        assert_eq!(meas.temperature, 23.8);
+        assert_eq!(meas.humidity, 55);
+        assert_eq!(meas.battery_percent, 90);
+    }
+
+    #[test]
+    fn ignores_other_devices() {
+        // A SwitchBot Bot has model byte 'H'.
+        let svc = svc_data(SVC_DATA_UUID, &[0x48, 0x00, 0x64, 0x01, 0x94, 0x2B]);
+        assert!(SwitchBot::decode(Some(&svc), None).is_none());
+        let svc = svc_data(SVC_DATA_UUID, &[0x54, 0x00, 0x64]);
+        assert!(SwitchBot::decode(Some(&svc), None).is_none());
+    }
+
+}
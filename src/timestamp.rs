@@ -0,0 +1,11 @@
+use std::time::SystemTime;
+
+/// The current time as unix time in milliseconds, the timestamp of the measurements.
+pub fn unix_timestamp() -> u64 {
+    let timestamp = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(t) => t,
+        Err(_) => panic!("System clock before unix epoch!"),
+    };
+    let millis = timestamp.subsec_millis() as u64;
+    timestamp.as_secs() * 1000 + millis
+}
@@ -0,0 +1,268 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+use std::collections::HashMap;
+
+use serde_json;
+
+use bt_sensor::{BTSensor, BTSensorConstructor, Sequence, Value};
+use bt_device::BTDevice;
+
+pub struct XiaomiATCConstructor;
+
+impl XiaomiATCConstructor {
+    pub fn new() -> Box<XiaomiATCConstructor> {
+        Box::new(XiaomiATCConstructor{})
+    }
+}
+
+impl BTSensorConstructor for XiaomiATCConstructor {
+    fn get_name(&self) -> &'static str {
+        "XiaomiATC"
+    }
+    fn construct(&self, device: Rc<RefCell<BTDevice>>) -> Box<dyn BTSensor> {
+        Box::new(XiaomiATC::new(device))
+    }
+    fn is_valid_data(&self, device: &BTDevice) -> bool {
+        XiaomiATC::_is_valid_data(device)
+    }
+}
+
+#[derive(Clone)]
+pub struct XiaomiATC {
+    bt_device: Rc<RefCell<BTDevice>>,
+}
+
+impl BTSensor for XiaomiATC {
+
+    fn get_sequence(&self) -> Option<Sequence> {
+        self._get_measurements().map(|m| Sequence{value: m.frame_counter as u32, modulus: 0x100})
+    }
+
+    fn is_valid_data(&self) -> bool {
+        XiaomiATC::_is_valid_data(&self.bt_device.borrow())
+    }
+
+    fn get_sensor_measurements_json_str(&self) -> Option<String> {
+        self._get_measurements()
+            .and_then(|m| serde_json::to_string(&m).ok())
+    }
+
+    fn get_sensor_measurements_str(&self) -> Option<String> {
+        self._get_measurements()
+            .map(|m| {
+                format!(
+                    "battery {} mV ({}%)\ntemp {}°C\thumidity {:.2}%\nframe {}\tmac {}",
+                    m.battery,
+                    m.battery_percent,
+                    m.temperature,
+                    m.humidity,
+                    m.frame_counter,
+                    m.mac,
+                )
+            })
+    }
+
+    fn get_sensor_measurements(&self) -> Option<HashMap<String, Value>> {
+        match self._get_measurements() {
+            Some(m) => {
+                let mut map = HashMap::<String, Value>::new();
+                map.insert(
+                    "temperature".to_string(),
+                    Value::Float(m.temperature),
+                );
+                map.insert(
+                    "humidity".to_string(),
+                    Value::Integer(m.humidity.round() as i64),
+                );
+                map.insert(
+                    "humidity_float".to_string(),
+                    Value::Float(m.humidity),
+                );
+                map.insert(
+                    "battery".to_string(),
+                    Value::Integer(m.battery as i64),
+                );
+                map.insert(
+                    "battery_percent".to_string(),
+                    Value::Integer(m.battery_percent as i64),
+                );
+                map.insert(
+                    "frame_counter".to_string(),
+                    Value::Integer(m.frame_counter as i64),
+                );
+                map.insert(
+                    "mac".to_string(),
+                    Value::String(m.mac),
+                );
+                Some(map)
+            },
+            None => None,
+        }
+    }
+
+    fn get_bt_device(&self) -> Ref<'_, BTDevice> {
+        self.bt_device.borrow()
+    }
+
+    fn get_address(&self) -> String {
+        self.get_bt_device().get_address().to_string()
+    }
+
+    fn get_tag(&self) -> String {
+        self.get_bt_device().get_tag().to_string()
+    }
+
+    fn get_measurement_timestamp(&self) -> u64 {
+        self.get_bt_device().get_measurement_timestamp()
+    }
+
+}
+
+pub static SVC_DATA_UUID: &str = "0000181a-0000-1000-8000-00805f9b34fb";
+const ATC1441_LEN: usize = 13;
+const PVVX_LEN: usize = 15;
+
+impl XiaomiATC {
+
+    pub fn new(bt_device: Rc<RefCell<BTDevice>>) -> XiaomiATC {
+        XiaomiATC{bt_device}
+    }
+
+    pub fn _is_valid_data(device: &BTDevice) -> bool {
+        match device.get_svc_data().and_then(|m| m.get(SVC_DATA_UUID)) {
+            Some(data) => data.len() == ATC1441_LEN || data.len() == PVVX_LEN,
+            None => false,
+        }
+    }
+
+    fn format_mac<'a, I: Iterator<Item = &'a u8>>(bytes: I) -> String {
+        let parts: Vec<String> = bytes.map(|b| format!("{:02X}", b)).collect();
+        parts.join(":")
+    }
+
+    // See https://github.com/atc1441/ATC_MiThermometer#advertising-format-of-the-custom-firmware
+    //
+    // 0-5     MAC address, most significant byte first
+    // 6-7     temperature, big endian i16, 0.1 °C
+    // 8       humidity, %
+    // 9       battery, %
+    // 10-11   battery, big endian u16, mV
+    // 12      frame counter
+    pub fn decode_atc1441(data: &[u8]) -> Option<XiaomiATCMeas> {
+        if data.len() != ATC1441_LEN {
+            return None;
+        }
+        let temperature = (((data[6] as u16) << 8) | data[7] as u16) as i16;
+        Some(XiaomiATCMeas{
+            format: "atc1441".to_string(),
+            temperature: temperature as f64 / 10.0,
+            humidity: data[8] as f64,
+            battery_percent: data[9],
+            battery: ((data[10] as u16) << 8) | data[11] as u16,
+            frame_counter: data[12],
+            flags: None,
+            mac: XiaomiATC::format_mac(data[0..6].iter()),
+            ..Default::default()
+        })
+    }
+
+    // See https://github.com/pvvx/ATC_MiThermometer#custom-format-all-data-little-endian
+    //
+    // 0-5     MAC address, least significant byte first
+    // 6-7     temperature, little endian i16, 0.01 °C
+    // 8-9     humidity, little endian u16, 0.01 %
+    // 10-11   battery, little endian u16, mV
+    // 12      battery, %
+    // 13      frame counter
+    // 14      flags
+    pub fn decode_pvvx(data: &[u8]) -> Option<XiaomiATCMeas> {
+        if data.len() != PVVX_LEN {
+            return None;
+        }
+        let temperature = (((data[7] as u16) << 8) | data[6] as u16) as i16;
+        let humidity = ((data[9] as u16) << 8) | data[8] as u16;
+        Some(XiaomiATCMeas{
+            format: "pvvx".to_string(),
+            temperature: temperature as f64 / 100.0,
+            humidity: humidity as f64 / 100.0,
+            battery: ((data[11] as u16) << 8) | data[10] as u16,
+            battery_percent: data[12],
+            frame_counter: data[13],
+            flags: Some(data[14]),
+            mac: XiaomiATC::format_mac(data[0..6].iter().rev()),
+            ..Default::default()
+        })
+    }
+
+    fn _get_measurements(&self) -> Option<XiaomiATCMeas> {
+        let device = self.get_bt_device();
+        let data = device
+            .get_svc_data()?
+            .get(SVC_DATA_UUID)?;
+        let mut meas = match data.len() {
+            ATC1441_LEN => XiaomiATC::decode_atc1441(data)?,
+            PVVX_LEN => XiaomiATC::decode_pvvx(data)?,
+            _ => return None,
+        };
+        meas.address = device.get_address().to_string();
+        meas.tag = device.get_tag().to_string();
+        Some(meas)
+    }
+
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct XiaomiATCMeas {
+    format: String,
+    temperature: f64,
+    humidity: f64,
+    battery: u16,
+    battery_percent: u8,
+    frame_counter: u8,
+    flags: Option<u8>,
+    mac: String,
+    address: String,
+    tag: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+
+    const MAC: &str = "A4:C1:38:38:83:F4";
+
+    #[test]
+    fn decodes_atc1441() {
+        // 22.5 °C, 47 %, 83 %, 2954 mV and the frame 28.
+        let meas = XiaomiATC::decode_atc1441(&hex::decode("A4C1383883F400E12F530B8A1C").unwrap()).unwrap();
+        assert_eq!(meas.temperature, 22.5);
+        assert_eq!(meas.humidity, 47.0);
+        assert_eq!(meas.battery_percent, 83);
+        assert_eq!(meas.battery, 2954);
+        assert_eq!(meas.frame_counter, 28);
+        assert_eq!(meas.flags, None);
+        assert_eq!(meas.mac, MAC);
+
+        let meas = XiaomiATC::decode_atc1441(&hex::decode("A4C1383883F4FF9C2F530B8A1C").unwrap()).unwrap();
+        assert_eq!(meas.temperature, -10.0);
+    }
+
+    #[test]
+    fn decodes_pvvx() {
+        // 23.20 °C, 45.67 %, 2954 mV, 83 %, the frame 28 and the flags 5.
+        let meas = XiaomiATC::decode_pvvx(&hex::decode("F4833838C1A41009D7118A0B531C05").unwrap()).unwrap();
+        assert_eq!(meas.temperature, 23.2);
+        assert_eq!(meas.humidity, 45.67);
+        assert_eq!(meas.battery, 2954);
+        assert_eq!(meas.battery_percent, 83);
+        assert_eq!(meas.frame_counter, 28);
+        assert_eq!(meas.flags, Some(5));
+        assert_eq!(meas.mac, MAC);
+
+        let meas = XiaomiATC::decode_pvvx(&hex::decode("F4833838C1A4F3FDD7118A0B531C05").unwrap()).unwrap();
+        assert_eq!(meas.temperature, -5.25);
+        assert!(XiaomiATC::decode_pvvx(&hex::decode("A4C1383883F400E12F530B8A1C").unwrap()).is_none());
+    }
+
+}